@@ -0,0 +1,58 @@
+import struct
+import unittest
+from capnproto import wrapper
+
+
+def frame(*segments):
+    header = struct.pack('<I', len(segments) - 1)
+    header += b''.join(struct.pack('<I', len(x) // 8) for x in segments)
+    if len(header) % 8:
+        header += b'\0' * (8 - len(header) % 8)
+    return header + b''.join(segments)
+
+
+class TestFrameDecoder(unittest.TestCase):
+    def test_chunked(self):
+        a = frame(b'\x01' * 8)
+        b = frame(b'\x02' * 16, b'\x03' * 8)
+        data = a + b
+
+        decoder = wrapper.FrameDecoderPy()
+        got = []
+        for i in range(len(data)):
+            got.extend(decoder.feed(data[i:i + 1]))
+
+        self.assertEqual([x.to_bytes() for x in got], [a, b])
+        self.assertEqual(got[1].segment_count, 2)
+        self.assertEqual(decoder.buffered, 0)
+        decoder.close()
+
+    def test_packed(self):
+        # header 00 00 00 00 01 00 00 00, then the word 01 00 00 00 00 00 00 02
+        packed = b'\x10\x01' + b'\x81\x01\x02'
+        decoder = wrapper.FrameDecoderPy(packed=True)
+
+        self.assertEqual(decoder.feed(packed[:3]), [])
+        got = decoder.feed(packed[3:])
+
+        self.assertEqual(len(got), 1)
+        self.assertEqual(got[0].to_bytes(), frame(b'\x01' + b'\0' * 6 + b'\x02'))
+
+    def test_limits(self):
+        decoder = wrapper.FrameDecoderPy(max_words=1)
+
+        with self.assertRaises(wrapper.CapnpError):
+            decoder.feed(frame(b'\0' * 16))
+
+        # a header asking for 0xffff words, then runs of zero words
+        packed = wrapper.FrameDecoderPy(packed=True, max_words=1)
+
+        with self.assertRaises(wrapper.CapnpError):
+            packed.feed(b'\x30\xff\xff' + b'\x00\xff' * 10000)
+
+    def test_incomplete(self):
+        decoder = wrapper.FrameDecoderPy()
+        decoder.feed(frame(b'\0' * 8)[:-1])
+
+        with self.assertRaises(wrapper.CapnpError):
+            decoder.close()
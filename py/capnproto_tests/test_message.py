@@ -0,0 +1,475 @@
+import array
+import json
+import os
+import unittest
+from capnproto import wrapper
+
+
+class TestMessage(unittest.TestCase):
+
+    def setUp(self) -> None:
+        super().setUp()
+        filename = os.path.join(os.path.split(__file__)[0], 'test.capnp')
+        [self.root] = wrapper.compile(filename).id
+
+    def test_allocation_options(self):
+        msg = self.root.TestAllTypes.new_message(first_segment_words=4, allocation_strategy='fixed')
+
+        # the root struct does not fit next to the root pointer
+        self.assertGreater(msg.segment_count, 1)
+        self.assertEqual(len(msg.to_bytes()) % 8, 0)
+
+        with self.assertRaises(TypeError):
+            self.root.TestAllTypes.new_message(allocation_strategy='bogus')
+
+    def test_scratch(self):
+        scratch = wrapper.ScratchSpacePy(words=256)
+
+        outputs = set()
+        for _ in range(3):
+            msg = self.root.TestAllTypes.new_message(scratch=scratch)
+            self.assertEqual(msg.segment_count, 1)
+            outputs.add(msg.to_bytes())
+            del msg
+
+        self.assertEqual(len(outputs), 1)
+
+    def test_orphans(self):
+        msg = self.root.TestAllTypes.new_message()
+        root = msg.root
+
+        orphan = msg.new_orphan(self.root.TestAllTypes)
+        orphan.get().set('textField', 'leaf')
+        root.adopt('structField', orphan)
+
+        self.assertTrue(orphan.adopted)
+        self.assertEqual(root.get('structField').get('textField'), 'leaf')
+
+        moved = root.disown('structField')
+        self.assertEqual(moved.get().get('textField'), 'leaf')
+
+        root.get('structField').adopt('structField', moved)
+        self.assertEqual(root.get('structField').get('structField').get('textField'), 'leaf')
+
+        with self.assertRaises(TypeError):
+            root.adopt('textField', msg.new_orphan(self.root.TestAllTypes))
+
+        # list elements reordered through orphans, without copying
+        root.set('structList', [{'int8Field': 1}, {'int8Field': 2}, {'int8Field': 3}])
+        items = root.get('structList')
+        orphans = [items.disown(i) for i in range(3)]
+        self.assertEqual([x.get('int8Field') for x in items], [0, 0, 0])
+
+        for i, orphan in enumerate(reversed(orphans)):
+            items.adopt(i, orphan)
+        self.assertEqual([x.get('int8Field') for x in items], [3, 2, 1])
+
+        items[0] = items.disown(2)
+        self.assertEqual([x.get('int8Field') for x in items], [1, 2, 0])
+
+    def test_growable(self):
+        msg = self.root.TestAllTypes.new_message()
+        root = msg.root
+
+        ints = root.growable('int32List')
+        ints.extend([1, 2, 3, 4, 5])
+        ints.insert(0, 0)
+        self.assertEqual(ints.pop(), 5)
+        self.assertEqual(list(ints), [0, 1, 2, 3, 4])
+        self.assertGreaterEqual(ints.capacity, 5)
+
+        # shrunk in place, only the spare capacity is left behind
+        wasted, spare = msg.wasted_bytes, (ints.capacity - 5) * 4
+        self.assertEqual(list(ints.finalize()), [0, 1, 2, 3, 4])
+        self.assertEqual(len(root.get('int32List')), 5)
+        self.assertLessEqual(msg.wasted_bytes, wasted + spare + 4)
+
+        texts = root.growable('textList')
+        texts.extend(['a', 'b'])
+        texts.insert(1, 'c')
+        self.assertEqual(texts.pop(0), 'a')
+        self.assertEqual(list(texts.finalize()), ['c', 'b'])
+
+        structs = root.growable('structList')
+        structs.append({'int8Field': 1})
+        structs.append({'int8Field': 2})
+        first = structs.pop(0)
+        self.assertEqual(first.get().get('int8Field'), 1)
+
+        # what pop gives back goes back in
+        structs.append(first)
+        structs.insert(0, structs.pop())
+        self.assertEqual([x.get('int8Field') for x in structs.finalize()], [1, 2])
+
+    def test_copy_from_reader(self):
+        src = self.root.TestAllTypes.new_message()
+        src.root.set('structField', {'textField': 'inner', 'int32List': [1, 2]})
+        src.root.set('textList', ['x', 'y'])
+        reader = self.root.TestAllTypes.read(src.to_bytes())
+
+        dst = self.root.TestAllTypes.new_message()
+        dst.root.set('structField', reader.structField)
+        dst.root.set('textList', reader.get('textList'))
+        del src, reader
+
+        copied = dst.root.as_reader()
+        self.assertEqual(copied.structField.textField, 'inner')
+        self.assertEqual(list(copied.structField.int32List), [1, 2])
+        self.assertEqual(list(copied.textList), ['x', 'y'])
+
+        with self.assertRaises(TypeError):
+            dst.root.set('structField', copied.textList)
+
+        wrapper_msg = self.root.TestAnyPointer.new_message()
+        wrapper_msg.root.set('anyPointerField', copied.structField)
+        any_ptr = wrapper_msg.root.as_reader().anyPointerField
+        self.assertEqual(any_ptr.as_struct(self.root.TestAllTypes).textField, 'inner')
+
+        # an untyped pointer goes only where its actual kind fits
+        dst.root.set('structField', any_ptr)
+        with self.assertRaises(TypeError):
+            dst.root.set('int32List', any_ptr)
+
+    def test_capability_placeholders(self):
+        Placeholder = wrapper.CapabilityPlaceholderPy
+
+        msg = self.root.TestCapabilityList.new_message()
+        msg.root.set('foo', [Placeholder(5), None, Placeholder(2)])
+
+        # written placeholders go into the message's own cap table
+        self.assertEqual(msg.cap_table, [5, 2])
+        self.assertEqual(list(msg.root.as_reader().foo), [Placeholder(5), None, Placeholder(2)])
+
+        # read back with that table, pointers carry the same indices
+        reader = self.root.TestCapabilityList.read(msg.to_bytes(), cap_table=msg.cap_table)
+        self.assertEqual(list(reader.foo), [Placeholder(5), None, Placeholder(2)])
+
+        copy = self.root.TestCapabilityList.new_message()
+        copy.root.set('foo', reader.foo)
+        self.assertEqual(copy.cap_table, [5, 2])
+
+        # without it, pointers carry their index on the wire
+        reader = self.root.TestCapabilityList.read(msg.to_bytes())
+        self.assertEqual(list(reader.foo), [Placeholder(0), None, Placeholder(1)])
+
+    def test_compact(self):
+        msg = self.root.TestAllTypes.new_message()
+        msg.root.set('textField', 'a' * 1000)
+        msg.root.set('textField', 'b')
+
+        self.assertGreaterEqual(msg.wasted_bytes, 1000)
+        before = len(msg.to_bytes())
+
+        msg.compact()
+
+        self.assertEqual(msg.wasted_bytes, 0)
+        self.assertLess(len(msg.to_bytes()), before)
+        self.assertEqual(msg.root.get('textField'), 'b')
+
+        root = msg.root
+        with self.assertRaises(ValueError):
+            msg.compact()
+
+        # objects out of reach of their pointers hang off landing pads,
+        # which are as live as the objects
+        small = self.root.TestAllTypes.new_message(first_segment_words=4, allocation_strategy='fixed')
+        small.root.set('textField', 'x' * 40)
+        small.root.set('structField', {'textField': 'y' * 40})
+
+        self.assertGreater(small.segment_count, 1)
+        self.assertEqual(small.wasted_bytes, 0)
+
+    def test_canonical(self):
+        def build(**kwargs):
+            msg = self.root.TestAllTypes.new_message(**kwargs)
+            msg.root.set('int32Field', 7)
+            msg.root.set('textField', 'same')
+            msg.root.set('structField', {'boolField': True})
+            return self.root.TestAllTypes.read(msg.to_bytes())
+
+        a = build()
+        b = build(first_segment_words=4, allocation_strategy='fixed')
+
+        self.assertEqual(a.canonicalize(), b.canonicalize())
+        self.assertEqual(a, b)
+        self.assertEqual(hash(a), hash(b))
+
+        canonical = a.canonicalize()
+        self.assertTrue(wrapper.is_canonical(canonical))
+        self.assertFalse(wrapper.is_canonical(canonical + b'\0' * 8))
+
+        b2 = build()
+        self.assertEqual(len({a, b, b2}), 1)
+
+        # a reader into a builder would change its hash with the builder
+        live = self.root.TestAllTypes.new_message()
+        with self.assertRaises(TypeError):
+            hash(live.root.as_reader())
+
+    def test_diff(self):
+        def build(node, **fields):
+            msg = node.new_message()
+            for k, v in fields.items():
+                msg.root.set(k, v)
+            return node.read(msg.to_bytes())
+
+        a = build(self.root.TestAllTypes, int32Field=1, structField={'textField': 'x'}, int32List=[1, 2])
+        b = build(self.root.TestAllTypes, int32Field=2, structField={'textField': 'y'}, int32List=[1, 2, 3])
+
+        changes = {str(path): (old, new) for path, old, new in wrapper.diff(a, b)}
+
+        self.assertEqual(changes, {
+            'int32Field': (1, 2),
+            'structField.textField': ('x', 'y'),
+            'int32List[2]': (None, 3),
+        })
+        self.assertIn('int32List[2]: <missing> -> 3', str(wrapper.diff(a, b)))
+        self.assertEqual(len(wrapper.diff(a, a)), 0)
+
+        foo = build(self.root.TestUnnamedUnion, foo=1)
+        bar = build(self.root.TestUnnamedUnion, bar=1)
+
+        [(path, old, new)] = wrapper.diff(foo, bar)
+        self.assertEqual((str(path), old, new), ('which', 'foo', 'bar'))
+
+    def test_text_printer(self):
+        msg = self.root.TestAllTypes.new_message()
+        self.assertEqual(
+            str(msg.root),
+            '(voidField = void, boolField = false, int8Field = 0, int16Field = 0, int32Field = 0, '
+            'int64Field = 0, uInt8Field = 0, uInt16Field = 0, uInt32Field = 0, uInt64Field = 0, '
+            'float32Field = 0, float64Field = 0, enumField = foo, interfaceField = void)'
+        )
+
+        msg.root.set('float32Field', 1234.5)
+        msg.root.set('float64Field', -123e45)
+        msg.root.set('textField', 'a "quoted"\n')
+        msg.root.set('dataField', b'\x01bar')
+        msg.root.set('enumField', 'corge')
+        msg.root.set('int32List', [1, -2])
+        msg.root.set('structList', [{'int8Field': 5}])
+
+        text = str(self.root.TestAllTypes.read(msg.to_bytes()))
+
+        self.assertIn('float32Field = 1234.5, float64Field = -1.23e47, ', text)
+        self.assertIn('textField = "a \\"quoted\\"\\n", dataField = "\\001bar", ', text)
+        self.assertIn('enumField = corge, ', text)
+        self.assertIn('int32List = [1, -2], ', text)
+        self.assertIn('structList = [(voidField = void, boolField = false, int8Field = 5, ', text)
+
+        pretty = msg.root.as_reader().to_text(pretty=True)
+        self.assertTrue(pretty.startswith('( voidField = void,\n  boolField = false,\n'))
+        self.assertEqual(pretty.replace('\n', '').replace(' ', ''), text.replace(' ', ''))
+
+    def test_text_parser(self):
+        text = (
+            '(boolField = true, int8Field = -12, uInt64Field = 0xffffffffffffffff, float32Field = 1234.5,\n'
+            ' float64Field = -inf, textField = "a \\"b\\"\\n", dataField = 0x"de ad", enumField = corge,\n'
+            ' structField = (voidField = void, int32List = [1, -2]), structList = [(textField = "x")],\n'
+            ' dataList = ["\\001", 0x"ff"])'
+        )
+        root = self.root.TestAllTypes.read(self.root.TestAllTypes.from_text(text).to_bytes())
+
+        self.assertEqual(root.int8Field, -12)
+        self.assertEqual(root.uInt64Field, 2 ** 64 - 1)
+        self.assertEqual(root.float32Field, 1234.5)
+        self.assertEqual(root.float64Field, float('-inf'))
+        self.assertEqual(root.textField, 'a "b"\n')
+        self.assertEqual(root.dataField, b'\xde\xad')
+        self.assertEqual(root.enumField, 'corge')
+        self.assertEqual(list(root.structField.int32List), [1, -2])
+        self.assertEqual(root.structList[0].textField, 'x')
+        self.assertEqual(list(root.dataList), [b'\x01', b'\xff'])
+
+        # what the printer emits reads back to the same message
+        self.assertEqual(self.root.TestAllTypes.read(self.root.TestAllTypes.from_text(str(root)).to_bytes()), root)
+
+        union = self.root.TestUnnamedUnion.from_text('(bar = 3)')
+        self.assertEqual(union.root.which(), 'bar')
+
+        with self.assertRaisesRegex(ValueError, 'line 2, column 14: integer out of range'):
+            self.root.TestAllTypes.from_text('(\n int8Field = 300)')
+
+        with self.assertRaisesRegex(ValueError, 'line 1, column 78: nested deeper than 64'):
+            self.root.TestAllTypes.from_text('(structList = ' + '[' * 100000)
+
+        union = self.root.TestUnnamedUnion.new_message()
+        union.root.set('before', 'a')
+        union.root.set('bar', 3)
+        union.root.set('after', 'z')
+        self.assertEqual(str(union.root), '(before = "a", middle = 0, bar = 3, after = "z")')
+
+        any_ptr = self.root.TestAnyPointer.new_message()
+        any_ptr.root.set('anyPointerField', root.structField)
+        text = str(any_ptr.root)
+
+        self.assertEqual(text, '(anyPointerField = <opaque pointer>)')
+        with self.assertRaisesRegex(ValueError, 'line 1, column 20: a placeholder'):
+            self.root.TestAnyPointer.from_text(text)
+
+        with self.assertRaisesRegex(ValueError, 'column 2: no field named bogus'):
+            self.root.TestAllTypes.from_text('(bogus = 1)')
+
+    def test_json(self):
+        msg = self.root.TestAllTypes.new_message()
+        msg.root.set('int64Field', -2 ** 60)
+        msg.root.set('float64Field', float('nan'))
+        msg.root.set('dataField', b'\x01\x02')
+        msg.root.set('enumField', 'bar')
+        msg.root.set('structList', [{'textField': 'x'}])
+
+        reader = self.root.TestAllTypes.read(msg.to_bytes())
+        value = json.loads(wrapper.to_json(reader))
+
+        self.assertEqual(value['int64Field'], str(-2 ** 60))
+        self.assertEqual(value['float64Field'], 'NaN')
+        self.assertEqual(value['dataField'], [1, 2])
+        self.assertEqual(value['enumField'], 'bar')
+        self.assertEqual(value['structList'][0]['textField'], 'x')
+        self.assertEqual(json.loads(wrapper.to_json(reader, data='base64'))['dataField'], 'AQI=')
+
+        back = self.root.TestAllTypes.from_json(wrapper.to_json(reader))
+        self.assertEqual(len(wrapper.diff(reader, back.root.as_reader())), 0)
+
+    def test_json_annotations(self):
+        filename = os.path.join(os.path.split(__file__)[0], 'json_test.capnp')
+        [root] = wrapper.compile(filename).id
+
+        msg = root.Shape.from_json(
+            '{"type": "circle", "circle": 1.5, "title": "a", "blob": "AQI=", "pos_x": "1", "pos_y": "-2"}'
+        )
+        shape = msg.root.as_reader()
+
+        self.assertEqual(shape.which(), 'circle')
+        self.assertEqual(shape.label, 'a')
+        self.assertEqual(shape.blob, b'\x01\x02')
+        self.assertEqual((shape.position.x, shape.position.y), (1, -2))
+
+        self.assertEqual(json.loads(wrapper.to_json(shape)), {
+            'type': 'circle', 'circle': 1.5, 'title': 'a', 'blob': 'AQI=', 'pos_x': '1', 'pos_y': '-2',
+        })
+
+        square = root.Shape.from_json('{"type": "square"}').root
+        self.assertEqual(square.which(), 'square')
+
+        self.assertEqual(root.Shape.from_json('{"digest": "00fF"}').root.get('digest'), b'\x00\xff')
+
+        for bad in ['{"digest": "0\u00e90"}', '{"blob": "AQI"}', '{"blob": "AQ=I"}', '{"blob": "AQJ="}']:
+            with self.assertRaises(ValueError):
+                root.Shape.from_json(bad)
+
+    def test_msgpack(self):
+        empty = self.root.TestAllTypes.new_message()
+        self.assertTrue(wrapper.to_msgpack(empty.root, keys='index').startswith(b'\x8e\x00\xc0\x01\xc2\x02\x00'))
+
+        text = (
+            '(boolField = true, int8Field = -100, int64Field = -5000000000, uInt64Field = 18446744073709551615,'
+            ' float32Field = 1.5, float64Field = -2.25e300, textField = "hello", dataField = 0x"00ff",'
+            ' structField = (textField = "nested", enumField = baz), enumField = corge,'
+            ' int16List = [1, -1], textList = ["a", "b"], dataList = [0x"01"], structList = [(int32Field = 7)],'
+            ' enumList = [foo, garply], boolList = [true, false, true])'
+        )
+        original = self.root.TestAllTypes.from_text(text).root.as_reader()
+
+        for keys in ('name', 'index'):
+            for enums in ('int', 'name'):
+                data = wrapper.to_msgpack(original, keys=keys, enums=enums)
+                back = self.root.TestAllTypes.from_msgpack(data).root.as_reader()
+
+                self.assertEqual(len(wrapper.diff(original, back)), 0, (keys, enums))
+                self.assertEqual(back, original)
+
+        with self.assertRaisesRegex(ValueError, 'nested deeper'):
+            self.root.TestAllTypes.from_msgpack(b'\x91' * 100000 + b'\xc0')
+
+    def test_csv(self):
+        msg = self.root.TestAllTypes.new_message()
+        msg.root.set('structList', [
+            {'int32Field': -7, 'textField': 'a, "b"\nc', 'enumField': 'bar', 'float64Field': 0.5},
+            {'boolField': True, 'dataField': b'\x01\x02'},
+        ])
+        items = msg.root.as_reader().structList
+
+        self.assertEqual(
+            wrapper.to_csv(items, columns=['int32Field', 'textField', 'enumField']),
+            'int32Field,textField,enumField\r\n-7,"a, ""b""\nc",bar\r\n0,,foo\r\n',
+        )
+
+        back = self.root.TestAllTypes.new_message()
+        wrapper.from_csv(back.root, 'structList', wrapper.to_csv(items))
+
+        self.assertEqual(list(back.root.as_reader().structList), list(items))
+
+        with self.assertRaisesRegex(ValueError, r'row 3, column 2 \(int8Field\): integer out of range'):
+            wrapper.from_csv(back.root, 'structList', 'boolField,int8Field\ntrue,1\nfalse,300\n')
+
+        # a bad cell leaves the field as it was
+        self.assertEqual(list(back.root.as_reader().structList), list(items))
+
+        msg.root.set('structList', [{'textField': ''}, {}])
+        items = msg.root.as_reader().structList
+        data = wrapper.to_csv(items, columns=['textField'], null='NULL')
+
+        self.assertEqual(data, 'textField\r\n""\r\nNULL\r\n')
+
+        wrapper.from_csv(back.root, 'structList', data, null='NULL')
+        self.assertEqual(list(back.root.as_reader().structList), list(items))
+
+    def test_buffer(self):
+        msg = self.root.TestAllTypes.new_message()
+        msg.root.set('float64List', [1.5, -2.0])
+        msg.root.set('int32List', [1, 2, 3])
+
+        reader = msg.root.as_reader()
+        view = memoryview(reader.float64List)
+
+        self.assertEqual((view.format, view.itemsize, view.readonly), ('d', 8, True))
+        self.assertEqual(view.tolist(), [1.5, -2.0])
+        self.assertEqual(memoryview(reader.int32List).cast('B').cast('i').tolist(), [1, 2, 3])
+
+        with self.assertRaises(BufferError):
+            memoryview(reader.boolList)
+
+        msg.root.set('int64List', array.array('q', [5, -6]))
+        msg.root.set('uInt32List', b'\x01\x00\x00\x00\x02\x00\x00\x00')
+        reader = msg.root.as_reader()
+
+        self.assertEqual(list(reader.int64List), [5, -6])
+        self.assertEqual(list(reader.uInt32List), [1, 2])
+
+        with self.assertRaises(TypeError):
+            msg.root.set('int16List', array.array('d', [1.0]))
+
+        # signed bytes are numbers, not an encoding
+        with self.assertRaises(TypeError):
+            msg.root.set('int16List', array.array('b', [1, 0]))
+
+    def test_decode_columns(self):
+        stream = b''
+
+        for i in range(3):
+            msg = self.root.TestAllTypes.new_message()
+            msg.root.set('int32Field', i - 1)
+            msg.root.set('float64Field', i / 2)
+            msg.root.set('enumField', 'bar')
+
+            if i != 1:
+                msg.root.set('textField', str(i))
+                msg.root.init('structField').set('uInt16Field', i * 100)
+
+            stream += msg.to_bytes()
+
+        columns = wrapper.decode_columns(
+            self.root.TestAllTypes, stream,
+            ['int32Field', 'float64Field', 'enumField', 'textField', 'structField.uInt16Field'],
+        )
+
+        self.assertEqual(columns['int32Field'].typecode, 'i')
+        self.assertEqual(list(columns['int32Field']), [-1, 0, 1])
+        self.assertEqual(list(columns['float64Field']), [0.0, 0.5, 1.0])
+        self.assertEqual(list(columns['enumField']), [1, 1, 1])
+        self.assertEqual(columns['textField'], ['0', None, '2'])
+        self.assertEqual(list(columns['structField.uInt16Field']), [0, 0, 200])
+
+        with self.assertRaises(TypeError):
+            wrapper.decode_columns(self.root.TestAllTypes, stream, ['int32List'])
@@ -0,0 +1,470 @@
+import os
+import socket
+import struct
+import tempfile
+import threading
+import unittest
+from capnproto import wrapper
+
+HERE = os.path.split(__file__)[0]
+
+
+def bootstrap_return(question):
+    # this Return carrying capability 0 (exported as id 0) is laid out by
+    # hand, so the client is checked against bytes no builder of ours wrote
+    words = [
+        (0, 1 | 1 << 16),      # root: Message, 1 data word, 1 pointer
+        (3, 0),                # which = return
+        (0, 2 | 1 << 16),      # Return, 2 data words, 1 pointer
+        (question, 0),         # answerId; results
+        (0, 0),
+        (0, 2 << 16),          # Payload, 2 pointers
+        (3, 0),                # content: capability 0
+        (1, 7 | 2 << 3),       # capTable: one inline composite element
+        (1 << 2, 1 | 1 << 16), # tag: CapDescriptor, 1 data word, 1 pointer
+        (1, 0),                # senderHosted = 0
+        (0, 0),
+    ]
+    segment = b''.join(struct.pack('<II', lo, hi) for lo, hi in words)
+    return struct.pack('<II', 0, len(words)) + segment
+
+
+class StandIn(threading.Thread):
+    """A peer on one end of a socket pair: it bootstraps to one capability
+    and answers calls with the Return bytes `handler(call)` produces. The
+    traffic is logged as `(client sent it, bytes)`."""
+
+    def __init__(self, rpc, sock, handler):
+        super().__init__(daemon=True)
+        self.rpc = rpc
+        self.sock = sock
+        self.handler = handler
+        self.received = []
+        self.log = []
+
+    def run(self):
+        decoder = wrapper.FrameDecoderPy()
+
+        while True:
+            data = self.sock.recv(65536)
+            if not data:
+                break
+
+            for frame in decoder.feed(data):
+                message = self.rpc.Message.read(frame)
+                self.received.append(message.which())
+                self.log.append((True, frame.to_bytes()))
+
+                if message.which() == 'bootstrap':
+                    self.send(bootstrap_return(message.get('bootstrap').get('questionId')))
+                elif message.which() == 'call':
+                    self.send(self.handler(message.get('call')))
+
+        self.sock.close()
+
+    def send(self, data):
+        self.log.append((False, data))
+        self.sock.sendall(data)
+
+
+class Foo:
+    def foo(self, params):
+        return 'foo' * params.get('i')
+
+
+class Pipeline:
+    """Serves `TestPipeline`: `getCap` puts a `Foo` in the box."""
+
+    def __init__(self, root):
+        self.root = root
+
+    def getCap(self, params):
+        return {'s': str(params.get('n')), 'outBox': {'cap': wrapper.ServerPy(self.root.TestCallee, Foo())}}
+
+
+class Sink:
+    """Takes a stream of numbers; 2 pauses its server until `end`, 99 fails."""
+
+    def __init__(self):
+        self.server = None
+        self.seen = []
+        self.held = None
+
+    def write(self, params):
+        n = params.get('n')
+        if n == 99:
+            raise ValueError('no 99s')
+
+        self.seen.append(n)
+        if n == 2:
+            self.server.pause()
+
+    def end(self, params):
+        self.held = self.server.held
+        self.server.resume()
+        return len(self.seen)
+
+
+class TestRpc(unittest.TestCase):
+
+    def setUp(self) -> None:
+        super().setUp()
+        self.test = wrapper.compile(os.path.join(HERE, 'test.capnp'))
+        [self.root] = self.test.id
+        [self.rpc] = wrapper.compile(os.path.join(HERE, 'rpc.capnp')).id
+
+        # the schema also has the root of /capnp/stream.capnp, for `-> stream`
+        self.rpc_test = next(x for x in wrapper.compile(os.path.join(HERE, 'rpc_test.capnp')).id if hasattr(x, 'TestPipeline'))
+
+    def results(self, answer, content=None):
+        msg = self.rpc.Message.new_message()
+        ret = msg.root.init('return')
+        ret.set('answerId', answer)
+        results = ret.init('results')
+        if content is not None:
+            results.set('content', content)
+        return msg.to_bytes()
+
+    def exception(self, answer, reason):
+        msg = self.rpc.Message.new_message()
+        ret = msg.root.init('return')
+        ret.set('answerId', answer)
+        ret.set('exception', {'reason': reason, 'type': 'unimplemented'})
+        return msg.to_bytes()
+
+    def test_client(self):
+        seen = []
+
+        def handler(call):
+            answer = call.get('questionId')
+
+            if call.get('methodId') == 1:
+                params = call.get('params').get('content').as_struct(self.root.TestBigStruct)
+                seen.append(params.get('int32Field'))
+                return self.results(answer)
+
+            if call.get('methodId') == 2:
+                big = self.root.TestBigStruct.new_message()
+                big.root.set('uint16Field', 7)
+                return self.results(answer, big.root)
+
+            return self.exception(answer, 'no qux here')
+
+        ours, theirs = socket.socketpair()
+        peer = StandIn(self.rpc, theirs, handler)
+        peer.start()
+
+        conn = wrapper.connect_fds(ours.fileno(), ours.fileno())
+        cap = conn.bootstrap(self.root.TestExtends)
+
+        self.assertEqual(cap.call('grault').wait().get('uint16Field'), 7)
+
+        request = cap.request('corge')
+        request.params.set('int32Field', -5)
+        request.send().wait()
+        self.assertEqual(seen, [-5])
+
+        with self.assertRaises(wrapper.RpcError):
+            cap.call('qux').wait()
+
+        with self.assertRaises(AttributeError):
+            cap.request('nope')
+
+        del cap, request
+        conn.close()
+        ours.close()
+        peer.join()
+
+        self.assertEqual(peer.received[0], 'bootstrap')
+        self.assertIn('finish', peer.received)
+        self.assertIn('release', peer.received)
+
+    def test_server(self):
+        class Impl:
+            def __init__(self):
+                self.calls = []
+
+            def foo(self, params):
+                self.calls.append((params.get('i'), params.get('j')))
+                return 'x' * params.get('i')
+
+            def bar(self, params):
+                return None
+
+            def baz(self, params):
+                raise NotImplementedError('later')
+
+            def bazz(self, params):
+                raise ValueError('bad struct')
+
+            def grault(self, params):
+                return {'int32Field': 3}
+
+        impl = Impl()
+
+        with tempfile.TemporaryDirectory() as tmp:
+            listener = wrapper.listen(os.path.join(tmp, 'rpc.sock'), wrapper.ServerPy(self.root.TestExtends, impl))
+            server = threading.Thread(target=lambda: listener.accept().run(), daemon=True)
+            server.start()
+
+            conn = wrapper.connect(os.path.join(tmp, 'rpc.sock'))
+
+        # inherited methods are served under the id of the base interface
+        cap = conn.bootstrap(self.root.TestInterface)
+        self.assertEqual(cap.call('foo', i=3, j=True).wait().get('x'), 'xxx')
+        cap.call('bar').wait()
+
+        with self.assertRaisesRegex(wrapper.RpcError, '^unimplemented: NotImplementedError: later'):
+            cap.call('baz').wait()
+
+        with self.assertRaisesRegex(wrapper.RpcError, '^failed: ValueError: bad struct'):
+            cap.call('bazz').wait()
+
+        extends = conn.bootstrap(self.root.TestExtends)
+        self.assertEqual(extends.call('grault').wait().get('int32Field'), 3)
+        self.assertEqual(extends.call('foo', i=1, j=False).wait().get('x'), 'x')
+
+        with self.assertRaisesRegex(wrapper.RpcError, '^unimplemented'):
+            extends.call('qux').wait()
+
+        self.assertEqual(impl.calls, [(3, True), (1, False)])
+
+        conn.close()
+        server.join()
+
+    def test_return_keeps_param_caps(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            listener = wrapper.listen(os.path.join(tmp, 'rpc.sock'), wrapper.ServerPy(self.root.TestInterface, Foo()))
+            server = threading.Thread(target=lambda: listener.accept().run(), daemon=True)
+            server.start()
+
+            sock = socket.socket(socket.AF_UNIX)
+            sock.connect(os.path.join(tmp, 'rpc.sock'))
+
+        [foo, *_] = self.root.TestInterface.methods
+        params = foo.params.new_message()
+        params.root.set('i', 2)
+
+        boot = self.rpc.Message.new_message()
+        boot.root.init('bootstrap').set('questionId', 0)
+
+        msg = self.rpc.Message.new_message()
+        call = msg.root.init('call')
+        call.set('questionId', 1)
+        call.init('target').init('promisedAnswer').set('questionId', 0)
+        call.set('interfaceId', foo.interface_id)
+        call.set('methodId', 0)
+        call.init('params').set('content', params.root)
+
+        sock.sendall(boot.to_bytes() + msg.to_bytes())
+
+        decoder = wrapper.FrameDecoderPy()
+        returns = {}
+
+        while 1 not in returns:
+            for frame in decoder.feed(sock.recv(65536)):
+                message = self.rpc.Message.read(frame)
+                if message.which() == 'return':
+                    returns[message.get('return').get('answerId')] = message.get('return')
+
+        # the caps in the params are released one by one, not by the return
+        self.assertFalse(returns[1].get('releaseParamCaps'))
+
+        sock.close()
+        server.join()
+
+    def test_method_table(self):
+        table = [(x.name, x.ordinal) for x in self.root.TestExtends.methods]
+        self.assertEqual(table, [
+            ('qux', 0), ('corge', 1), ('grault', 2),
+            ('foo', 0), ('bar', 1), ('baz', 2), ('bazz', 3),
+        ])
+
+        [foo, *_] = self.root.TestInterface.methods
+        self.assertEqual(self.root.TestExtends.methods[3].interface_id, foo.interface_id)
+
+        [get] = self.rpc_test.GenericExtend.methods
+        self.assertEqual(get.name, 'get')
+        self.assertEqual(get.bindings, {'T': 'Data'})
+
+        [get] = self.rpc_test.GenericExtend2.methods
+        self.assertEqual(get.bindings, {'T': 'GenericBase(Data)'})
+
+        [get] = self.rpc_test.GenericBase.methods
+        self.assertEqual(get.bindings, {})
+
+    def test_method_types(self):
+        [foo, *_] = self.root.TestInterface.methods
+
+        # named parameter lists get a struct of their own, outside any scope
+        params = foo.params.new_message()
+        params.root.set('i', 5)
+        self.assertEqual(foo.params.read(params.to_bytes()).get('i'), 5)
+        self.assertEqual(foo.results.new_message().root.get('x'), '')
+
+        [call] = self.root.TestImplicitMethodParams.methods
+        self.assertEqual(call.implicit_parameters, ['T', 'U'])
+        self.assertEqual(call.result_type, 'TestGenerics(T, U)')
+        self.assertEqual(call.results.children(), self.root.TestGenerics.children())
+
+        call4 = self.root.TestImplicitMethodParamsInGeneric.methods[4]
+        self.assertEqual(call4.param_type, 'TestGenerics(V, V)')
+        self.assertEqual(call4.result_type, 'TestGenerics(V, AnyPointer)')
+
+    def test_pipeline(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            listener = wrapper.listen(os.path.join(tmp, 'rpc.sock'), wrapper.ServerPy(self.rpc_test.TestPipeline, Pipeline(self.rpc_test)))
+            server = threading.Thread(target=lambda: listener.accept().run(), daemon=True)
+            server.start()
+
+            conn = wrapper.connect(os.path.join(tmp, 'rpc.sock'))
+
+        cap = conn.bootstrap(self.rpc_test.TestPipeline)
+        promise = cap.call('getCap', n=5)
+
+        # addressed to the pending answer, without waiting for it
+        foo = promise.pipeline.get('outBox').get('cap')
+        self.assertEqual(foo.call('foo', i=2).wait().get('x'), 'foofoo')
+
+        results = promise.wait()
+        self.assertEqual(results.get('s'), '5')
+        self.assertEqual(results.get('outBox').get('cap').call('foo', i=1).wait().get('x'), 'foo')
+
+        with self.assertRaises(TypeError):
+            promise.pipeline.get('s')
+
+        conn.close()
+        server.join()
+
+    def test_loopback(self):
+        pair = wrapper.loopback_pair(wrapper.ServerPy(self.rpc_test.TestPipeline, Pipeline(self.rpc_test)), latency=0.5)
+
+        cap = pair.client.bootstrap(self.rpc_test.TestPipeline)
+        self.assertEqual(pair.clock, 1.0)
+
+        # the pipelined call goes out with the first, one round trip for both
+        promise = cap.call('getCap', n=1)
+        foo = promise.pipeline.get('outBox').get('cap').call('foo', i=1)
+        self.assertEqual(foo.wait().get('x'), 'foo')
+        self.assertEqual(promise.wait().get('s'), '1')
+        self.assertEqual(pair.clock, 2.0)
+
+        pair.client.close()
+        self.assertTrue(pair.client.closed)
+
+        lossy = wrapper.loopback_pair(wrapper.ServerPy(self.rpc_test.TestPipeline, Pipeline(self.rpc_test)), drop_rate=1.0)
+
+        with self.assertRaisesRegex(wrapper.RpcError, 'no message is in flight'):
+            lossy.client.bootstrap(self.rpc_test.TestPipeline)
+
+        self.assertEqual(lossy.dropped, 1)
+
+        # the server connection goes with the pair, the client sees it close
+        orphan = wrapper.loopback_pair(wrapper.ServerPy(self.rpc_test.TestPipeline, Pipeline(self.rpc_test))).client
+
+        with self.assertRaisesRegex(wrapper.RpcError, 'closed'):
+            orphan.bootstrap(self.rpc_test.TestPipeline)
+
+    def test_trace(self):
+        def handler(call):
+            big = self.root.TestBigStruct.new_message()
+            big.root.set('uint16Field', 7)
+            return self.results(call.get('questionId'), big.root)
+
+        ours, theirs = socket.socketpair()
+        peer = StandIn(self.rpc, theirs, handler)
+        peer.start()
+
+        conn = wrapper.connect_fds(ours.fileno(), ours.fileno())
+        conn.bootstrap(self.root.TestExtends).call('grault').wait()
+
+        conn.close()
+        ours.close()
+        peer.join()
+
+        tracer = wrapper.RpcTracerPy(self.test)
+        lines = [x for sent, data in peer.log for x in tracer.feed(data, sent)]
+
+        self.assertEqual(lines[:2], [
+            '> bootstrap q=0',
+            '< return a=0 results=<bootstrap capability> caps=[senderHosted 0]',
+        ])
+        self.assertIn('> call q=1 target=import 0 TestExtends.grault params=()', lines)
+        self.assertTrue(any(x.startswith('< return a=1 results=(') and 'uint16Field = 7' in x for x in lines))
+        self.assertIn('> finish q=1 releaseResultCaps=false', lines)
+
+        # without a schema the ids are all there is
+        anonymous = wrapper.RpcTracerPy()
+        self.assertTrue(any(
+            x.startswith('> call q=1 target=import 0 0x') and x.endswith('.@2 params=<not decoded>')
+            for sent, data in peer.log for x in anonymous.feed(data, sent)
+        ))
+
+    def test_streaming(self):
+        sink = Sink()
+        sink.server = wrapper.ServerPy(self.rpc_test.TestStreaming, sink)
+        pair = wrapper.loopback_pair(sink.server)
+        cap = pair.client.bootstrap(self.rpc_test.TestStreaming)
+
+        write, end = self.rpc_test.TestStreaming.methods
+        self.assertTrue(write.streaming)
+        self.assertFalse(end.streaming)
+
+        with self.assertRaises(TypeError):
+            cap.stream('end')
+
+        # a window of one byte holds one call: each send waits for the last
+        stream = cap.stream('write', window=1)
+        stream.send(n=0)
+        stream.send(n=1)
+        self.assertEqual(sink.seen, [0])
+        stream.wait()
+        self.assertEqual(sink.seen, [0, 1])
+        self.assertEqual(stream.pending, 0)
+
+        # with room in the window, nothing is waited for
+        stream = cap.stream('write')
+        stream.send(n=2)
+        stream.send(n=3)
+        self.assertEqual(sink.seen, [0, 1])
+        self.assertEqual(stream.pending, 2)
+
+        # the paused server runs both calls but returns them only on resume
+        self.assertEqual(cap.call('end').wait().get('count'), 4)
+        self.assertEqual(sink.held, 2)
+        self.assertFalse(sink.server.paused)
+        self.assertEqual(stream.pending, 0)
+
+        stream.send(n=99)
+        with self.assertRaisesRegex(wrapper.RpcError, 'no 99s'):
+            stream.wait()
+        with self.assertRaisesRegex(wrapper.RpcError, 'no 99s'):
+            stream.send(n=4)
+        self.assertEqual(sink.seen, [0, 1, 2, 3])
+
+    def test_mock_server(self):
+        mock = wrapper.mock_server(
+            self.root.TestExtends,
+            grault={'int32Field': 4},
+            foo=lambda params: 'x' * params.get('i'),
+        )
+        pair = wrapper.loopback_pair(mock)
+
+        extends = pair.client.bootstrap(self.root.TestExtends)
+        self.assertEqual(extends.call('grault').wait().get('int32Field'), 4)
+        extends.call('corge', int32Field=9).wait()
+
+        base = pair.client.bootstrap(self.root.TestInterface)
+        self.assertEqual(base.call('foo', i=2).wait().get('x'), 'xx')
+
+        # without a fixture the results are all defaults
+        self.assertEqual(base.call('bazz').wait().get('r').get('int32Field'), 0)
+
+        self.assertEqual([name for name, _ in mock.calls], ['grault', 'corge', 'foo', 'bazz'])
+        self.assertEqual(mock.calls[1][1].get('int32Field'), 9)
+
+        with self.assertRaises(AttributeError):
+            wrapper.mock_server(self.root.TestExtends, nope={})
+
+        with self.assertRaisesRegex(TypeError, 'not an interface'):
+            wrapper.mock_server(self.root.TestAllTypes)
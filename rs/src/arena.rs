@@ -69,6 +69,10 @@ impl <A: Arena>ArenaRef<A> {
         return ArenaRc::from(&self.arena)
     }
 
+    pub fn rc(&self) -> Rc<A> {
+        self.arena.clone()
+    }
+
     pub fn get(&self) -> Option<&A::Item> {
         self.arena.get(&self.idx)
     }
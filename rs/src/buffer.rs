@@ -0,0 +1,205 @@
+//! Buffer protocol for lists of numbers, so numpy and `memoryview` see the
+//! message memory without copying it.
+
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+use capnp::private::layout::{ElementSize, PointerBuilder};
+use capnpc::schema_capnp;
+use pyo3::prelude::*;
+use pyo3::buffer::PyBuffer;
+use pyo3::types::PyAny;
+use pyo3::{exceptions, ffi, PyBufferProtocol};
+
+use crate::Error;
+use crate::dynamic::{self, ListReaderPy, TypeReader};
+
+/// The `struct` module format of a list element and its size in bytes; None
+/// for elements that are not plain little-endian numbers in memory (bools,
+/// pointers and everything on big-endian hosts).
+pub fn element_format(element: &TypeReader) -> Result<Option<(&'static str, usize)>, Error> {
+    use schema_capnp::type_ as T;
+
+    if cfg!(target_endian = "big") {
+        return Ok(None);
+    }
+
+    // NUL-terminated, Py_buffer.format is a C string
+    Ok(Some(match element.which()? {
+        T::Int8(()) => ("b\0", 1),
+        T::Int16(()) => ("h\0", 2),
+        T::Int32(()) => ("i\0", 4),
+        T::Int64(()) => ("q\0", 8),
+        T::Uint8(()) => ("B\0", 1),
+        T::Uint16(()) => ("H\0", 2),
+        T::Uint32(()) => ("I\0", 4),
+        T::Uint64(()) => ("Q\0", 8),
+        T::Float32(()) => ("f\0", 4),
+        T::Float64(()) => ("d\0", 8),
+        T::Enum(_) => ("H\0", 2),
+        _ => return Ok(None),
+    }))
+}
+
+fn item(format: &str) -> &str {
+    format.trim_end_matches('\0').trim_start_matches(|c| c == '@' || c == '=' || c == '<')
+}
+
+/// Signed, unsigned or float, for a single-item format in native or
+/// little-endian byte order.
+fn kind(format: &str) -> Option<char> {
+    match item(format) {
+        "b" | "h" | "i" | "l" | "q" | "n" => Some('i'),
+        "B" | "H" | "I" | "L" | "Q" | "N" => Some('u'),
+        "e" | "f" | "d" => Some('f'),
+        _ => None,
+    }
+}
+
+// plain bytes, as opposed to bytes that hold numbers of their own
+fn untyped(format: &str) -> bool {
+    match item(format) {
+        "B" | "c" => true,
+        _ => false,
+    }
+}
+
+// list element counts take 29 bits
+const MAX_COUNT: usize = (1 << 29) - 1;
+
+pub fn is_buffer(value: &PyAny) -> bool {
+    unsafe { ffi::PyObject_CheckBuffer(value.as_ptr()) != 0 }
+}
+
+/// Initializes a list of numbers from a buffer object with one copy. Items
+/// must match the element type in kind and size; a buffer of plain bytes
+/// (`B` or `c`) is taken as the raw little-endian encoding instead.
+pub fn init_list(ptr: PointerBuilder, element: &TypeReader, value: &PyAny) -> Result<(), Error> {
+    let (format, itemsize) = element_format(element)?
+        .ok_or(Error::Type("only lists of numbers and enums are initialized from buffers".into()))?;
+
+    let buffer = PyBuffer::get(value.py(), value)?;
+
+    if !buffer.is_c_contiguous() {
+        return Err(Error::Type("buffer is not C-contiguous".into()));
+    }
+
+    let source = buffer.format().to_string_lossy();
+
+    let count = if kind(&source).is_some() && kind(&source) == kind(format) && buffer.item_size() == itemsize {
+        buffer.item_count()
+    } else if untyped(&source) {
+        if buffer.len_bytes() % itemsize != 0 {
+            return Err(Error::Value(format!(
+                "{} bytes is not a whole number of {}-byte elements", buffer.len_bytes(), itemsize
+            )));
+        }
+
+        buffer.len_bytes() / itemsize
+    } else {
+        return Err(Error::Type(format!(
+            "buffer of {:?} items does not fit a list of {:?}", source, format.trim_end_matches('\0')
+        )));
+    };
+
+    if count > MAX_COUNT {
+        return Err(Error::Value(format!("{} elements do not fit in a list", count)));
+    }
+
+    let list = ptr.init_list(dynamic::element_size(element)?, count as u32);
+    let target = list.into_raw_bytes();
+
+    unsafe {
+        ptr::copy_nonoverlapping(buffer.buf_ptr() as *const u8, target.as_mut_ptr(), count * itemsize);
+    }
+
+    Ok(())
+}
+
+/// What a `memoryview` of a list reader points at: a handle on the message,
+/// so the memory outlives the reader it came from, and the shape and strides
+/// the `Py_buffer` refers to.
+#[pyclass]
+pub struct ListView {
+    list: ListReaderPy,
+    shape: [isize; 1],
+    strides: [isize; 1],
+}
+
+fn buffer_error(message: &str) -> PyErr {
+    PyErr::new::<exceptions::BufferError, _>(message.to_string())
+}
+
+#[pyproto]
+impl PyBufferProtocol for ListReaderPy {
+    fn bf_getbuffer(&self, view: *mut ffi::Py_buffer, flags: c_int) -> PyResult<()> {
+        let gil = GILGuard::acquire();
+        let py = gil.python();
+
+        if view.is_null() {
+            return Err(buffer_error("view is null"));
+        }
+
+        if flags & ffi::PyBUF_WRITABLE == ffi::PyBUF_WRITABLE {
+            return Err(buffer_error("list readers are read-only"));
+        }
+
+        let (format, itemsize) = element_format(&self.element())?
+            .ok_or(buffer_error("only lists of numbers and enums have a buffer"))?;
+
+        let count = self.reader().len() as usize;
+        let mut data = self.reader().into_raw_bytes();
+        let mut step = itemsize;
+
+        if count > 0 {
+            if let ElementSize::InlineComposite = self.reader().get_element_size() {
+                // lists written as structs by a newer schema keep the number
+                // first in the data section of each struct
+                let first = self.reader().get_struct_element(0);
+                let data_bytes = (first.get_data_section_size() / 8) as usize;
+
+                if data_bytes < itemsize {
+                    return Err(buffer_error("list elements are structs without the number"));
+                }
+
+                step = data_bytes + first.get_pointer_section_size() as usize * 8;
+                data = first.get_data_section_as_blob();
+            } else if data.len() != count * itemsize {
+                return Err(buffer_error("list elements do not have the size of the type"));
+            }
+        }
+
+        if step != itemsize && flags & ffi::PyBUF_STRIDES != ffi::PyBUF_STRIDES {
+            return Err(buffer_error("list elements are not contiguous, request a strided buffer"));
+        }
+
+        let owner = Py::new(py, ListView {
+            list: self.share(),
+            shape: [count as isize],
+            strides: [step as isize],
+        })?;
+
+        let shape = owner.as_ref(py).shape.as_ptr() as *mut isize;
+        let strides = owner.as_ref(py).strides.as_ptr() as *mut isize;
+
+        unsafe {
+            (*view).obj = owner.into_ptr();
+            (*view).buf = data.as_ptr() as *mut c_void;
+            (*view).len = (count * itemsize) as isize;
+            (*view).readonly = 1;
+            (*view).itemsize = itemsize as isize;
+            (*view).format = if flags & ffi::PyBUF_FORMAT == ffi::PyBUF_FORMAT {
+                format.as_ptr() as *mut _
+            } else {
+                ptr::null_mut()
+            };
+            (*view).ndim = 1;
+            (*view).shape = if flags & ffi::PyBUF_ND == ffi::PyBUF_ND { shape } else { ptr::null_mut() };
+            (*view).strides = if flags & ffi::PyBUF_STRIDES == ffi::PyBUF_STRIDES { strides } else { ptr::null_mut() };
+            (*view).suboffsets = ptr::null_mut();
+            (*view).internal = ptr::null_mut();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use capnp::Word;
+use capnp::private::layout::{PointerBuilder, PointerReader, StructReader};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::Error;
+use crate::dynamic::RootPointer;
+use crate::framing::Frame;
+use crate::message::{BuilderArena, BuilderOptions};
+
+/// Encodes whatever `fill` copies into the root pointer in canonical form:
+/// a single segment, objects in preorder, trailing zero words of struct
+/// sections truncated. `words` is an upper bound of the content size.
+fn canonical<F>(words: u64, fill: F) -> Result<Vec<u8>, Error>
+    where F: FnOnce(PointerBuilder) -> capnp::Result<()>
+{
+    let mut options = BuilderOptions::new();
+    options.first_segment_words = words as u32 + 1;
+
+    let arena = BuilderArena::new(&options);
+    fill(arena.root_pointer())?;
+
+    let segments = arena.segments();
+
+    if segments.len() != 1 {
+        return Err(Error::Text("canonical form does not fit a single segment".into()));
+    }
+
+    Ok(Word::words_to_bytes(segments[0]).to_vec())
+}
+
+/// The canonical encoding of a message rooted at `reader`, without the
+/// stream framing header.
+pub fn canonicalize_struct(reader: &StructReader) -> Result<Vec<u8>, Error> {
+    canonical(reader.total_size()?.word_count, |mut x| x.set_struct(reader, true))
+}
+
+pub fn canonicalize_pointer(reader: PointerReader) -> Result<Vec<u8>, Error> {
+    canonical(reader.total_size()?.word_count, |mut x| x.copy_from(reader, true))
+}
+
+/// Whether `data` (a bare segment, no framing header) is already canonical.
+/// Anything that does not even decode is not.
+pub fn is_canonical(data: &[u8]) -> bool {
+    if data.is_empty() || data.len() % 8 != 0 {
+        return false;
+    }
+
+    let message = Frame::single_segment(data).into_reader(capnp::message::ReaderOptions::new());
+
+    let root: RootPointer = match message.get_root() {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+
+    match canonicalize_pointer(root.0) {
+        Ok(x) => x == data,
+        Err(_) => false,
+    }
+}
+
+pub fn hash_bytes(data: &[u8]) -> isize {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+
+    // -1 is the error marker of tp_hash
+    match hasher.finish() as isize {
+        -1 => -2,
+        x => x,
+    }
+}
+
+#[pyclass]
+pub struct IsCanonicalFun {}
+
+#[pymethods]
+impl IsCanonicalFun {
+    #[call]
+    fn is_canonical(&self, data: &PyBytes) -> PyResult<bool> {
+        Ok(is_canonical(data.as_bytes()))
+    }
+}
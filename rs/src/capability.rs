@@ -0,0 +1,253 @@
+//! Cap tables.
+//!
+//! The layout API only reads and writes capability pointers through a cap
+//! table of `ClientHook`s: reading takes the hook at the pointer's index,
+//! writing appends a hook and stores its position. Every hook in the tables
+//! of this crate is a `Slot` that says what the entry stands for, so a
+//! pointer read back yields an `Entry` and a finished table lists, in wire
+//! order, what was written.
+//!
+//! Messages read or built outside a connection have no capabilities to
+//! refer to, only indices into a table kept elsewhere. Their pointers read
+//! as placeholders that carry the index: a builder lists the indices written
+//! into it by position, and a reader is given that list with the bytes, or
+//! without one takes the index on the wire.
+
+use std::cell::UnsafeCell;
+
+use capnp::capability::{Promise, RemotePromise, Request};
+use capnp::message::HeapAllocator;
+use capnp::private::capability::{ClientHook, ParamsHook, PipelineHook, PipelineOp, RequestHook, ResultsHook};
+use capnp::private::layout::{CapTableBuilder, CapTableReader, PointerBuilder, PointerReader};
+use capnp::message::ReaderSegments;
+use capnp::{any_pointer, MessageSize, Word};
+use pyo3::basic::CompareOp;
+use pyo3::prelude::*;
+use pyo3::PyObjectProtocol;
+
+use crate::Error;
+use crate::rpc::Cap;
+
+/// What a capability pointer refers to.
+#[derive(Clone)]
+pub enum Entry {
+    /// Position in the cap table that travels beside a plain message.
+    Index(u32),
+    /// A cap table entry the sender left empty.
+    Null,
+    Cap(Cap),
+}
+
+#[derive(Clone)]
+struct Slot(Entry);
+
+impl ClientHook for Slot {
+    fn add_ref(&self) -> Box<dyn ClientHook> {
+        Box::new(self.clone())
+    }
+
+    fn new_call(&self, _: u64, _: u16, _: Option<MessageSize>) -> Request<any_pointer::Owned, any_pointer::Owned> {
+        Request::new(Box::new(BrokenRequest {
+            message: capnp::message::Builder::new_default(),
+        }))
+    }
+
+    fn call(&self, _: u64, _: u16, _: Box<dyn ParamsHook>, _: Box<dyn ResultsHook>) -> Promise<(), capnp::Error> {
+        Promise::err(not_callable())
+    }
+
+    fn get_brand(&self) -> usize {
+        slot_brand()
+    }
+
+    fn get_ptr(&self) -> usize {
+        self as *const Slot as usize
+    }
+
+    fn get_resolved(&self) -> Option<Box<dyn ClientHook>> {
+        None
+    }
+
+    fn when_more_resolved(&self) -> Option<Promise<Box<dyn ClientHook>, capnp::Error>> {
+        None
+    }
+}
+
+// cap table entries are called through the connection, never through the
+// layout API
+fn not_callable() -> capnp::Error {
+    capnp::Error::failed("cap table entries cannot be called directly".into())
+}
+
+struct BrokenRequest {
+    message: capnp::message::Builder<HeapAllocator>,
+}
+
+impl RequestHook for BrokenRequest {
+    fn get(&mut self) -> any_pointer::Builder {
+        self.message.init_root()
+    }
+
+    fn get_brand(&self) -> usize {
+        0
+    }
+
+    fn send(self: Box<Self>) -> RemotePromise<any_pointer::Owned> {
+        RemotePromise {
+            promise: Promise::err(not_callable()),
+            pipeline: any_pointer::Pipeline::new(Box::new(BrokenPipeline)),
+        }
+    }
+
+    fn tail_send(self: Box<Self>) -> Option<(u32, Promise<(), capnp::Error>, Box<dyn PipelineHook>)> {
+        None
+    }
+}
+
+struct BrokenPipeline;
+
+impl PipelineHook for BrokenPipeline {
+    fn add_ref(&self) -> Box<dyn PipelineHook> {
+        Box::new(BrokenPipeline)
+    }
+
+    fn get_pipelined_cap(&self, _: &[PipelineOp]) -> Box<dyn ClientHook> {
+        Box::new(Slot(Entry::Null))
+    }
+}
+
+// the address of a static is unique to this crate, other hooks carry
+// their own brand
+static SLOT_BRAND: u8 = 0;
+
+fn slot_brand() -> usize {
+    &SLOT_BRAND as *const u8 as usize
+}
+
+fn entry(hook: &dyn ClientHook) -> Result<Entry, Error> {
+    if hook.get_brand() != slot_brand() {
+        return Err(Error::Rpc("the cap table holds a capability of another implementation".into()));
+    }
+
+    Ok(unsafe { &*(hook as *const dyn ClientHook as *const Slot) }.0.clone())
+}
+
+/// Readers and builders imbued with a table keep a raw pointer to it, so it
+/// is boxed and must outlive them. Builders append to it behind our back.
+pub struct CapTable {
+    hooks: UnsafeCell<Vec<Option<Box<dyn ClientHook>>>>,
+}
+
+// indices past this read as invalid pointers in plain messages
+const MAX_PLAIN_ENTRIES: u32 = 1 << 16;
+
+impl CapTable {
+    pub fn new(entries: Vec<Entry>) -> Box<CapTable> {
+        Box::new(CapTable {
+            hooks: UnsafeCell::new(entries.into_iter().map(|x| Some(Box::new(Slot(x)) as Box<dyn ClientHook>)).collect()),
+        })
+    }
+
+    /// The entries in wire order.
+    pub fn entries(&self) -> Result<Vec<Entry>, Error> {
+        unsafe { &*self.hooks.get() }.iter()
+            .map(|x| x.as_ref().map(|x| entry(x.as_ref())).unwrap_or(Ok(Entry::Null)))
+            .collect()
+    }
+
+    /// A table for a message read from bytes without the table that travels
+    /// beside it: entry i is `Entry::Index(i)` for every index that a
+    /// capability pointer in `segments` holds. A data word that happens to
+    /// look like such a pointer only makes the table longer.
+    pub fn plain<S: ReaderSegments>(segments: &S) -> Box<CapTable> {
+        let mut len = 0;
+        let mut id = 0;
+
+        while let Some(words) = segments.get_segment(id) {
+            for x in Word::words_to_bytes(words).chunks(8) {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(x);
+                let word = u64::from_le_bytes(bytes);
+
+                // kind "other" with all other bits of the low half clear
+                let index = (word >> 32) as u32;
+                if word as u32 == 3 && index < MAX_PLAIN_ENTRIES {
+                    len = len.max(index + 1);
+                }
+            }
+
+            id += 1;
+        }
+
+        CapTable::new((0..len).map(Entry::Index).collect())
+    }
+
+    pub fn imbue_reader(&self, ptr: &mut PointerReader) {
+        ptr.imbue(CapTableReader::Plain(self.hooks.get() as *const _));
+    }
+
+    pub fn imbue_builder(&self, ptr: &mut PointerBuilder) {
+        ptr.imbue(CapTableBuilder::Plain(self.hooks.get()));
+    }
+}
+
+/// What a capability pointer refers to; None for a null pointer.
+pub fn read(ptr: &PointerReader) -> Result<Option<Entry>, Error> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+
+    Ok(Some(entry(ptr.get_capability()?.as_ref())?))
+}
+
+/// Appends `entry` to the table `ptr` was imbued with and points at it.
+pub fn write(ptr: &PointerBuilder, entry: Entry) {
+    ptr.set_capability(Box::new(Slot(entry)));
+}
+
+/// A capability pointer of a message without a connection: the index into
+/// a table kept beside the message. Writing one into a builder appends it to
+/// the builder's table, see `Builder.cap_table`; reading it back from bytes
+/// takes that table, see `read(..., cap_table=...)`, or else the index on
+/// the wire.
+#[pyclass]
+pub struct CapabilityPlaceholderPy {
+    pub index: u32,
+}
+
+#[pymethods]
+impl CapabilityPlaceholderPy {
+    #[new]
+    fn __new__(obj: &PyRawObject, index: u32) -> PyResult<()> {
+        obj.init(CapabilityPlaceholderPy { index });
+        Ok(())
+    }
+
+    #[getter]
+    fn index(&self) -> PyResult<u32> {
+        Ok(self.index)
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for CapabilityPlaceholderPy {
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("<capability placeholder {}>", self.index))
+    }
+
+    fn __richcmp__(&self, other: &PyAny, op: CompareOp) -> PyResult<PyObject> {
+        let gil = GILGuard::acquire();
+        let py = gil.python();
+
+        let equal = match other.downcast_ref::<CapabilityPlaceholderPy>() {
+            Ok(x) => x.index == self.index,
+            Err(_) => return Ok(py.NotImplemented()),
+        };
+
+        Ok(match op {
+            CompareOp::Eq => equal.to_object(py),
+            CompareOp::Ne => (!equal).to_object(py),
+            _ => py.NotImplemented(),
+        })
+    }
+}
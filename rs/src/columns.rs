@@ -0,0 +1,288 @@
+//! Columnar decoding: one pass over a stream of messages fills one array per
+//! selected field, for handing to dataframe libraries.
+//!
+//! The schema is only consulted up front, to turn field paths into offsets.
+//! What remains holds no Python or schema references, so the decoding loop
+//! runs with the GIL released.
+
+use std::rc::Rc;
+
+use capnp::private::layout::StructReader;
+use capnpc::schema_capnp;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+use crate::{Error, NodeArena, NodePy};
+use crate::dynamic::{self, FieldReader, NodeRef, RootPointer};
+use crate::framing::{FrameDecoder, DEFAULT_MAX_SEGMENTS, DEFAULT_MAX_WORDS};
+
+// how much of the stream is handed to the frame decoder at a time
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+struct Discriminant {
+    offset: usize,
+    value: u16,
+}
+
+impl Discriminant {
+    fn matches(&self, reader: &StructReader) -> bool {
+        reader.get_data_field::<u16>(self.offset) == self.value
+    }
+}
+
+/// A group (no pointer) or a struct field on the way to the value.
+struct Step {
+    discriminant: Option<Discriminant>,
+    pointer: Option<usize>,
+}
+
+enum Leaf {
+    Bool { offset: usize, mask: bool },
+    // `code` is the `array` module typecode, `size` the width in bytes
+    Number { code: &'static str, size: usize, offset: usize, mask: u64 },
+    Text(usize),
+    Data(usize),
+}
+
+struct Column {
+    steps: Vec<Step>,
+    discriminant: Option<Discriminant>,
+    leaf: Leaf,
+}
+
+enum Values {
+    Number(&'static str, Vec<u8>),
+    Text(Vec<Option<String>>),
+    Data(Vec<Option<Vec<u8>>>),
+}
+
+fn discriminant(node: &NodeRef, field: &FieldReader) -> Result<Option<Discriminant>, Error> {
+    if field.get_discriminant_value() == schema_capnp::field::NO_DISCRIMINANT {
+        return Ok(None);
+    }
+
+    Ok(Some(Discriminant {
+        offset: dynamic::struct_node(node)?.get_discriminant_offset() as usize,
+        value: field.get_discriminant_value(),
+    }))
+}
+
+fn resolve(arena: &Rc<NodeArena>, node: &NodeRef, path: &str) -> Result<Column, Error> {
+    use schema_capnp::type_ as T;
+
+    let parts: Vec<&str> = path.split('.').collect();
+    let mut node = node.clone();
+    let mut steps = Vec::new();
+
+    for part in &parts[..parts.len() - 1] {
+        let field = dynamic::find_field(&node, part)?;
+        let discriminant = discriminant(&node, &field)?;
+
+        node = match field.which()? {
+            schema_capnp::field::Group(x) => {
+                steps.push(Step { discriminant, pointer: None });
+                dynamic::get_node(arena, x.get_type_id())?
+            }
+            schema_capnp::field::Slot(x) => match x.get_type()?.which()? {
+                T::Struct(t) => {
+                    steps.push(Step { discriminant, pointer: Some(x.get_offset() as usize) });
+                    dynamic::get_node(arena, t.get_type_id())?
+                }
+                _ => return Err(Error::Type(format!("{} is not a group or a struct", part))),
+            },
+        };
+    }
+
+    let field = dynamic::find_field(&node, parts[parts.len() - 1])?;
+
+    let slot = match field.which()? {
+        schema_capnp::field::Slot(x) => x,
+        schema_capnp::field::Group(_) => return Err(Error::Type(format!("{} is a group", path))),
+    };
+
+    let offset = slot.get_offset() as usize;
+    let mask = dynamic::default_bits(slot.get_default_value()?)?;
+    let number = |code, size| Leaf::Number { code, size, offset, mask };
+
+    let leaf = match slot.get_type()?.which()? {
+        T::Bool(()) => Leaf::Bool { offset, mask: mask != 0 },
+        T::Int8(()) => number("b", 1),
+        T::Int16(()) => number("h", 2),
+        T::Int32(()) => number("i", 4),
+        T::Int64(()) => number("q", 8),
+        T::Uint8(()) => number("B", 1),
+        T::Uint16(()) => number("H", 2),
+        T::Uint32(()) => number("I", 4),
+        T::Uint64(()) => number("Q", 8),
+        T::Float32(()) => number("f", 4),
+        T::Float64(()) => number("d", 8),
+        T::Enum(_) => number("H", 2),
+        T::Text(()) => Leaf::Text(offset),
+        T::Data(()) => Leaf::Data(offset),
+        _ => return Err(Error::Type(format!("{} has no columnar form", path))),
+    };
+
+    Ok(Column { steps, discriminant: discriminant(&node, &field)?, leaf })
+}
+
+impl Column {
+    fn values(&self) -> Values {
+        match self.leaf {
+            Leaf::Bool { .. } => Values::Number("B", Vec::new()),
+            Leaf::Number { code, .. } => Values::Number(code, Vec::new()),
+            Leaf::Text(_) => Values::Text(Vec::new()),
+            Leaf::Data(_) => Values::Data(Vec::new()),
+        }
+    }
+
+    /// Appends this column's value in `root`. Inactive union members and
+    /// null structs along the way read as defaults, text and data as None.
+    fn read(&self, root: StructReader, out: &mut Values) -> capnp::Result<()> {
+        let mut reader = root;
+        let mut present = true;
+
+        for step in &self.steps {
+            if step.discriminant.map(|x| x.matches(&reader)) == Some(false) {
+                present = false;
+                break;
+            }
+
+            if let Some(offset) = step.pointer {
+                reader = reader.get_pointer_field(offset).get_struct(None)?;
+            }
+        }
+
+        present = present && self.discriminant.map(|x| x.matches(&reader)) != Some(false);
+
+        match (&self.leaf, out) {
+            (&Leaf::Bool { offset, mask }, Values::Number(_, out)) => {
+                let value = if present { reader.get_bool_field(offset) } else { false };
+                out.push((value ^ mask) as u8);
+            }
+            (&Leaf::Number { size, offset, mask, .. }, Values::Number(_, out)) => {
+                let raw = match size {
+                    _ if !present => 0,
+                    1 => reader.get_data_field::<u8>(offset) as u64,
+                    2 => reader.get_data_field::<u16>(offset) as u64,
+                    4 => reader.get_data_field::<u32>(offset) as u64,
+                    _ => reader.get_data_field::<u64>(offset),
+                };
+                let bits = raw ^ mask;
+
+                match size {
+                    1 => out.push(bits as u8),
+                    2 => out.extend_from_slice(&(bits as u16).to_ne_bytes()),
+                    4 => out.extend_from_slice(&(bits as u32).to_ne_bytes()),
+                    _ => out.extend_from_slice(&bits.to_ne_bytes()),
+                }
+            }
+            (&Leaf::Text(offset), Values::Text(out)) => {
+                let ptr = reader.get_pointer_field(offset);
+
+                out.push(if present && !ptr.is_null() { Some(ptr.get_text(None)?.to_string()) } else { None });
+            }
+            (&Leaf::Data(offset), Values::Data(out)) => {
+                let ptr = reader.get_pointer_field(offset);
+
+                out.push(if present && !ptr.is_null() { Some(ptr.get_data(None)?.to_vec()) } else { None });
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+}
+
+// PyErr may not cross threads, errors from the decoding loop are plain capnp ones
+fn detach(error: Error) -> capnp::Error {
+    match error {
+        Error::Capnp(x) => x,
+        Error::Text(x) | Error::Type(x) | Error::Value(x) => capnp::Error::failed(x),
+        _ => capnp::Error::failed("frame decoding failed".into()),
+    }
+}
+
+fn decode(columns: &[Column], data: &[u8], packed: bool, max_segments: usize, max_words: usize) -> capnp::Result<Vec<Values>> {
+    let mut out: Vec<Values> = columns.iter().map(|x| x.values()).collect();
+    let mut decoder = FrameDecoder::new(packed, max_segments, max_words);
+
+    for chunk in data.chunks(CHUNK) {
+        for frame in decoder.feed(chunk).map_err(detach)? {
+            let message = frame.into_reader(capnp::message::ReaderOptions::new());
+            let root: RootPointer = message.get_root()?;
+            let root = root.0.get_struct(None)?;
+
+            for (column, values) in columns.iter().zip(out.iter_mut()) {
+                column.read(root, values)?;
+            }
+        }
+    }
+
+    decoder.close().map_err(detach)?;
+
+    Ok(out)
+}
+
+fn to_python(py: Python, values: Values) -> PyResult<PyObject> {
+    Ok(match values {
+        Values::Number(code, bytes) => {
+            let array = py.import("array")?.call1("array", (code,))?;
+            array.call_method1("frombytes", (PyBytes::new(py, &bytes),))?;
+            array.into()
+        }
+        Values::Text(items) => PyList::new(py, &items).into(),
+        Values::Data(items) => {
+            let items: Vec<PyObject> = items.iter()
+                .map(|x| match x {
+                    Some(x) => PyBytes::new(py, x).into(),
+                    None => py.None(),
+                })
+                .collect();
+
+            PyList::new(py, &items).into()
+        }
+    })
+}
+
+#[pyclass]
+pub struct DecodeColumnsFun {}
+
+#[pymethods]
+impl DecodeColumnsFun {
+    /// Decodes every message in `data` (a byte stream of framed messages
+    /// whose root is `node`) and returns a dict from each dotted path in
+    /// `fields` to an `array.array` of numbers (bools as 0/1, enums as their
+    /// ordinal) or a list of `str`/`bytes`.
+    #[call]
+    #[args(packed = false, max_segments = "DEFAULT_MAX_SEGMENTS", max_words = "DEFAULT_MAX_WORDS")]
+    fn decode_columns(
+        &self,
+        py: Python,
+        node: &NodePy,
+        data: &PyBytes,
+        fields: Vec<String>,
+        packed: bool,
+        max_segments: usize,
+        max_words: usize,
+    ) -> PyResult<PyObject> {
+        let root = dynamic::node_ref(node)?;
+        dynamic::struct_node(&root)?;
+
+        let columns = fields.iter()
+            .map(|x| resolve(&node.i.arena, &root, x))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let bytes = data.as_bytes();
+        let values = py.allow_threads(|| decode(&columns, bytes, packed, max_segments, max_words))
+            .map_err(Error::from)?;
+
+        let r = PyDict::new(py);
+
+        for (name, values) in fields.iter().zip(values) {
+            r.set_item(name, to_python(py, values)?)?;
+        }
+
+        Ok(r.into())
+    }
+}
@@ -0,0 +1,595 @@
+//! CSV export and import of `List(Struct)` values.
+//!
+//! Each row is one list element. Groups and struct fields are flattened into
+//! dotted column names (`structField.int32Field`), a struct with a union
+//! gets a `which` column naming the active member. Lists, AnyPointers and
+//! capabilities have no column.
+//!
+//! Inactive union members and null pointers have no value. By default they
+//! are written as empty cells, and empty cells are left at their default
+//! when importing, so an empty text or data field reads back as null. Pass a
+//! `null` marker to tell the two apart: cells without a value are written
+//! as the marker, only cells equal to it are skipped when importing, and an
+//! empty cell is an empty text or data value.
+
+use std::rc::Rc;
+
+use capnp::private::layout::{ElementSize, StructBuilder, StructReader};
+use capnpc::schema_capnp;
+use pyo3::prelude::*;
+
+use crate::{Error, NodeArena};
+use crate::dynamic::{self, FieldReader, ListBuilderPy, ListReaderPy, NodeRef, StructBuilderPy, TypeReader};
+use crate::json;
+use crate::message;
+use crate::text;
+
+struct Column {
+    name: String,
+    // groups and struct fields leading to the struct that holds the value
+    path: Vec<(NodeRef, FieldReader)>,
+    target: NodeRef,
+    // None for the union discriminant of `target`
+    leaf: Option<FieldReader>,
+}
+
+fn is_scalar(type_: &TypeReader) -> Result<bool, Error> {
+    use schema_capnp::type_ as T;
+
+    Ok(match type_.which()? {
+        T::Struct(_) | T::List(_) | T::AnyPointer(_) | T::Interface(_) => false,
+        _ => true,
+    })
+}
+
+fn active(node: &NodeRef, reader: &StructReader, field: &FieldReader) -> Result<bool, Error> {
+    if field.get_discriminant_value() == schema_capnp::field::NO_DISCRIMINANT {
+        return Ok(true);
+    }
+
+    Ok(dynamic::which_field(node, reader)?.map(|x| x.get_discriminant_value()) == Some(field.get_discriminant_value()))
+}
+
+/// Prefixes conversion errors with the cell they came from.
+fn at(row: usize, column: usize, name: &str) -> impl Fn(Error) -> Error + '_ {
+    move |e| match e {
+        Error::Value(x) => Error::Value(format!("row {}, column {} ({}): {}", row, column, name, x)),
+        e => e,
+    }
+}
+
+/// A parsed cell, checked against its column before anything is written.
+enum Value<'a> {
+    Member(FieldReader),
+    Text(&'a str),
+    Data(Vec<u8>),
+    Bits(u64),
+}
+
+struct Table {
+    arena: Rc<NodeArena>,
+    node: NodeRef,
+}
+
+impl Table {
+    fn child(&self, field: &FieldReader) -> Result<Option<NodeRef>, Error> {
+        match field.which()? {
+            schema_capnp::field::Group(x) => Ok(Some(dynamic::get_node(&self.arena, x.get_type_id())?)),
+            schema_capnp::field::Slot(x) => match x.get_type()?.which()? {
+                schema_capnp::type_::Struct(x) => Ok(Some(dynamic::get_node(&self.arena, x.get_type_id())?)),
+                _ => Ok(None),
+            },
+        }
+    }
+
+    /// Every scalar reachable from the element struct. A struct field whose
+    /// type is already being expanded is skipped, recursive schemas would
+    /// never end otherwise.
+    fn all_columns(&self, node: &NodeRef, prefix: &str, path: &[(NodeRef, FieldReader)], out: &mut Vec<Column>) -> Result<(), Error> {
+        let struct_ = dynamic::struct_node(node)?;
+
+        if struct_.get_discriminant_count() > 0 {
+            out.push(Column { name: format!("{}which", prefix), path: path.to_vec(), target: node.clone(), leaf: None });
+        }
+
+        for field in struct_.get_fields()? {
+            let name = format!("{}{}", prefix, field.get_name()?);
+
+            match self.child(&field)? {
+                Some(child) => {
+                    let is_group = match field.which()? {
+                        schema_capnp::field::Group(_) => true,
+                        _ => false,
+                    };
+
+                    if !is_group && (child.get_id() == node.get_id() || path.iter().any(|(x, _)| x.get_id() == child.get_id())) {
+                        continue;
+                    }
+
+                    let mut path = path.to_vec();
+                    path.push((node.clone(), field));
+
+                    self.all_columns(&child, &format!("{}.", name), &path, out)?;
+                }
+                None => {
+                    if let schema_capnp::field::Slot(x) = field.which()? {
+                        if is_scalar(&x.get_type()?)? {
+                            out.push(Column { name, path: path.to_vec(), target: node.clone(), leaf: Some(field) });
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn column(&self, name: &str) -> Result<Column, Error> {
+        let parts: Vec<&str> = name.split('.').collect();
+        let mut node = self.node.clone();
+        let mut path = Vec::new();
+
+        for part in &parts[..parts.len() - 1] {
+            let field = dynamic::find_field(&node, part)?;
+            let child = self.child(&field)?.ok_or(Error::Type(format!("{} is not a group or a struct", part)))?;
+
+            path.push((node, field));
+            node = child;
+        }
+
+        let last = parts[parts.len() - 1];
+
+        let leaf = match dynamic::find_field(&node, last) {
+            Ok(field) => field,
+            Err(_) if last == "which" && dynamic::struct_node(&node)?.get_discriminant_count() > 0 => {
+                return Ok(Column { name: name.to_string(), path, target: node, leaf: None });
+            }
+            Err(x) => return Err(x),
+        };
+
+        match leaf.which()? {
+            schema_capnp::field::Slot(x) if is_scalar(&x.get_type()?)? => {}
+            _ => return Err(Error::Type(format!("column {} is not a scalar field", name))),
+        }
+
+        Ok(Column { name: name.to_string(), path, target: node, leaf: Some(leaf) })
+    }
+
+    fn columns(&self, names: Option<Vec<String>>) -> Result<Vec<Column>, Error> {
+        match names {
+            Some(names) => names.iter().map(|x| self.column(x)).collect(),
+            None => {
+                let mut r = Vec::new();
+                self.all_columns(&self.node, "", &[], &mut r)?;
+                Ok(r)
+            }
+        }
+    }
+
+    /// The struct holding the column's value, None when the path crosses an
+    /// inactive union member or a null struct.
+    fn locate<'a>(&self, column: &Column, mut reader: StructReader<'a>) -> Result<Option<StructReader<'a>>, Error> {
+        for (node, field) in &column.path {
+            if !active(node, &reader, field)? {
+                return Ok(None);
+            }
+
+            if let schema_capnp::field::Slot(x) = field.which()? {
+                let ptr = reader.get_pointer_field(x.get_offset() as usize);
+
+                if ptr.is_null() {
+                    return Ok(None);
+                }
+
+                reader = ptr.get_struct(None)?;
+            }
+        }
+
+        Ok(Some(reader))
+    }
+
+    /// The text of a cell, None when the column has no value in this row.
+    fn cell(&self, column: &Column, reader: StructReader) -> Result<Option<String>, Error> {
+        let reader = match self.locate(column, reader)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let field = match column.leaf {
+            Some(ref x) => x,
+            None => return match dynamic::which_field(&column.target, &reader)? {
+                Some(x) => Ok(Some(x.get_name()?.to_string())),
+                None => Ok(None),
+            },
+        };
+
+        if !active(&column.target, &reader, field)? {
+            return Ok(None);
+        }
+
+        let slot = match field.which()? {
+            schema_capnp::field::Slot(x) => x,
+            _ => return Err(Error::Type("not a slot".into())),
+        };
+
+        let type_ = slot.get_type()?;
+        let offset = slot.get_offset() as usize;
+
+        if dynamic::is_pointer(&type_)? {
+            let ptr = reader.get_pointer_field(offset);
+
+            return Ok(match type_.which()? {
+                _ if ptr.is_null() => None,
+                schema_capnp::type_::Text(()) => Some(ptr.get_text(None)?.to_string()),
+                _ => Some(json::base64_encode(ptr.get_data(None)?)),
+            });
+        }
+
+        let bits = match dynamic::element_size(&type_)? {
+            ElementSize::Void => 0,
+            ElementSize::Bit => reader.get_bool_field(offset) as u64,
+            ElementSize::Byte => reader.get_data_field::<u8>(offset) as u64,
+            ElementSize::TwoBytes => reader.get_data_field::<u16>(offset) as u64,
+            ElementSize::FourBytes => reader.get_data_field::<u32>(offset) as u64,
+            _ => reader.get_data_field::<u64>(offset),
+        };
+
+        self.format(&type_, bits ^ dynamic::default_bits(slot.get_default_value()?)?).map(Some)
+    }
+
+    fn format(&self, type_: &TypeReader, bits: u64) -> Result<String, Error> {
+        use schema_capnp::type_ as T;
+
+        Ok(match type_.which()? {
+            T::Void(()) => String::new(),
+            T::Bool(()) => (if bits != 0 { "true" } else { "false" }).to_string(),
+            T::Int8(()) => (bits as u8 as i8).to_string(),
+            T::Int16(()) => (bits as u16 as i16).to_string(),
+            T::Int32(()) => (bits as u32 as i32).to_string(),
+            T::Int64(()) => (bits as i64).to_string(),
+            T::Uint8(()) | T::Uint16(()) | T::Uint32(()) | T::Uint64(()) => bits.to_string(),
+            T::Float32(()) => text::format_f32(f32::from_bits(bits as u32)),
+            T::Float64(()) => text::format_f64(f64::from_bits(bits)),
+            T::Enum(x) => dynamic::enumerant_name(&self.arena, x.get_type_id(), bits as u16)?.unwrap_or(bits.to_string()),
+            _ => return Err(Error::Type("not a primitive type".into())),
+        })
+    }
+
+    fn parse(&self, type_: &TypeReader, cell: &str) -> Result<u64, Error> {
+        use schema_capnp::type_ as T;
+
+        let cell = cell.trim();
+        let invalid = |kind: &str| Error::Value(format!("invalid {} value: {:?}", kind, cell));
+
+        let int = |min: i128, max: i128| -> Result<u64, Error> {
+            match cell.parse::<i128>() {
+                Ok(x) if x >= min && x <= max => Ok(x as u64),
+                Ok(x) => Err(Error::Value(format!("integer out of range: {}", x))),
+                Err(_) => Err(invalid("integer")),
+            }
+        };
+
+        let float = || -> Result<f64, Error> {
+            match cell.to_ascii_lowercase().as_str() {
+                "nan" => Ok(std::f64::NAN),
+                "inf" | "+inf" | "infinity" => Ok(std::f64::INFINITY),
+                "-inf" | "-infinity" => Ok(std::f64::NEG_INFINITY),
+                x => x.parse().map_err(|_| invalid("float")),
+            }
+        };
+
+        Ok(match type_.which()? {
+            T::Void(()) => 0,
+            T::Bool(()) => match cell.to_ascii_lowercase().as_str() {
+                "true" | "1" => 1,
+                "false" | "0" => 0,
+                _ => return Err(invalid("bool")),
+            },
+            T::Int8(()) => int(i8::min_value() as i128, i8::max_value() as i128)? & 0xff,
+            T::Int16(()) => int(i16::min_value() as i128, i16::max_value() as i128)? & 0xffff,
+            T::Int32(()) => int(i32::min_value() as i128, i32::max_value() as i128)? & 0xffff_ffff,
+            T::Int64(()) => int(i64::min_value() as i128, i64::max_value() as i128)?,
+            T::Uint8(()) => int(0, u8::max_value() as i128)?,
+            T::Uint16(()) => int(0, u16::max_value() as i128)?,
+            T::Uint32(()) => int(0, u32::max_value() as i128)?,
+            T::Uint64(()) => int(0, u64::max_value() as i128)?,
+            T::Float32(()) => (float()? as f32).to_bits() as u64,
+            T::Float64(()) => float()?.to_bits(),
+            T::Enum(x) => match cell.parse::<u16>() {
+                Ok(x) => x as u64,
+                Err(_) => dynamic::enumerant_index(&self.arena, x.get_type_id(), cell)
+                    .map_err(|_| Error::Value(format!("unknown enumerant: {:?}", cell)))? as u64,
+            },
+            _ => return Err(Error::Type("not a primitive type".into())),
+        })
+    }
+
+    fn value<'a>(&self, column: &Column, cell: &'a str) -> Result<Value<'a>, Error> {
+        let field = match column.leaf {
+            Some(ref x) => x,
+            None => {
+                let field = dynamic::find_field(&column.target, cell.trim())
+                    .map_err(|_| Error::Value(format!("no union member named {:?}", cell)))?;
+
+                if field.get_discriminant_value() == schema_capnp::field::NO_DISCRIMINANT {
+                    return Err(Error::Value(format!("{} is not a union member", cell)));
+                }
+
+                return Ok(Value::Member(field));
+            }
+        };
+
+        let slot = match field.which()? {
+            schema_capnp::field::Slot(x) => x,
+            _ => return Err(Error::Type("not a slot".into())),
+        };
+
+        let type_ = slot.get_type()?;
+
+        Ok(match type_.which()? {
+            schema_capnp::type_::Text(()) => Value::Text(cell),
+            schema_capnp::type_::Data(()) => Value::Data(json::base64_decode(cell.trim())?),
+            _ => Value::Bits(self.parse(&type_, cell)? ^ dynamic::default_bits(slot.get_default_value()?)?),
+        })
+    }
+
+    fn write(&self, column: &Column, mut builder: StructBuilder, value: &Value) -> Result<(), Error> {
+        for (node, field) in &column.path {
+            dynamic::set_active(node, &builder, field)?;
+
+            if let schema_capnp::field::Slot(x) = field.which()? {
+                let size = message::get_node_struct_size(&self.child(field)?.unwrap())?;
+                builder = builder.get_pointer_field(x.get_offset() as usize).get_struct(size, None)?;
+            }
+        }
+
+        let field = match (&column.leaf, value) {
+            (None, Value::Member(x)) => return dynamic::set_active(&column.target, &builder, x),
+            (Some(x), _) => x,
+            (None, _) => return Err(Error::Type("not a union member".into())),
+        };
+
+        dynamic::set_active(&column.target, &builder, field)?;
+
+        let slot = match field.which()? {
+            schema_capnp::field::Slot(x) => x,
+            _ => return Err(Error::Type("not a slot".into())),
+        };
+
+        let type_ = slot.get_type()?;
+        let offset = slot.get_offset() as usize;
+
+        let bits = match *value {
+            Value::Bits(x) => x,
+            Value::Text(x) => {
+                builder.get_pointer_field(offset).set_text(x);
+                return Ok(());
+            }
+            Value::Data(ref x) => {
+                builder.get_pointer_field(offset).set_data(x);
+                return Ok(());
+            }
+            Value::Member(_) => return Err(Error::Type("not a union discriminant".into())),
+        };
+
+        match dynamic::element_size(&type_)? {
+            ElementSize::Void => {}
+            ElementSize::Bit => builder.set_bool_field(offset, bits != 0),
+            ElementSize::Byte => builder.set_data_field::<u8>(offset, bits as u8),
+            ElementSize::TwoBytes => builder.set_data_field::<u16>(offset, bits as u16),
+            ElementSize::FourBytes => builder.set_data_field::<u32>(offset, bits as u32),
+            _ => builder.set_data_field::<u64>(offset, bits),
+        }
+
+        Ok(())
+    }
+}
+
+fn struct_element(arena: &Rc<NodeArena>, element: &TypeReader) -> Result<Table, Error> {
+    match element.which()? {
+        schema_capnp::type_::Struct(x) => Ok(Table { arena: arena.clone(), node: dynamic::get_node(arena, x.get_type_id())? }),
+        _ => Err(Error::Type("CSV needs a list of structs".into())),
+    }
+}
+
+fn write_record(out: &mut String, cells: &[String]) {
+    for (i, x) in cells.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        // a lone empty cell is quoted, a blank line would be skipped
+        if x.contains(|c| c == ',' || c == '"' || c == '\r' || c == '\n') || (cells.len() == 1 && x.is_empty()) {
+            out.push('"');
+            out.push_str(&x.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(x);
+        }
+    }
+
+    out.push_str("\r\n");
+}
+
+/// Splits RFC 4180 text into records. Blank lines are skipped, quoted cells
+/// may span lines.
+fn read_records(text: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut cell = String::new();
+    let mut chars = text.chars().peekable();
+
+    // a line with nothing on it, not even an empty quoted cell
+    let mut blank = true;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if cell.is_empty() => {
+                blank = false;
+
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            cell.push('"');
+                        }
+                        Some('"') => break,
+                        Some(x) => cell.push(x),
+                        None => return Err(Error::Value(format!("row {}: unterminated quoted cell", records.len() + 1))),
+                    }
+                }
+
+                match chars.peek() {
+                    None | Some(',') | Some('\r') | Some('\n') => {}
+                    Some(_) => return Err(Error::Value(format!("row {}: text after a closing quote", records.len() + 1))),
+                }
+            }
+            ',' => {
+                blank = false;
+                record.push(std::mem::replace(&mut cell, String::new()));
+            }
+            '\r' | '\n' => {
+                if c == '\r' && chars.peek() == Some(&'\n') {
+                    chars.next();
+                }
+
+                record.push(std::mem::replace(&mut cell, String::new()));
+
+                if blank {
+                    record.clear();
+                } else {
+                    records.push(std::mem::replace(&mut record, Vec::new()));
+                }
+
+                blank = true;
+            }
+            x => {
+                blank = false;
+                cell.push(x);
+            }
+        }
+    }
+
+    if !blank || !cell.is_empty() {
+        record.push(cell);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+pub fn to_csv(list: &ListReaderPy, columns: Option<Vec<String>>, null: Option<&str>) -> Result<String, Error> {
+    let table = struct_element(list.arena(), &list.element())?;
+    let columns = table.columns(columns)?;
+    let reader = list.reader();
+
+    let mut out = String::new();
+    write_record(&mut out, &columns.iter().map(|x| x.name.clone()).collect::<Vec<_>>());
+
+    for i in 0..reader.len() {
+        let element = reader.get_struct_element(i);
+        let cells = columns.iter()
+            .map(|x| Ok(table.cell(x, element)?.unwrap_or_else(|| null.unwrap_or("").to_string())))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        write_record(&mut out, &cells);
+    }
+
+    Ok(out)
+}
+
+/// Replaces the `List(Struct)` field `name` of `builder` with one element
+/// per CSV row. The header row names the columns, in any order and any
+/// subset of what `to_csv` writes. Every row is checked before the field is
+/// touched, a bad cell leaves the builder as it was.
+pub fn from_csv(builder: &StructBuilderPy, name: &str, text: &str, null: Option<&str>) -> Result<ListBuilderPy, Error> {
+    let arena = builder.arena();
+    let field = dynamic::find_field(builder.node(), name)?;
+    let (ptr, type_) = builder.pointer_field(&field)?;
+
+    let element = match type_.which()? {
+        schema_capnp::type_::List(x) => x.get_element_type()?,
+        _ => return Err(Error::Type(format!("{} is not a list", name))),
+    };
+
+    let table = struct_element(&arena, &element)?;
+    let mut records = read_records(text)?.into_iter();
+
+    let header = records.next().ok_or(Error::Value("no header row".into()))?;
+    let columns = header.iter().enumerate()
+        .map(|(i, x)| table.column(x).map_err(|e| match e {
+            Error::Type(e) => Error::Value(format!("row 1, column {}: {}", i + 1, e)),
+            Error::Attribute(e) => Error::Value(format!("row 1, column {}: no field {}", i + 1, e)),
+            e => e,
+        }))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let rows: Vec<Vec<String>> = records.collect();
+    let mut values = Vec::with_capacity(rows.len());
+
+    for (i, row) in rows.iter().enumerate() {
+        // the header is row 1, like in a spreadsheet
+        let number = i + 2;
+
+        if row.len() != columns.len() {
+            return Err(Error::Value(format!("row {}: expected {} cells, got {}", number, columns.len(), row.len())));
+        }
+
+        let mut cells = Vec::with_capacity(columns.len());
+
+        for (j, (column, cell)) in columns.iter().zip(row).enumerate() {
+            let skip = match null {
+                Some(x) => cell == x,
+                None => cell.is_empty(),
+            };
+
+            if !skip {
+                cells.push((column, table.value(column, cell).map_err(at(number, j + 1, &column.name))?));
+            }
+        }
+
+        values.push(cells);
+    }
+
+    dynamic::set_active(builder.node(), &builder.builder(), &field)?;
+
+    let size = message::get_node_struct_size(&table.node)?;
+    let mut list = ptr.init_struct_list(values.len() as u32, size);
+
+    for (i, cells) in values.iter().enumerate() {
+        for (column, value) in cells {
+            table.write(column, list.reborrow().get_struct_element(i as u32), value)?;
+        }
+    }
+
+    Ok(ListBuilderPy::new(builder.message(), arena, element, list))
+}
+
+#[pyclass]
+pub struct ToCsvFun {}
+
+#[pymethods]
+impl ToCsvFun {
+    /// `columns` are dotted field names; by default every scalar field,
+    /// including those of groups and nested structs. `null` is written for
+    /// cells without a value, empty by default.
+    #[call]
+    #[args(columns = "None", null = "None")]
+    fn to_csv(&self, list: &ListReaderPy, columns: Option<Vec<String>>, null: Option<String>) -> PyResult<String> {
+        Ok(to_csv(list, columns, null.as_ref().map(String::as_str))?)
+    }
+}
+
+#[pyclass]
+pub struct FromCsvFun {}
+
+#[pymethods]
+impl FromCsvFun {
+    /// Cells equal to `null` are left at their default; without a marker
+    /// empty cells are.
+    #[call]
+    #[args(null = "None")]
+    fn from_csv(&self, builder: &StructBuilderPy, name: &str, text: &str, null: Option<String>) -> PyResult<ListBuilderPy> {
+        Ok(from_csv(builder, name, text, null.as_ref().map(String::as_str))?)
+    }
+}
@@ -0,0 +1,339 @@
+use std::rc::Rc;
+
+use capnp::private::layout::{ListReader, PointerReader, PrimitiveElement, StructReader};
+use capnpc::schema_capnp;
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+use pyo3::{PyObjectProtocol, PySequenceProtocol};
+
+use crate::{Error, NodeArena};
+use crate::dynamic::{self, FieldReader, NodeRef, Owner, StructReaderPy, TypeReader};
+use crate::message::{Path, PathBuilder};
+
+pub struct Change {
+    pub path: PathBuilder,
+    // None on one side for list elements that exist only on the other
+    pub old: PyObject,
+    pub new: PyObject,
+}
+
+/// Walks two readers of the same struct node side by side.
+struct Differ<'a> {
+    py: Python<'a>,
+    arena: Rc<NodeArena>,
+    old: &'a Owner,
+    new: &'a Owner,
+    changes: Vec<Change>,
+}
+
+impl<'a> Differ<'a> {
+    fn change(&mut self, path: PathBuilder, old: PyObject, new: PyObject) {
+        self.changes.push(Change { path, old, new });
+    }
+
+    fn structs(
+        &mut self,
+        path: &PathBuilder,
+        node: &NodeRef,
+        a: StructReader<'static>,
+        b: StructReader<'static>,
+    ) -> Result<(), Error> {
+        let arm = match (dynamic::which_field(node, &a)?, dynamic::which_field(node, &b)?) {
+            (Some(x), Some(y)) => {
+                if x.get_discriminant_value() == y.get_discriminant_value() {
+                    Some(x.get_discriminant_value())
+                } else {
+                    let step = Path::Which(y.get_discriminant_value() as usize, node.clone());
+                    let old = x.get_name()?.to_object(self.py);
+                    let new = y.get_name()?.to_object(self.py);
+
+                    self.change(path.with_append(step), old, new);
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        for (idx, field) in dynamic::struct_node(node)?.get_fields()?.iter().enumerate() {
+            let discriminant = field.get_discriminant_value();
+
+            let path = if discriminant == schema_capnp::field::NO_DISCRIMINANT {
+                path.clone()
+            } else if Some(discriminant) == arm {
+                path.with_append(Path::Which(discriminant as usize, node.clone()))
+            } else {
+                continue;
+            };
+
+            self.field(&path, node, idx as u32, &field, a, b)?;
+        }
+
+        Ok(())
+    }
+
+    fn field(
+        &mut self,
+        path: &PathBuilder,
+        node: &NodeRef,
+        idx: u32,
+        field: &FieldReader,
+        a: StructReader<'static>,
+        b: StructReader<'static>,
+    ) -> Result<(), Error> {
+        let slot = match field.which()? {
+            schema_capnp::field::Group(x) => {
+                let group = dynamic::get_node(&self.arena, x.get_type_id())?;
+                return self.structs(&path.with_append(Path::Group(idx)), &group, a, b);
+            }
+            schema_capnp::field::Slot(x) => x,
+        };
+
+        let type_ = slot.get_type()?;
+        let offset = slot.get_offset() as usize;
+
+        if let schema_capnp::type_::Interface(_) = type_.which()? {
+            // capabilities only make sense within a connection
+            return Ok(());
+        }
+
+        let path = path.with_append(Path::Field(idx));
+
+        if dynamic::is_pointer(&type_)? {
+            return self.pointers(&path, &type_, a.get_pointer_field(offset), b.get_pointer_field(offset));
+        }
+
+        // both sides share the default mask, so comparing raw bits is enough
+        // and keeps NaN equal to itself
+        if data_equal(&a, &b, offset, &type_)? {
+            return Ok(());
+        }
+
+        let old = dynamic::read_field(self.py, self.old, &self.arena, node, a, field)?;
+        let new = dynamic::read_field(self.py, self.new, &self.arena, node, b, field)?;
+
+        self.change(path, old, new);
+
+        Ok(())
+    }
+
+    fn pointers(
+        &mut self,
+        path: &PathBuilder,
+        type_: &TypeReader,
+        a: PointerReader<'static>,
+        b: PointerReader<'static>,
+    ) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        let equal = match type_.which()? {
+            T::Struct(x) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                return self.structs(path, &node, a.get_struct(None)?, b.get_struct(None)?);
+            }
+            T::List(x) => return self.lists(path, &x.get_element_type()?, a, b),
+            T::Text(()) => a.get_text(None)? == b.get_text(None)?,
+            T::Data(()) => a.get_data(None)? == b.get_data(None)?,
+            T::AnyPointer(_) => crate::canonical::canonicalize_pointer(a)? == crate::canonical::canonicalize_pointer(b)?,
+            _ => return Ok(()),
+        };
+
+        if !equal {
+            let old = dynamic::read_pointer(self.py, self.old, &self.arena, a, type_)?;
+            let new = dynamic::read_pointer(self.py, self.new, &self.arena, b, type_)?;
+
+            self.change(path.clone(), old, new);
+        }
+
+        Ok(())
+    }
+
+    fn lists(
+        &mut self,
+        path: &PathBuilder,
+        element: &TypeReader,
+        a: PointerReader<'static>,
+        b: PointerReader<'static>,
+    ) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        let size = dynamic::element_size(element)?;
+        let (a, b) = (a.get_list(size, None)?, b.get_list(size, None)?);
+        let common = a.len().min(b.len());
+
+        for i in 0..common {
+            let path = path.with_append(Path::Index(i as usize));
+
+            match element.which()? {
+                T::Struct(x) => {
+                    let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                    self.structs(&path, &node, a.get_struct_element(i), b.get_struct_element(i))?;
+                }
+                T::Interface(_) => {}
+                T::Text(()) | T::Data(()) | T::List(_) | T::AnyPointer(_) => {
+                    self.pointers(&path, element, a.get_pointer_element(i), b.get_pointer_element(i))?;
+                }
+                _ => {
+                    if !element_equal(&a, &b, i, element)? {
+                        let old = dynamic::read_element(self.py, &self.arena, &a, i, element)?;
+                        let new = dynamic::read_element(self.py, &self.arena, &b, i, element)?;
+
+                        self.change(path, old, new);
+                    }
+                }
+            }
+        }
+
+        for i in common..a.len() {
+            let old = dynamic::read_list_item(self.py, self.old, &self.arena, &a, i, element)?;
+            self.change(path.with_append(Path::Index(i as usize)), old, self.py.None());
+        }
+
+        for i in common..b.len() {
+            let new = dynamic::read_list_item(self.py, self.new, &self.arena, &b, i, element)?;
+            self.change(path.with_append(Path::Index(i as usize)), self.py.None(), new);
+        }
+
+        Ok(())
+    }
+}
+
+fn data_equal(a: &StructReader, b: &StructReader, offset: usize, type_: &TypeReader) -> Result<bool, Error> {
+    use schema_capnp::type_ as T;
+
+    Ok(match type_.which()? {
+        T::Void(()) => true,
+        T::Bool(()) => a.get_bool_field(offset) == b.get_bool_field(offset),
+        T::Int8(()) | T::Uint8(()) => a.get_data_field::<u8>(offset) == b.get_data_field::<u8>(offset),
+        T::Int16(()) | T::Uint16(()) | T::Enum(_) => a.get_data_field::<u16>(offset) == b.get_data_field::<u16>(offset),
+        T::Int32(()) | T::Uint32(()) | T::Float32(()) => a.get_data_field::<u32>(offset) == b.get_data_field::<u32>(offset),
+        T::Int64(()) | T::Uint64(()) | T::Float64(()) => a.get_data_field::<u64>(offset) == b.get_data_field::<u64>(offset),
+        _ => return Err(Error::Type("not a data field".into()))
+    })
+}
+
+fn element_equal(a: &ListReader, b: &ListReader, idx: u32, type_: &TypeReader) -> Result<bool, Error> {
+    use schema_capnp::type_ as T;
+
+    fn eq<T: PrimitiveElement + PartialEq>(a: &ListReader, b: &ListReader, idx: u32) -> bool {
+        T::get(a, idx) == T::get(b, idx)
+    }
+
+    Ok(match type_.which()? {
+        T::Void(()) => true,
+        T::Bool(()) => eq::<bool>(a, b, idx),
+        T::Int8(()) | T::Uint8(()) => eq::<u8>(a, b, idx),
+        T::Int16(()) | T::Uint16(()) | T::Enum(_) => eq::<u16>(a, b, idx),
+        T::Int32(()) | T::Uint32(()) | T::Float32(()) => eq::<u32>(a, b, idx),
+        T::Int64(()) | T::Uint64(()) | T::Float64(()) => eq::<u64>(a, b, idx),
+        _ => return Err(Error::Type("not a primitive element".into()))
+    })
+}
+
+/// Field-level differences between two readers of the same struct node.
+pub fn diff(py: Python, a: &StructReaderPy, b: &StructReaderPy) -> Result<Vec<Change>, Error> {
+    if a.node.get_id() != b.node.get_id() {
+        return Err(Error::Type("can only diff readers of the same struct".into()));
+    }
+
+    let mut differ = Differ { py, arena: a.node.rc(), old: &a.owner, new: &b.owner, changes: Vec::new() };
+
+    differ.structs(&PathBuilder::new(a.node.clone()), &a.node, a.reader, b.reader)?;
+
+    Ok(differ.changes)
+}
+
+#[pyclass]
+pub struct PathPy {
+    pub i: PathBuilder,
+}
+
+#[pyproto]
+impl PyObjectProtocol for PathPy {
+    fn __str__(&self) -> PyResult<String> {
+        Ok(self.i.render()?)
+    }
+
+    fn __repr__(&self) -> PyResult<String> {
+        Ok(format!("<path {}>", self.i.render()?))
+    }
+}
+
+/// The result of `diff`: a sequence of `(path, old, new)` tuples whose
+/// `str()` lists one change per line.
+#[pyclass]
+pub struct DiffPy {
+    changes: Vec<Change>,
+}
+
+impl DiffPy {
+    fn render(&self, py: Python) -> Result<String, Error> {
+        if self.changes.is_empty() {
+            return Ok("no differences".into());
+        }
+
+        let value = |x: &PyObject| -> Result<String, Error> {
+            if x.is_none() {
+                return Ok("<missing>".into());
+            }
+
+            Ok(x.as_ref(py).repr()?.to_string()?.to_string())
+        };
+
+        let mut r = format!("{} difference(s):", self.changes.len());
+
+        for x in self.changes.iter() {
+            r.push_str(&format!("\n  {}: {} -> {}", x.path.render()?, value(&x.old)?, value(&x.new)?));
+        }
+
+        Ok(r)
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for DiffPy {
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.changes.len())
+    }
+
+    fn __getitem__(&self, idx: isize) -> PyResult<PyObject> {
+        let gil = GILGuard::acquire();
+        let py = gil.python();
+
+        let len = self.changes.len() as isize;
+        let idx = if idx < 0 { idx + len } else { idx };
+
+        if idx < 0 || idx >= len {
+            return Err(PyErr::new::<pyo3::exceptions::IndexError, _>("diff index out of range"));
+        }
+
+        let x = &self.changes[idx as usize];
+        let path: PyObject = Py::new(py, PathPy { i: x.path.clone() })?.into();
+
+        Ok(PyTuple::new(py, vec![path, x.old.clone_ref(py), x.new.clone_ref(py)]).to_object(py))
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for DiffPy {
+    fn __str__(&self) -> PyResult<String> {
+        let gil = GILGuard::acquire();
+
+        Ok(self.render(gil.python())?)
+    }
+
+    // pytest prints the repr of a failed `assert not diff(a, b)`
+    fn __repr__(&self) -> PyResult<String> {
+        self.__str__()
+    }
+}
+
+#[pyclass]
+pub struct DiffFun {}
+
+#[pymethods]
+impl DiffFun {
+    #[call]
+    fn diff(&self, py: Python, a: &StructReaderPy, b: &StructReaderPy) -> PyResult<DiffPy> {
+        Ok(DiffPy { changes: diff(py, a, b)? })
+    }
+}
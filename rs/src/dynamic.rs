@@ -0,0 +1,1775 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use capnp::Word;
+use capnp::private::layout::{
+    StructBuilder, StructReader, ListBuilder, ListReader, PointerBuilder, PointerReader, ElementSize,
+    PrimitiveElement, StructSize,
+};
+use capnpc::schema_capnp;
+use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyBytes, PyDict, PyString};
+use pyo3::{PyObjectProtocol, PySequenceProtocol};
+use pyo3::basic::CompareOp;
+
+use crate::{Error, NodeArena, NodePy};
+use crate::arena::{ArenaRc, ArenaRef};
+use crate::buffer;
+use crate::canonical;
+use crate::capability::{self, CapTable};
+use crate::text;
+use crate::framing::Frame;
+use crate::message;
+use crate::objs;
+use crate::rpc;
+
+pub type NodeRef = ArenaRef<NodeArena>;
+pub type FieldReader = schema_capnp::field::Reader<'static>;
+pub type TypeReader = schema_capnp::type_::Reader<'static>;
+
+pub type MessageRc = Rc<RefCell<message::Builder>>;
+
+/// Whatever owns the memory a reader points into.
+#[derive(Clone)]
+pub enum Owner {
+    // with the table its readers are imbued with
+    Message(Rc<capnp::message::Reader<Frame>>, Rc<CapTable>),
+    Builder(MessageRc),
+    // also keeps the cap table readers into the payload are imbued with
+    Payload(Rc<rpc::Payload>),
+}
+
+// Python handles keep `MessageRc` alive next to the raw builder. The arena
+// sits behind the Rc and never moves, so it outlives every handle into it.
+unsafe fn detach_struct(x: StructBuilder) -> StructBuilder<'static> {
+    std::mem::transmute(x)
+}
+
+unsafe fn detach_pointer(x: PointerBuilder) -> PointerBuilder<'static> {
+    std::mem::transmute(x)
+}
+
+pub unsafe fn detach_reader(x: StructReader) -> StructReader<'static> {
+    std::mem::transmute(x)
+}
+
+pub unsafe fn detach_pointer_reader(x: PointerReader) -> PointerReader<'static> {
+    std::mem::transmute(x)
+}
+
+/// Lets `message::Reader::get_root` hand out the raw root pointer.
+pub struct RootPointer<'a>(pub PointerReader<'a>);
+
+impl<'a> capnp::traits::FromPointerReader<'a> for RootPointer<'a> {
+    fn get_from_pointer(reader: &PointerReader<'a>, _default: Option<&'a [Word]>) -> capnp::Result<RootPointer<'a>> {
+        Ok(RootPointer(*reader))
+    }
+}
+
+/// Lets `capnp::message::Builder::init_root` hand out the raw root pointer.
+pub struct RootBuilder<'a>(pub PointerBuilder<'a>);
+
+impl<'a> capnp::traits::FromPointerBuilder<'a> for RootBuilder<'a> {
+    fn init_pointer(builder: PointerBuilder<'a>, _size: u32) -> RootBuilder<'a> {
+        RootBuilder(builder)
+    }
+
+    fn get_from_pointer(builder: PointerBuilder<'a>, _default: Option<&'a [Word]>) -> capnp::Result<RootBuilder<'a>> {
+        Ok(RootBuilder(builder))
+    }
+}
+
+// raw builders are plain pointers without Drop, this is what reborrow() does
+fn alias<T>(x: &T) -> T {
+    unsafe { std::ptr::read(x) }
+}
+
+pub fn get_node(arena: &Rc<NodeArena>, id: u64) -> Result<NodeRef, Error> {
+    ArenaRc::from(arena).get_ref(&id).ok_or(Error::Type(format!("unknown node 0x{:x}", id)))
+}
+
+pub fn struct_node(node: &NodeRef) -> Result<schema_capnp::node::struct_::Reader<'static>, Error> {
+    match node.which()? {
+        schema_capnp::node::Struct(x) => Ok(x),
+        _ => Err(Error::Type("not a struct".into()))
+    }
+}
+
+pub fn interface_node(node: &NodeRef) -> Result<schema_capnp::node::interface::Reader<'static>, Error> {
+    match node.which()? {
+        schema_capnp::node::Interface(x) => Ok(x),
+        _ => Err(Error::Type("not an interface".into()))
+    }
+}
+
+pub fn find_field(node: &NodeRef, name: &str) -> Result<FieldReader, Error> {
+    for field in struct_node(node)?.get_fields()? {
+        if field.get_name()? == name {
+            return Ok(field);
+        }
+    }
+
+    Err(Error::Attribute(name.to_string()))
+}
+
+/// The union member currently selected in `reader`, if the struct has a union.
+pub fn which_field(node: &NodeRef, reader: &StructReader) -> Result<Option<FieldReader>, Error> {
+    let x = struct_node(node)?;
+
+    if x.get_discriminant_count() == 0 {
+        return Ok(None);
+    }
+
+    let discriminant = reader.get_data_field::<u16>(x.get_discriminant_offset() as usize);
+
+    for field in x.get_fields()? {
+        if field.get_discriminant_value() == discriminant {
+            return Ok(Some(field));
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn check_active(node: &NodeRef, reader: &StructReader, field: &FieldReader) -> Result<(), Error> {
+    if field.get_discriminant_value() == schema_capnp::field::NO_DISCRIMINANT {
+        return Ok(());
+    }
+
+    match which_field(node, reader)? {
+        Some(ref x) if x.get_discriminant_value() == field.get_discriminant_value() => Ok(()),
+        _ => Err(Error::Type(format!("{} is not the active union member", field.get_name()?)))
+    }
+}
+
+pub fn set_active(node: &NodeRef, builder: &StructBuilder, field: &FieldReader) -> Result<(), Error> {
+    if field.get_discriminant_value() == schema_capnp::field::NO_DISCRIMINANT {
+        return Ok(());
+    }
+
+    builder.set_data_field::<u16>(
+        struct_node(node)?.get_discriminant_offset() as usize,
+        field.get_discriminant_value(),
+    );
+
+    Ok(())
+}
+
+pub fn element_size(type_: &TypeReader) -> Result<ElementSize, Error> {
+    use schema_capnp::type_ as T;
+
+    Ok(match type_.which()? {
+        T::Void(()) => ElementSize::Void,
+        T::Bool(()) => ElementSize::Bit,
+        T::Int8(()) | T::Uint8(()) => ElementSize::Byte,
+        T::Int16(()) | T::Uint16(()) | T::Enum(_) => ElementSize::TwoBytes,
+        T::Int32(()) | T::Uint32(()) | T::Float32(()) => ElementSize::FourBytes,
+        T::Int64(()) | T::Uint64(()) | T::Float64(()) => ElementSize::EightBytes,
+        T::Struct(_) => ElementSize::InlineComposite,
+        T::Text(()) | T::Data(()) | T::List(_) | T::Interface(_) | T::AnyPointer(_) => ElementSize::Pointer,
+    })
+}
+
+pub fn is_pointer(type_: &TypeReader) -> Result<bool, Error> {
+    Ok(match element_size(type_)? {
+        ElementSize::Pointer | ElementSize::InlineComposite => true,
+        _ => false,
+    })
+}
+
+/// Raw bits of a primitive default value, used as the XOR mask of a data field.
+pub fn default_bits(value: schema_capnp::value::Reader) -> Result<u64, Error> {
+    use schema_capnp::value as V;
+
+    Ok(match value.which()? {
+        V::Bool(x) => x as u64,
+        V::Int8(x) => x as u8 as u64,
+        V::Int16(x) => x as u16 as u64,
+        V::Int32(x) => x as u32 as u64,
+        V::Int64(x) => x as u64,
+        V::Uint8(x) => x as u64,
+        V::Uint16(x) => x as u64,
+        V::Uint32(x) => x as u64,
+        V::Uint64(x) => x,
+        V::Float32(x) => x.to_bits() as u64,
+        V::Float64(x) => x.to_bits(),
+        V::Enum(x) => x as u64,
+        _ => 0,
+    })
+}
+
+pub fn enumerant_name(arena: &Rc<NodeArena>, id: u64, value: u16) -> Result<Option<String>, Error> {
+    match get_node(arena, id)?.which()? {
+        schema_capnp::node::Enum(x) => {
+            let items = x.get_enumerants()?;
+
+            if (value as u32) < items.len() {
+                Ok(Some(items.get(value as u32).get_name()?.to_string()))
+            } else {
+                Ok(None)
+            }
+        }
+        _ => Err(Error::Type("not an enum".into()))
+    }
+}
+
+pub fn enumerant_value(arena: &Rc<NodeArena>, id: u64, value: &PyAny) -> Result<u16, Error> {
+    if let Ok(x) = value.extract::<u16>() {
+        return Ok(x);
+    }
+
+    enumerant_index(arena, id, value.extract()?)
+}
+
+pub fn enumerant_index(arena: &Rc<NodeArena>, id: u64, name: &str) -> Result<u16, Error> {
+    match get_node(arena, id)?.which()? {
+        schema_capnp::node::Enum(x) => {
+            for (i, item) in x.get_enumerants()?.iter().enumerate() {
+                if item.get_name()? == name {
+                    return Ok(i as u16);
+                }
+            }
+
+            Err(Error::Type(format!("unknown enumerant: {}", name)))
+        }
+        _ => Err(Error::Type("not an enum".into()))
+    }
+}
+
+/// Reads a data (non-pointer) slot, applying the schema default.
+pub fn read_data_slot(
+    py: Python,
+    arena: &Rc<NodeArena>,
+    reader: &StructReader,
+    slot: &schema_capnp::field::slot::Reader<'static>,
+) -> Result<PyObject, Error> {
+    use schema_capnp::type_ as T;
+
+    let offset = slot.get_offset() as usize;
+    let mask = default_bits(slot.get_default_value()?)?;
+
+    Ok(match slot.get_type()?.which()? {
+        T::Void(()) => py.None(),
+        T::Bool(()) => reader.get_bool_field_mask(offset, mask != 0).to_object(py),
+        T::Int8(()) => reader.get_data_field_mask::<i8>(offset, mask as i8).to_object(py),
+        T::Int16(()) => reader.get_data_field_mask::<i16>(offset, mask as i16).to_object(py),
+        T::Int32(()) => reader.get_data_field_mask::<i32>(offset, mask as i32).to_object(py),
+        T::Int64(()) => reader.get_data_field_mask::<i64>(offset, mask as i64).to_object(py),
+        T::Uint8(()) => reader.get_data_field_mask::<u8>(offset, mask as u8).to_object(py),
+        T::Uint16(()) => reader.get_data_field_mask::<u16>(offset, mask as u16).to_object(py),
+        T::Uint32(()) => reader.get_data_field_mask::<u32>(offset, mask as u32).to_object(py),
+        T::Uint64(()) => reader.get_data_field_mask::<u64>(offset, mask).to_object(py),
+        T::Float32(()) => reader.get_data_field_mask::<f32>(offset, mask as u32).to_object(py),
+        T::Float64(()) => reader.get_data_field_mask::<f64>(offset, mask).to_object(py),
+        T::Enum(x) => {
+            let value = reader.get_data_field_mask::<u16>(offset, mask as u16);
+
+            match enumerant_name(arena, x.get_type_id(), value)? {
+                Some(name) => name.to_object(py),
+                None => value.to_object(py),
+            }
+        }
+        _ => return Err(Error::Type("not a data field".into()))
+    })
+}
+
+pub fn write_data_slot(
+    arena: &Rc<NodeArena>,
+    builder: &StructBuilder,
+    slot: &schema_capnp::field::slot::Reader<'static>,
+    value: &PyAny,
+) -> Result<(), Error> {
+    use schema_capnp::type_ as T;
+
+    let offset = slot.get_offset() as usize;
+    let mask = default_bits(slot.get_default_value()?)?;
+
+    match slot.get_type()?.which()? {
+        T::Void(()) => {}
+        T::Bool(()) => builder.set_bool_field_mask(offset, value.extract()?, mask != 0),
+        T::Int8(()) => builder.set_data_field_mask::<i8>(offset, value.extract()?, mask as i8),
+        T::Int16(()) => builder.set_data_field_mask::<i16>(offset, value.extract()?, mask as i16),
+        T::Int32(()) => builder.set_data_field_mask::<i32>(offset, value.extract()?, mask as i32),
+        T::Int64(()) => builder.set_data_field_mask::<i64>(offset, value.extract()?, mask as i64),
+        T::Uint8(()) => builder.set_data_field_mask::<u8>(offset, value.extract()?, mask as u8),
+        T::Uint16(()) => builder.set_data_field_mask::<u16>(offset, value.extract()?, mask as u16),
+        T::Uint32(()) => builder.set_data_field_mask::<u32>(offset, value.extract()?, mask as u32),
+        T::Uint64(()) => builder.set_data_field_mask::<u64>(offset, value.extract()?, mask),
+        T::Float32(()) => builder.set_data_field_mask::<f32>(offset, value.extract()?, mask as u32),
+        T::Float64(()) => builder.set_data_field_mask::<f64>(offset, value.extract()?, mask),
+        T::Enum(x) => builder.set_data_field_mask::<u16>(
+            offset,
+            enumerant_value(arena, x.get_type_id(), value)?,
+            mask as u16,
+        ),
+        _ => return Err(Error::Type("not a data field".into()))
+    }
+
+    Ok(())
+}
+
+pub fn read_element(
+    py: Python,
+    arena: &Rc<NodeArena>,
+    list: &ListReader,
+    index: u32,
+    element: &TypeReader,
+) -> Result<PyObject, Error> {
+    use schema_capnp::type_ as T;
+
+    Ok(match element.which()? {
+        T::Void(()) => py.None(),
+        T::Bool(()) => <bool as PrimitiveElement>::get(list, index).to_object(py),
+        T::Int8(()) => <i8 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Int16(()) => <i16 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Int32(()) => <i32 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Int64(()) => <i64 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Uint8(()) => <u8 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Uint16(()) => <u16 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Uint32(()) => <u32 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Uint64(()) => <u64 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Float32(()) => <f32 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Float64(()) => <f64 as PrimitiveElement>::get(list, index).to_object(py),
+        T::Enum(x) => {
+            let value = <u16 as PrimitiveElement>::get(list, index);
+
+            match enumerant_name(arena, x.get_type_id(), value)? {
+                Some(name) => name.to_object(py),
+                None => value.to_object(py),
+            }
+        }
+        T::Text(()) => list.get_pointer_element(index).get_text(None)?.to_object(py),
+        T::Data(()) => PyBytes::new(py, list.get_pointer_element(index).get_data(None)?).into(),
+        _ => return Err(Error::Type("not a primitive element".into()))
+    })
+}
+
+pub fn write_element(
+    arena: &Rc<NodeArena>,
+    list: &mut ListBuilder,
+    index: u32,
+    element: &TypeReader,
+    value: &PyAny,
+) -> Result<(), Error> {
+    use schema_capnp::type_ as T;
+
+    match element.which()? {
+        T::Void(()) => {}
+        T::Bool(()) => <bool as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Int8(()) => <i8 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Int16(()) => <i16 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Int32(()) => <i32 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Int64(()) => <i64 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Uint8(()) => <u8 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Uint16(()) => <u16 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Uint32(()) => <u32 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Uint64(()) => <u64 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Float32(()) => <f32 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Float64(()) => <f64 as PrimitiveElement>::set(list, index, value.extract()?),
+        T::Enum(x) => <u16 as PrimitiveElement>::set(
+            list, index, enumerant_value(arena, x.get_type_id(), value)?,
+        ),
+        _ => write_pointer(arena, list.reborrow().get_pointer_element(index), element, value)?,
+    }
+
+    Ok(())
+}
+
+/// Writes a Python value into a pointer: `str` for Text, `bytes` for Data,
+/// sequences (or buffers, for lists of numbers) for lists, dicts for structs,
+/// capabilities or servers for interfaces and `None` to clear it.
+pub fn write_pointer(
+    arena: &Rc<NodeArena>,
+    mut ptr: PointerBuilder,
+    type_: &TypeReader,
+    value: &PyAny,
+) -> Result<(), Error> {
+    use schema_capnp::type_ as T;
+
+    if value.is_none() {
+        ptr.clear();
+        return Ok(());
+    }
+
+    if let Some(source) = Source::from_py(value)? {
+        return source.copy_into(ptr, type_);
+    }
+
+    match type_.which()? {
+        T::Text(()) => ptr.set_text(value.downcast_ref::<PyString>()?.to_string()?.as_ref()),
+        T::Data(()) => ptr.set_data(value.downcast_ref::<PyBytes>()?.as_bytes()),
+        T::Struct(x) => {
+            let node = get_node(arena, x.get_type_id())?;
+            let mut builder = ptr.init_struct(message::get_node_struct_size(&node)?);
+
+            for (k, v) in value.downcast_ref::<PyDict>()?.iter() {
+                set_field(arena, &node, &mut builder, k.extract()?, v)?;
+            }
+        }
+        T::List(x) => {
+            let element = x.get_element_type()?;
+
+            if buffer::is_buffer(value) && buffer::element_format(&element)?.is_some() {
+                return buffer::init_list(ptr, &element, value);
+            }
+
+            let items: Vec<&PyAny> = value.extract()?;
+            let mut list = init_list(arena, ptr, &element, items.len() as u32)?;
+
+            for (i, v) in items.into_iter().enumerate() {
+                write_list_item(arena, &mut list, i as u32, &element, v)?;
+            }
+        }
+        T::Interface(_) => capability::write(&ptr, rpc::entry_from_py(value)?),
+        _ => return Err(Error::Type("unsupported pointer type".into()))
+    }
+
+    Ok(())
+}
+
+pub fn write_list_item(
+    arena: &Rc<NodeArena>,
+    list: &mut ListBuilder,
+    index: u32,
+    element: &TypeReader,
+    value: &PyAny,
+) -> Result<(), Error> {
+    if let schema_capnp::type_::Struct(x) = element.which()? {
+        let node = get_node(arena, x.get_type_id())?;
+        let mut builder = list.reborrow().get_struct_element(index);
+
+        if let Some(source) = Source::from_py(value)? {
+            source.check(element)?;
+
+            return match source {
+                Source::Struct(_, reader) => Ok(builder.copy_content_from(&reader)?),
+                _ => Err(Error::Type("expected a struct".into()))
+            };
+        }
+
+        for (k, v) in value.downcast_ref::<PyDict>()?.iter() {
+            set_field(arena, &node, &mut builder, k.extract()?, v)?;
+        }
+
+        return Ok(());
+    }
+
+    write_element(arena, list, index, element, value)
+}
+
+pub fn init_list<'a>(
+    arena: &Rc<NodeArena>,
+    ptr: PointerBuilder<'a>,
+    element: &TypeReader,
+    size: u32,
+) -> Result<ListBuilder<'a>, Error> {
+    Ok(match element.which()? {
+        schema_capnp::type_::Struct(x) => {
+            let node = get_node(arena, x.get_type_id())?;
+            ptr.init_struct_list(size, message::get_node_struct_size(&node)?)
+        }
+        _ => ptr.init_list(element_size(element)?, size)
+    })
+}
+
+pub fn get_list<'a>(
+    arena: &Rc<NodeArena>,
+    ptr: PointerBuilder<'a>,
+    element: &TypeReader,
+) -> Result<ListBuilder<'a>, Error> {
+    Ok(match element.which()? {
+        schema_capnp::type_::Struct(x) => {
+            let node = get_node(arena, x.get_type_id())?;
+            ptr.get_struct_list(message::get_node_struct_size(&node)?, None)?
+        }
+        _ => ptr.get_list(element_size(element)?, None)?
+    })
+}
+
+/// Sets a field by name; groups take a dict of their own fields.
+pub fn set_field(
+    arena: &Rc<NodeArena>,
+    node: &NodeRef,
+    builder: &mut StructBuilder,
+    name: &str,
+    value: &PyAny,
+) -> Result<(), Error> {
+    let field = find_field(node, name)?;
+    set_active(node, builder, &field)?;
+
+    match field.which()? {
+        schema_capnp::field::Group(x) => {
+            let group = get_node(arena, x.get_type_id())?;
+
+            for (k, v) in value.downcast_ref::<PyDict>()?.iter() {
+                set_field(arena, &group, builder, k.extract()?, v)?;
+            }
+
+            Ok(())
+        }
+        schema_capnp::field::Slot(x) => {
+            let type_ = x.get_type()?;
+
+            if is_pointer(&type_)? {
+                write_pointer(arena, builder.reborrow().get_pointer_field(x.get_offset() as usize), &type_, value)
+            } else {
+                write_data_slot(arena, builder, &x, value)
+            }
+        }
+    }
+}
+
+/// A reader (or builder) passed as the value of a field: it is deep-copied.
+pub enum Source<'a> {
+    Struct(u64, StructReader<'a>),
+    List(TypeReader, ListReader<'a>),
+    Pointer(PointerReader<'a>),
+}
+
+impl<'a> Source<'a> {
+    pub fn from_py(value: &'a PyAny) -> Result<Option<Source<'a>>, Error> {
+        if let Ok(x) = value.downcast_ref::<StructReaderPy>() {
+            return Ok(Some(Source::Struct(x.node.get_id(), x.reader)));
+        }
+        if let Ok(x) = value.downcast_ref::<StructBuilderPy>() {
+            return Ok(Some(Source::Struct(x.node.get_id(), x.builder.as_reader())));
+        }
+        if let Ok(x) = value.downcast_ref::<ListReaderPy>() {
+            return Ok(Some(Source::List(x.element, x.reader)));
+        }
+        if let Ok(x) = value.downcast_ref::<ListBuilderPy>() {
+            return Ok(Some(Source::List(x.element, x.builder().into_reader())));
+        }
+        if let Ok(x) = value.downcast_ref::<AnyPointerReaderPy>() {
+            return Ok(Some(Source::Pointer(x.reader)));
+        }
+
+        Ok(None)
+    }
+
+    fn type_(&self) -> Result<objs::Type, Error> {
+        Ok(match self {
+            Source::Struct(id, _) => objs::Type::Struct { id: *id, brand: objs::Brand::default() },
+            Source::List(element, _) => objs::Type::List { element: Box::new(objs::Type::from_reader(element)?) },
+            Source::Pointer(_) => objs::Type::AnyPointer(objs::AnyPointerType::Any),
+        })
+    }
+
+    /// Fails unless this value may be stored in a slot of type `target`.
+    /// Where a side is AnyPointer the schema cannot tell, so the pointer
+    /// read must have the kind, and a list the element size, of the slot.
+    pub fn check(&self, target: &TypeReader) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        let fits = match self {
+            Source::Pointer(x) => pointer_fits(x, target)?,
+            Source::List(element, x) => {
+                let wire = match target.which()? {
+                    // an upgraded list of structs keeps its older encoding
+                    T::List(y) => element_size(&y.get_element_type()?)? == x.get_element_size() ||
+                        element_size(element)? == ElementSize::InlineComposite,
+                    _ => true,
+                };
+
+                wire && objs::Type::from_reader(target)?.is_compatible(&self.type_()?)
+            }
+            Source::Struct(..) => objs::Type::from_reader(target)?.is_compatible(&self.type_()?),
+        };
+
+        if fits {
+            Ok(())
+        } else {
+            Err(Error::Type("value does not match the type of the field".into()))
+        }
+    }
+
+    pub fn copy_into(&self, mut ptr: PointerBuilder, target: &TypeReader) -> Result<(), Error> {
+        self.check(target)?;
+
+        match self {
+            Source::Struct(_, x) => ptr.set_struct(x, false)?,
+            Source::List(_, x) => ptr.set_list(x, false)?,
+            Source::Pointer(x) => ptr.copy_from(*x, false)?,
+        }
+
+        Ok(())
+    }
+}
+
+// whether an untyped pointer reads as what a slot of type `target` holds
+fn pointer_fits(ptr: &PointerReader, target: &TypeReader) -> Result<bool, Error> {
+    use schema_capnp::type_ as T;
+
+    if ptr.is_null() {
+        return Ok(true);
+    }
+
+    Ok(match target.which()? {
+        T::Struct(_) => ptr.get_struct(None).is_ok(),
+        T::List(x) => ptr.get_list(element_size(&x.get_element_type()?)?, None).is_ok(),
+        T::Text(()) => ptr.get_text(None).is_ok(),
+        T::Data(()) => ptr.get_data(None).is_ok(),
+        T::Interface(_) => ptr.get_capability().is_ok(),
+        T::AnyPointer(_) => true,
+        _ => false,
+    })
+}
+
+/// Describes what an orphan (or any detached pointer) holds.
+#[derive(Clone)]
+pub enum PointerKind {
+    Struct(NodeRef),
+    List(TypeReader),
+    Text,
+    Data,
+}
+
+impl PointerKind {
+    pub fn from_type(arena: &Rc<NodeArena>, type_: &TypeReader) -> Result<PointerKind, Error> {
+        use schema_capnp::type_ as T;
+
+        Ok(match type_.which()? {
+            T::Struct(x) => PointerKind::Struct(get_node(arena, x.get_type_id())?),
+            T::List(x) => PointerKind::List(x.get_element_type()?),
+            T::Text(()) => PointerKind::Text,
+            T::Data(()) => PointerKind::Data,
+            _ => return Err(Error::Type("not a struct, list, text or data pointer".into()))
+        })
+    }
+
+    /// Whether a pointer of this kind may be stored in a field of type `type_`.
+    pub fn fits(&self, arena: &Rc<NodeArena>, type_: &TypeReader) -> Result<bool, Error> {
+        Ok(match (self, PointerKind::from_type(arena, type_)?) {
+            (PointerKind::Struct(a), PointerKind::Struct(b)) => a.get_id() == b.get_id(),
+            (PointerKind::List(a), PointerKind::List(b)) => same_type(a, &b)?,
+            (PointerKind::Text, PointerKind::Text) => true,
+            (PointerKind::Data, PointerKind::Data) => true,
+            _ => false,
+        })
+    }
+}
+
+pub fn same_type(a: &TypeReader, b: &TypeReader) -> Result<bool, Error> {
+    use schema_capnp::type_ as T;
+
+    Ok(match (a.which()?, b.which()?) {
+        (T::Struct(x), T::Struct(y)) => x.get_type_id() == y.get_type_id(),
+        (T::Enum(x), T::Enum(y)) => x.get_type_id() == y.get_type_id(),
+        (T::Interface(x), T::Interface(y)) => x.get_type_id() == y.get_type_id(),
+        (T::List(x), T::List(y)) => same_type(&x.get_element_type()?, &y.get_element_type()?)?,
+        (T::AnyPointer(_), T::AnyPointer(_)) => true,
+        (T::Text(()), T::Text(())) | (T::Data(()), T::Data(())) => true,
+        // the remaining variants are all primitives without payload
+        (x, y) => !is_pointer(a)? && std::mem::discriminant(&x) == std::mem::discriminant(&y),
+    })
+}
+
+fn pointer_value(
+    py: Python,
+    message: &MessageRc,
+    arena: &Rc<NodeArena>,
+    ptr: PointerBuilder<'static>,
+    kind: &PointerKind,
+) -> Result<PyObject, Error> {
+    Ok(match kind {
+        PointerKind::Struct(node) => {
+            let builder = ptr.get_struct(message::get_node_struct_size(node)?, None)?;
+
+            Py::new(py, StructBuilderPy {
+                message: message.clone(),
+                node: node.clone(),
+                builder,
+            })?.into()
+        }
+        PointerKind::List(element) => {
+            let builder = get_list(arena, ptr, element)?;
+
+            Py::new(py, ListBuilderPy {
+                message: message.clone(),
+                arena: arena.clone(),
+                element: *element,
+                builder,
+            })?.into()
+        }
+        PointerKind::Text => ptr.as_reader().get_text(None)?.to_object(py),
+        PointerKind::Data => PyBytes::new(py, ptr.as_reader().get_data(None)?).into(),
+    })
+}
+
+#[pyclass]
+pub struct StructBuilderPy {
+    message: MessageRc,
+    node: NodeRef,
+    builder: StructBuilder<'static>,
+}
+
+impl StructBuilderPy {
+    pub fn new_root(message: &MessageRc) -> Result<StructBuilderPy, Error> {
+        let node = get_node(message.borrow().node_arena(), message.borrow().node_id())?;
+        let builder = unsafe { detach_struct(message.borrow_mut().get_root()?) };
+
+        Ok(StructBuilderPy { message: message.clone(), node, builder })
+    }
+
+    pub(crate) fn builder(&self) -> StructBuilder<'static> {
+        alias(&self.builder)
+    }
+
+    pub(crate) fn message(&self) -> &MessageRc {
+        &self.message
+    }
+
+    pub(crate) fn node(&self) -> &NodeRef {
+        &self.node
+    }
+
+    pub(crate) fn arena(&self) -> Rc<NodeArena> {
+        self.message.borrow().node_arena().clone()
+    }
+
+    pub(crate) fn pointer_field(&self, field: &FieldReader) -> Result<(PointerBuilder<'static>, TypeReader), Error> {
+        match field.which()? {
+            schema_capnp::field::Slot(x) => {
+                let type_ = x.get_type()?;
+
+                if !is_pointer(&type_)? {
+                    return Err(Error::Type(format!("{} is not a pointer field", field.get_name()?)));
+                }
+
+                Ok((self.builder().get_pointer_field(x.get_offset() as usize), type_))
+            }
+            schema_capnp::field::Group(_) => Err(Error::Type(format!("{} is a group", field.get_name()?)))
+        }
+    }
+}
+
+#[pymethods]
+impl StructBuilderPy {
+    fn get(&self, py: Python, name: &str) -> PyResult<PyObject> {
+        let inner = |this: &Self| -> Result<PyObject, Error> {
+            let arena = this.arena();
+            let field = find_field(&this.node, name)?;
+            check_active(&this.node, &this.builder.as_reader(), &field)?;
+
+            match field.which()? {
+                schema_capnp::field::Group(x) => {
+                    Ok(Py::new(py, StructBuilderPy {
+                        message: this.message.clone(),
+                        node: get_node(&arena, x.get_type_id())?,
+                        builder: this.builder(),
+                    })?.into())
+                }
+                schema_capnp::field::Slot(x) => {
+                    if !is_pointer(&x.get_type()?)? {
+                        return read_data_slot(py, &arena, &this.builder.as_reader(), &x);
+                    }
+
+                    let (ptr, type_) = this.pointer_field(&field)?;
+                    pointer_value(py, &this.message, &arena, ptr, &PointerKind::from_type(&arena, &type_)?)
+                }
+            }
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    fn set(&self, name: &str, value: &PyAny) -> PyResult<()> {
+        Ok(set_field(&self.arena(), &self.node, &mut self.builder(), name, value)?)
+    }
+
+    #[args(size = "None")]
+    fn init(&self, py: Python, name: &str, size: Option<u32>) -> PyResult<PyObject> {
+        let inner = |this: &Self| -> Result<PyObject, Error> {
+            let arena = this.arena();
+            let field = find_field(&this.node, name)?;
+            set_active(&this.node, &this.builder, &field)?;
+
+            let (ptr, type_) = this.pointer_field(&field)?;
+            let kind = PointerKind::from_type(&arena, &type_)?;
+
+            match (&kind, size) {
+                (PointerKind::Struct(node), None) => {
+                    ptr.init_struct(message::get_node_struct_size(node)?);
+                }
+                (PointerKind::List(element), Some(size)) => {
+                    init_list(&arena, ptr, element, size)?;
+                }
+                _ => return Err(Error::Type("structs are initialized without a size, lists with one".into()))
+            }
+
+            let (ptr, _) = this.pointer_field(&field)?;
+            pointer_value(py, &this.message, &arena, ptr, &kind)
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    fn as_reader(&self) -> PyResult<StructReaderPy> {
+        Ok(StructReaderPy {
+            owner: Owner::Builder(self.message.clone()),
+            node: self.node.clone(),
+            reader: unsafe { detach_reader(self.builder.as_reader()) },
+        })
+    }
+
+    fn which(&self) -> PyResult<Option<String>> {
+        let inner = |this: &Self| -> Result<Option<String>, Error> {
+            match which_field(&this.node, &this.builder.as_reader())? {
+                Some(x) => Ok(Some(x.get_name()?.to_string())),
+                None => Ok(None),
+            }
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// A list handle that behaves like a Python list: it reallocates the
+    /// field's list when it runs out of room. Call `finalize()` when done,
+    /// until then the list on the wire still has its spare capacity.
+    fn growable(&self, name: &str) -> PyResult<GrowableListPy> {
+        let inner = |this: &Self| -> Result<GrowableListPy, Error> {
+            let arena = this.arena();
+            let field = find_field(&this.node, name)?;
+            check_active(&this.node, &this.builder.as_reader(), &field)?;
+
+            let (ptr, type_) = this.pointer_field(&field)?;
+            let element = match PointerKind::from_type(&arena, &type_)? {
+                PointerKind::List(x) => x,
+                _ => return Err(Error::Type(format!("{} is not a list", name)))
+            };
+
+            let offset = match field.which()? {
+                schema_capnp::field::Slot(x) => x.get_offset() as usize,
+                schema_capnp::field::Group(_) => return Err(Error::Type(format!("{} is a group", name))),
+            };
+
+            // the pointer section directly follows the data section
+            let data = this.builder.as_reader().get_data_section_as_blob();
+            let wire = unsafe { data.as_ptr().add(data.len() + offset * 8) } as *mut u8;
+
+            let len = if ptr.is_null() { 0 } else { get_list(&arena, alias(&ptr), &element)?.len() };
+
+            Ok(GrowableListPy { message: this.message.clone(), arena, element, ptr, wire, len })
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// Detaches the value of a pointer field, leaving the field null.
+    fn disown(&self, name: &str) -> PyResult<OrphanPy> {
+        let inner = |this: &Self| -> Result<OrphanPy, Error> {
+            let arena = this.arena();
+            let field = find_field(&this.node, name)?;
+            check_active(&this.node, &this.builder.as_reader(), &field)?;
+
+            let (mut ptr, type_) = this.pointer_field(&field)?;
+            let kind = PointerKind::from_type(&arena, &type_)?;
+            let idx = this.message.borrow_mut().disown(&mut ptr)?;
+
+            Ok(OrphanPy { message: this.message.clone(), idx: Some(idx), kind })
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// Attaches an orphan to a pointer field. The orphan must come from the
+    /// same message and have the field's type; it is consumed.
+    fn adopt(&self, name: &str, orphan: &mut OrphanPy) -> PyResult<()> {
+        let inner = |this: &Self, orphan: &mut OrphanPy| -> Result<(), Error> {
+            if !Rc::ptr_eq(&this.message, &orphan.message) {
+                return Err(Error::Type("orphan belongs to a different message".into()));
+            }
+
+            let arena = this.arena();
+            let field = find_field(&this.node, name)?;
+            let (mut ptr, type_) = this.pointer_field(&field)?;
+
+            if !orphan.kind.fits(&arena, &type_)? {
+                return Err(Error::Type(format!("orphan does not match the type of {}", name)));
+            }
+
+            let idx = orphan.idx.take().ok_or(Error::Text("orphan has already been adopted".into()))?;
+            set_active(&this.node, &this.builder, &field)?;
+
+            this.message.borrow_mut().adopt(idx, &mut ptr)
+        };
+
+        inner(self, orphan).map_err(PyErr::from)
+    }
+}
+
+fn list_item(
+    py: Python,
+    message: &MessageRc,
+    arena: &Rc<NodeArena>,
+    list: ListBuilder<'static>,
+    idx: u32,
+    element: &TypeReader,
+) -> Result<PyObject, Error> {
+    use schema_capnp::type_ as T;
+
+    match element.which()? {
+        T::Struct(x) => {
+            Ok(Py::new(py, StructBuilderPy {
+                message: message.clone(),
+                node: get_node(arena, x.get_type_id())?,
+                builder: list.get_struct_element(idx),
+            })?.into())
+        }
+        T::List(_) => {
+            let ptr = list.get_pointer_element(idx);
+            let kind = PointerKind::from_type(arena, element)?;
+
+            pointer_value(py, message, arena, ptr, &kind)
+        }
+        _ => read_element(py, arena, &list.into_reader(), idx, element)
+    }
+}
+
+/// Moves the data words and pointers of `src` into `dst`; pointers are
+/// transferred rather than copied, so nothing is left behind as garbage.
+fn move_struct(mut src: StructBuilder, mut dst: StructBuilder, size: StructSize) {
+    for w in 0..size.data as usize {
+        dst.set_data_field::<u64>(w, src.get_data_field::<u64>(w));
+        src.set_data_field::<u64>(w, 0);
+    }
+
+    for p in 0..size.pointers as usize {
+        let orphan = src.reborrow().get_pointer_field(p).disown();
+        dst.reborrow().get_pointer_field(p).adopt(orphan);
+    }
+}
+
+fn move_element(
+    arena: &Rc<NodeArena>,
+    src: &ListBuilder<'static>,
+    src_idx: u32,
+    dst: &ListBuilder<'static>,
+    dst_idx: u32,
+    element: &TypeReader,
+) -> Result<(), Error> {
+    match element_size(element)? {
+        ElementSize::Void => {}
+        ElementSize::Bit => <bool as PrimitiveElement>::set(dst, dst_idx, <bool as PrimitiveElement>::get_from_builder(src, src_idx)),
+        ElementSize::Byte => <u8 as PrimitiveElement>::set(dst, dst_idx, <u8 as PrimitiveElement>::get_from_builder(src, src_idx)),
+        ElementSize::TwoBytes => <u16 as PrimitiveElement>::set(dst, dst_idx, <u16 as PrimitiveElement>::get_from_builder(src, src_idx)),
+        ElementSize::FourBytes => <u32 as PrimitiveElement>::set(dst, dst_idx, <u32 as PrimitiveElement>::get_from_builder(src, src_idx)),
+        ElementSize::EightBytes => <u64 as PrimitiveElement>::set(dst, dst_idx, <u64 as PrimitiveElement>::get_from_builder(src, src_idx)),
+        ElementSize::Pointer => {
+            let orphan = alias(src).get_pointer_element(src_idx).disown();
+            alias(dst).get_pointer_element(dst_idx).adopt(orphan);
+        }
+        ElementSize::InlineComposite => {
+            let size = match element.which()? {
+                schema_capnp::type_::Struct(x) => message::get_node_struct_size(&get_node(arena, x.get_type_id())?)?,
+                _ => return Err(Error::Type("inline composite element is not a struct".into()))
+            };
+
+            move_struct(alias(src).get_struct_element(src_idx), alias(dst).get_struct_element(dst_idx), size);
+        }
+    }
+
+    Ok(())
+}
+
+fn clear_element(
+    arena: &Rc<NodeArena>,
+    list: &ListBuilder<'static>,
+    idx: u32,
+    element: &TypeReader,
+) -> Result<(), Error> {
+    match element_size(element)? {
+        ElementSize::Void => {}
+        ElementSize::Bit => <bool as PrimitiveElement>::set(list, idx, false),
+        ElementSize::Byte => <u8 as PrimitiveElement>::set(list, idx, 0),
+        ElementSize::TwoBytes => <u16 as PrimitiveElement>::set(list, idx, 0),
+        ElementSize::FourBytes => <u32 as PrimitiveElement>::set(list, idx, 0),
+        ElementSize::EightBytes => <u64 as PrimitiveElement>::set(list, idx, 0),
+        ElementSize::Pointer => alias(list).get_pointer_element(idx).clear(),
+        ElementSize::InlineComposite => {
+            if let schema_capnp::type_::Struct(x) = element.which()? {
+                let size = message::get_node_struct_size(&get_node(arena, x.get_type_id())?)?;
+                let mut item = alias(list).get_struct_element(idx);
+
+                for w in 0..size.data as usize {
+                    item.set_data_field::<u64>(w, 0);
+                }
+                for p in 0..size.pointers as usize {
+                    item.reborrow().get_pointer_field(p).clear();
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Detaches element `idx` of `list` into an orphan. A struct element lives
+/// inside the list, so its content moves to a new struct and the element is
+/// left cleared; any other element leaves a null pointer.
+fn disown_element(
+    message: &MessageRc,
+    arena: &Rc<NodeArena>,
+    list: &ListBuilder<'static>,
+    idx: u32,
+    element: &TypeReader,
+) -> Result<OrphanPy, Error> {
+    match element_size(element)? {
+        ElementSize::Pointer => {
+            let kind = PointerKind::from_type(arena, element)?;
+            let idx = message.borrow_mut().disown(&mut alias(list).get_pointer_element(idx))?;
+
+            Ok(OrphanPy { message: message.clone(), idx: Some(idx), kind })
+        }
+        ElementSize::InlineComposite => {
+            let node = match element.which()? {
+                schema_capnp::type_::Struct(x) => get_node(arena, x.get_type_id())?,
+                _ => return Err(Error::Type("inline composite element is not a struct".into()))
+            };
+            let size = message::get_node_struct_size(&node)?;
+            let orphan = OrphanPy::new_struct(message, node)?;
+
+            let cell = unsafe { detach_pointer(message.borrow().orphan(orphan.idx.unwrap())?) };
+            move_struct(alias(list).get_struct_element(idx), cell.get_struct(size, None)?, size);
+
+            Ok(orphan)
+        }
+        _ => Err(Error::Type("only struct and pointer elements can be disowned".into())),
+    }
+}
+
+/// Attaches an orphan as element `idx` of `list`, abandoning what was there.
+/// A struct orphan has its content moved into the element, its own storage
+/// stays behind as garbage.
+fn adopt_element(
+    message: &MessageRc,
+    arena: &Rc<NodeArena>,
+    list: &ListBuilder<'static>,
+    idx: u32,
+    element: &TypeReader,
+    orphan: &mut OrphanPy,
+) -> Result<(), Error> {
+    if !Rc::ptr_eq(message, &orphan.message) {
+        return Err(Error::Type("orphan belongs to a different message".into()));
+    }
+
+    if !orphan.kind.fits(arena, element)? {
+        return Err(Error::Type("orphan does not match the element type of the list".into()));
+    }
+
+    let cell = orphan.idx.ok_or(Error::Text("orphan has already been adopted".into()))?;
+
+    match element_size(element)? {
+        ElementSize::InlineComposite => {
+            let size = match element.which()? {
+                schema_capnp::type_::Struct(x) => message::get_node_struct_size(&get_node(arena, x.get_type_id())?)?,
+                _ => return Err(Error::Type("inline composite element is not a struct".into()))
+            };
+
+            let src = unsafe { detach_pointer(message.borrow().orphan(cell)?) }.get_struct(size, None)?;
+            move_struct(src, alias(list).get_struct_element(idx), size);
+            message.borrow_mut().release_orphan(cell);
+        }
+        _ => message.borrow_mut().adopt(cell, &mut alias(list).get_pointer_element(idx))?,
+    }
+
+    orphan.idx = None;
+
+    Ok(())
+}
+
+/// Writes element `idx` of `list`; an orphan is adopted, anything else is
+/// converted as by `write_list_item`.
+fn set_element(
+    message: &MessageRc,
+    arena: &Rc<NodeArena>,
+    list: &mut ListBuilder<'static>,
+    idx: u32,
+    element: &TypeReader,
+    value: &PyAny,
+) -> Result<(), Error> {
+    if let Ok(orphan) = value.downcast_mut::<OrphanPy>() {
+        return adopt_element(message, arena, list, idx, element, orphan);
+    }
+
+    write_list_item(arena, list, idx, element, value)
+}
+
+#[pyclass]
+pub struct ListBuilderPy {
+    message: MessageRc,
+    arena: Rc<NodeArena>,
+    element: TypeReader,
+    builder: ListBuilder<'static>,
+}
+
+impl ListBuilderPy {
+    pub(crate) fn new(message: &MessageRc, arena: Rc<NodeArena>, element: TypeReader, builder: ListBuilder<'static>) -> ListBuilderPy {
+        ListBuilderPy { message: message.clone(), arena, element, builder }
+    }
+
+    fn builder(&self) -> ListBuilder<'static> {
+        alias(&self.builder)
+    }
+
+    fn index(&self, idx: isize) -> Result<u32, Error> {
+        let len = self.builder.len() as isize;
+        let idx = if idx < 0 { idx + len } else { idx };
+
+        if idx < 0 || idx >= len {
+            return Err(Error::Py(PyErr::new::<pyo3::exceptions::IndexError, _>("list index out of range")));
+        }
+
+        Ok(idx as u32)
+    }
+
+    fn item(&self, py: Python, idx: u32) -> Result<PyObject, Error> {
+        list_item(py, &self.message, &self.arena, self.builder(), idx, &self.element)
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for ListBuilderPy {
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.builder.len() as usize)
+    }
+
+    fn __getitem__(&self, idx: isize) -> PyResult<PyObject> {
+        let gil = GILGuard::acquire();
+        let py = gil.python();
+
+        let idx = self.index(idx)?;
+        Ok(self.item(py, idx)?)
+    }
+
+    fn __setitem__(&mut self, idx: isize, value: &PyAny) -> PyResult<()> {
+        let idx = self.index(idx)?;
+        Ok(set_element(&self.message, &self.arena, &mut self.builder(), idx, &self.element, value)?)
+    }
+}
+
+#[pymethods]
+impl ListBuilderPy {
+    /// Detaches element `idx`; a struct element is left cleared, a pointer
+    /// element null.
+    fn disown(&self, idx: isize) -> PyResult<OrphanPy> {
+        let idx = self.index(idx)?;
+        Ok(disown_element(&self.message, &self.arena, &self.builder, idx, &self.element)?)
+    }
+
+    /// Attaches an orphan of the element type as element `idx`; it is
+    /// consumed.
+    fn adopt(&self, idx: isize, orphan: &mut OrphanPy) -> PyResult<()> {
+        let idx = self.index(idx)?;
+        Ok(adopt_element(&self.message, &self.arena, &self.builder, idx, &self.element, orphan)?)
+    }
+}
+
+#[pyclass]
+pub struct GrowableListPy {
+    message: MessageRc,
+    arena: Rc<NodeArena>,
+    element: TypeReader,
+    // the field holding the list; the list behind it is swapped on growth
+    ptr: PointerBuilder<'static>,
+    // the wire pointer of that field, see `truncate`
+    wire: *mut u8,
+    len: u32,
+}
+
+impl GrowableListPy {
+    fn list(&self) -> Result<ListBuilder<'static>, Error> {
+        get_list(&self.arena, alias(&self.ptr), &self.element)
+    }
+
+    fn allocated(&self) -> Result<u32, Error> {
+        if self.ptr.is_null() {
+            return Ok(0);
+        }
+
+        Ok(self.list()?.len())
+    }
+
+    /// Moves the first `len` elements into a new list of `capacity` elements
+    /// and points the field at it; the old list is left behind as garbage.
+    fn reallocate(&mut self, capacity: u32) -> Result<ListBuilder<'static>, Error> {
+        let idx = self.message.borrow_mut().new_orphan_cell();
+        let cell = unsafe { detach_pointer(self.message.borrow().orphan(idx)?) };
+        let new = init_list(&self.arena, cell, &self.element, capacity)?;
+
+        if !self.ptr.is_null() {
+            let old = self.list()?;
+
+            for i in 0..self.len {
+                move_element(&self.arena, &old, i, &new, i, &self.element)?;
+            }
+        }
+
+        self.message.borrow_mut().adopt(idx, &mut alias(&self.ptr))?;
+        self.list()
+    }
+
+    /// Shortens the list to `len` in place by rewriting its element count;
+    /// the elements past it are already cleared. False, and nothing done,
+    /// for a list reached through a far pointer.
+    fn truncate(&mut self) -> Result<bool, Error> {
+        let word = unsafe { u64::from_le((self.wire as *const u64).read_unaligned()) };
+
+        // list pointers have kind 1, far pointers 2
+        if word & 3 != 1 {
+            return Ok(false);
+        }
+
+        let list = self.list()?;
+        let len = self.len as u64;
+
+        let count = match element_size(&self.element)? {
+            ElementSize::InlineComposite => {
+                let first = alias(&list).get_struct_element(0).as_reader();
+                let words = first.get_data_section_size() as u64 / 64 + first.get_pointer_section_size() as u64;
+
+                // the tag word before the first element counts the elements
+                unsafe {
+                    let tag = list.into_raw_bytes().as_mut_ptr().sub(8) as *mut u64;
+                    let value = u64::from_le(tag.read_unaligned());
+                    tag.write_unaligned(((value & !0xffff_fffc) | (len << 2)).to_le());
+                }
+
+                len * words
+            }
+            _ => len,
+        };
+
+        unsafe {
+            (self.wire as *mut u64).write_unaligned(((word & ((1 << 35) - 1)) | (count << 35)).to_le());
+        }
+
+        Ok(true)
+    }
+
+    fn reserve(&mut self, needed: u32) -> Result<ListBuilder<'static>, Error> {
+        let capacity = self.allocated()?;
+
+        if capacity >= needed {
+            return self.list();
+        }
+
+        self.reallocate(std::cmp::max(needed, std::cmp::max(4, capacity * 2)))
+    }
+
+    fn index(&self, idx: isize, allow_end: bool) -> Result<u32, Error> {
+        let len = self.len as isize;
+        let idx = if idx < 0 { idx + len } else { idx };
+
+        if idx < 0 || idx > len || (idx == len && !allow_end) {
+            return Err(Error::Py(PyErr::new::<pyo3::exceptions::IndexError, _>("list index out of range")));
+        }
+
+        Ok(idx as u32)
+    }
+
+    fn insert_at(&mut self, idx: u32, value: &PyAny) -> Result<(), Error> {
+        let mut list = self.reserve(self.len + 1)?;
+
+        for i in (idx..self.len).rev() {
+            move_element(&self.arena, &list, i, &list, i + 1, &self.element)?;
+        }
+
+        clear_element(&self.arena, &list, idx, &self.element)?;
+        self.len += 1;
+
+        // the slot is already the default value
+        if value.is_none() {
+            return Ok(());
+        }
+
+        set_element(&self.message, &self.arena, &mut list, idx, &self.element, value)
+    }
+
+    /// Removes element `idx`; struct and list elements come back as an orphan.
+    fn remove_at(&mut self, py: Python, idx: u32) -> Result<PyObject, Error> {
+        let list = self.list()?;
+
+        let value = match element_size(&self.element)? {
+            ElementSize::Pointer => match PointerKind::from_type(&self.arena, &self.element)? {
+                PointerKind::Text | PointerKind::Data => {
+                    let value = read_element(py, &self.arena, &alias(&list).into_reader(), idx, &self.element)?;
+                    alias(&list).get_pointer_element(idx).clear();
+
+                    value
+                }
+                _ => Py::new(py, disown_element(&self.message, &self.arena, &list, idx, &self.element)?)?.into(),
+            }
+            ElementSize::InlineComposite => {
+                Py::new(py, disown_element(&self.message, &self.arena, &list, idx, &self.element)?)?.into()
+            }
+            _ => read_element(py, &self.arena, &alias(&list).into_reader(), idx, &self.element)?,
+        };
+
+        for i in idx + 1..self.len {
+            move_element(&self.arena, &list, i, &list, i - 1, &self.element)?;
+        }
+
+        self.len -= 1;
+        clear_element(&self.arena, &list, self.len, &self.element)?;
+
+        Ok(value)
+    }
+}
+
+#[pymethods]
+impl GrowableListPy {
+    fn append(&mut self, value: &PyAny) -> PyResult<()> {
+        let len = self.len;
+        Ok(self.insert_at(len, value)?)
+    }
+
+    fn extend(&mut self, values: &PyAny) -> PyResult<()> {
+        let values: Vec<&PyAny> = values.extract()?;
+
+        self.reserve(self.len + values.len() as u32)?;
+
+        for x in values {
+            self.append(x)?;
+        }
+
+        Ok(())
+    }
+
+    fn insert(&mut self, idx: isize, value: &PyAny) -> PyResult<()> {
+        let idx = self.index(idx, true)?;
+        Ok(self.insert_at(idx, value)?)
+    }
+
+    #[args(idx = "-1")]
+    fn pop(&mut self, py: Python, idx: isize) -> PyResult<PyObject> {
+        let idx = self.index(idx, false)?;
+        Ok(self.remove_at(py, idx)?)
+    }
+
+    #[getter]
+    fn capacity(&self) -> PyResult<u32> {
+        Ok(self.allocated()?)
+    }
+
+    /// Shrinks the list to its length in place and returns a plain list
+    /// builder. A list that sits in another segment than its field is copied
+    /// instead, leaving the longer one behind until `Builder.compact()`.
+    fn finalize(&mut self) -> PyResult<ListBuilderPy> {
+        let list = if self.allocated()? == self.len || self.truncate()? {
+            self.list()?
+        } else {
+            self.reallocate(self.len)?
+        };
+
+        Ok(ListBuilderPy {
+            message: self.message.clone(),
+            arena: self.arena.clone(),
+            element: self.element,
+            builder: list,
+        })
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for GrowableListPy {
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.len as usize)
+    }
+
+    fn __getitem__(&self, idx: isize) -> PyResult<PyObject> {
+        let gil = GILGuard::acquire();
+        let py = gil.python();
+
+        let idx = self.index(idx, false)?;
+        Ok(list_item(py, &self.message, &self.arena, self.list()?, idx, &self.element)?)
+    }
+
+    fn __setitem__(&mut self, idx: isize, value: &PyAny) -> PyResult<()> {
+        let idx = self.index(idx, false)?;
+        Ok(set_element(&self.message, &self.arena, &mut self.list()?, idx, &self.element, value)?)
+    }
+}
+
+/// A value detached from its message tree, see `StructBuilderPy.disown` and
+/// `Builder.new_orphan`.
+#[pyclass]
+pub struct OrphanPy {
+    message: MessageRc,
+    // None once adopted
+    idx: Option<u64>,
+    kind: PointerKind,
+}
+
+impl OrphanPy {
+    pub fn new_struct(message: &MessageRc, node: NodeRef) -> Result<OrphanPy, Error> {
+        let idx = message.borrow_mut().new_orphan(&node)?;
+
+        Ok(OrphanPy { message: message.clone(), idx: Some(idx), kind: PointerKind::Struct(node) })
+    }
+}
+
+#[pymethods]
+impl OrphanPy {
+    /// Builder (or value, for Text and Data) of the orphaned object.
+    fn get(&self, py: Python) -> PyResult<PyObject> {
+        let inner = |this: &Self| -> Result<PyObject, Error> {
+            let idx = this.idx.ok_or(Error::Text("orphan has already been adopted".into()))?;
+            let arena = this.message.borrow().node_arena().clone();
+            let ptr = unsafe { detach_pointer(this.message.borrow().orphan(idx)?) };
+
+            pointer_value(py, &this.message, &arena, ptr, &this.kind)
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    #[getter]
+    fn adopted(&self) -> PyResult<bool> {
+        Ok(self.idx.is_none())
+    }
+}
+
+impl Drop for OrphanPy {
+    fn drop(&mut self) {
+        if let Some(idx) = self.idx {
+            self.message.borrow_mut().release_orphan(idx);
+        }
+    }
+}
+
+/// Reads a field of `node` out of `reader`; groups and pointers come back as
+/// reader handles that keep `owner` alive.
+pub fn read_field(
+    py: Python,
+    owner: &Owner,
+    arena: &Rc<NodeArena>,
+    node: &NodeRef,
+    reader: StructReader<'static>,
+    field: &FieldReader,
+) -> Result<PyObject, Error> {
+    check_active(node, &reader, field)?;
+
+    match field.which()? {
+        schema_capnp::field::Group(x) => {
+            Ok(Py::new(py, StructReaderPy {
+                owner: owner.clone(),
+                node: get_node(arena, x.get_type_id())?,
+                reader,
+            })?.into())
+        }
+        schema_capnp::field::Slot(x) => {
+            let type_ = x.get_type()?;
+
+            if !is_pointer(&type_)? {
+                return read_data_slot(py, arena, &reader, &x);
+            }
+
+            read_pointer(py, owner, arena, reader.get_pointer_field(x.get_offset() as usize), &type_)
+        }
+    }
+}
+
+pub fn read_pointer(
+    py: Python,
+    owner: &Owner,
+    arena: &Rc<NodeArena>,
+    ptr: PointerReader<'static>,
+    type_: &TypeReader,
+) -> Result<PyObject, Error> {
+    use schema_capnp::type_ as T;
+
+    Ok(match type_.which()? {
+        T::Text(()) => ptr.get_text(None)?.to_object(py),
+        T::Data(()) => PyBytes::new(py, ptr.get_data(None)?).into(),
+        T::Struct(x) => {
+            Py::new(py, StructReaderPy {
+                owner: owner.clone(),
+                node: get_node(arena, x.get_type_id())?,
+                reader: ptr.get_struct(None)?,
+            })?.into()
+        }
+        T::List(x) => {
+            let element = x.get_element_type()?;
+
+            Py::new(py, ListReaderPy {
+                owner: owner.clone(),
+                arena: arena.clone(),
+                element,
+                reader: ptr.get_list(element_size(&element)?, None)?,
+            })?.into()
+        }
+        T::AnyPointer(_) => {
+            Py::new(py, AnyPointerReaderPy { owner: owner.clone(), reader: ptr })?.into()
+        }
+        T::Interface(x) => rpc::read_cap(py, &get_node(arena, x.get_type_id())?, &ptr)?,
+        _ => return Err(Error::Type("not a pointer type".into()))
+    })
+}
+
+pub fn read_list_item(
+    py: Python,
+    owner: &Owner,
+    arena: &Rc<NodeArena>,
+    list: &ListReader<'static>,
+    idx: u32,
+    element: &TypeReader,
+) -> Result<PyObject, Error> {
+    use schema_capnp::type_ as T;
+
+    match element.which()? {
+        T::Struct(x) => {
+            Ok(Py::new(py, StructReaderPy {
+                owner: owner.clone(),
+                node: get_node(arena, x.get_type_id())?,
+                reader: list.get_struct_element(idx),
+            })?.into())
+        }
+        T::List(_) | T::AnyPointer(_) | T::Interface(_) => {
+            read_pointer(py, owner, arena, list.get_pointer_element(idx), element)
+        }
+        _ => read_element(py, arena, list, idx, element)
+    }
+}
+
+#[pyclass]
+pub struct StructReaderPy {
+    pub owner: Owner,
+    pub node: NodeRef,
+    pub reader: StructReader<'static>,
+}
+
+impl StructReaderPy {
+    /// `caps` is the cap table that travels beside the message, by position;
+    /// without it capability pointers carry their index on the wire.
+    pub fn read_root(frame: Frame, node: NodeRef, caps: Option<Vec<capability::Entry>>) -> Result<StructReaderPy, Error> {
+        struct_node(&node)?;
+
+        let table: Rc<CapTable> = match caps {
+            Some(x) => CapTable::new(x),
+            None => CapTable::plain(&frame),
+        }.into();
+
+        let message = Rc::new(frame.into_reader(capnp::message::ReaderOptions::new()));
+        let mut root: RootPointer = message.get_root()?;
+        table.imbue_reader(&mut root.0);
+
+        let reader = unsafe { detach_reader(root.0.get_struct(None)?) };
+
+        Ok(StructReaderPy { owner: Owner::Message(message, table), node, reader })
+    }
+
+    fn arena(&self) -> Rc<NodeArena> {
+        self.node.rc()
+    }
+}
+
+#[pymethods]
+impl StructReaderPy {
+    fn get(&self, py: Python, name: &str) -> PyResult<PyObject> {
+        let inner = |this: &Self| -> Result<PyObject, Error> {
+            let field = find_field(&this.node, name)?;
+            read_field(py, &this.owner, &this.arena(), &this.node, this.reader, &field)
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// False for null pointer fields.
+    fn has(&self, name: &str) -> PyResult<bool> {
+        let inner = |this: &Self| -> Result<bool, Error> {
+            let field = find_field(&this.node, name)?;
+
+            match field.which()? {
+                schema_capnp::field::Slot(x) if is_pointer(&x.get_type()?)? => {
+                    Ok(!this.reader.get_pointer_field(x.get_offset() as usize).is_null())
+                }
+                _ => Ok(true),
+            }
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    fn which(&self) -> PyResult<Option<String>> {
+        let inner = |this: &Self| -> Result<Option<String>, Error> {
+            match which_field(&this.node, &this.reader)? {
+                Some(x) => Ok(Some(x.get_name()?.to_string())),
+                None => Ok(None),
+            }
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// The capnp text format; `str()` gives the same on one line.
+    #[args(pretty = false)]
+    fn to_text(&self, pretty: bool) -> PyResult<String> {
+        Ok(text::print_struct(&self.node, &self.reader, pretty)?)
+    }
+
+    /// Canonical encoding of this struct as a message root: a single bare
+    /// segment without the framing header.
+    fn canonicalize(&self, py: Python) -> PyResult<PyObject> {
+        Ok(PyBytes::new(py, &canonical::canonicalize_struct(&self.reader)?).into())
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for StructReaderPy {
+    fn __getattr__(&self, name: String) -> PyResult<PyObject> {
+        let gil = GILGuard::acquire();
+        let py = gil.python();
+
+        self.get(py, &name)
+    }
+
+    fn __str__(&self) -> PyResult<String> {
+        Ok(text::print_struct(&self.node, &self.reader, false)?)
+    }
+
+    // equality and hashing ignore segmentation and layout, only content
+    // counts; a reader into a builder can still change, so it has no hash
+    fn __hash__(&self) -> PyResult<isize> {
+        if let Owner::Builder(_) = self.owner {
+            return Err(Error::Type("readers of a message still being built are unhashable".into()).into());
+        }
+
+        Ok(canonical::hash_bytes(&canonical::canonicalize_struct(&self.reader)?))
+    }
+
+    fn __richcmp__(&self, other: &PyAny, op: CompareOp) -> PyResult<PyObject> {
+        let gil = GILGuard::acquire();
+        let py = gil.python();
+
+        let other = match other.downcast_ref::<StructReaderPy>() {
+            Ok(x) => x,
+            Err(_) => return Ok(py.NotImplemented()),
+        };
+
+        let equal = self.node.get_id() == other.node.get_id()
+            && canonical::canonicalize_struct(&self.reader)? == canonical::canonicalize_struct(&other.reader)?;
+
+        Ok(match op {
+            CompareOp::Eq => equal.to_object(py),
+            CompareOp::Ne => (!equal).to_object(py),
+            _ => py.NotImplemented(),
+        })
+    }
+}
+
+#[pyclass]
+pub struct ListReaderPy {
+    owner: Owner,
+    arena: Rc<NodeArena>,
+    element: TypeReader,
+    reader: ListReader<'static>,
+}
+
+impl ListReaderPy {
+    pub(crate) fn arena(&self) -> &Rc<NodeArena> {
+        &self.arena
+    }
+
+    pub(crate) fn element(&self) -> TypeReader {
+        self.element
+    }
+
+    pub(crate) fn reader(&self) -> ListReader<'static> {
+        self.reader
+    }
+
+    /// Another handle on the same list, keeping the message alive.
+    pub(crate) fn share(&self) -> ListReaderPy {
+        ListReaderPy { owner: self.owner.clone(), arena: self.arena.clone(), element: self.element, reader: self.reader }
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for ListReaderPy {
+    fn __str__(&self) -> PyResult<String> {
+        Ok(text::print_list(&self.arena, &self.reader, &self.element, false)?)
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for ListReaderPy {
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.reader.len() as usize)
+    }
+
+    fn __getitem__(&self, idx: isize) -> PyResult<PyObject> {
+        let gil = GILGuard::acquire();
+        let py = gil.python();
+
+        let len = self.reader.len() as isize;
+        let idx = if idx < 0 { idx + len } else { idx };
+
+        if idx < 0 || idx >= len {
+            return Err(PyErr::new::<pyo3::exceptions::IndexError, _>("list index out of range"));
+        }
+
+        Ok(read_list_item(py, &self.owner, &self.arena, &self.reader, idx as u32, &self.element)?)
+    }
+}
+
+/// An untyped pointer (an `AnyPointer` field); reinterpret it with one of
+/// the `as_*` methods or copy it into a builder field as is.
+#[pyclass]
+pub struct AnyPointerReaderPy {
+    owner: Owner,
+    reader: PointerReader<'static>,
+}
+
+#[pymethods]
+impl AnyPointerReaderPy {
+    fn is_null(&self) -> PyResult<bool> {
+        Ok(self.reader.is_null())
+    }
+
+    fn as_struct(&self, node: &NodePy) -> PyResult<StructReaderPy> {
+        let node = node_ref(node)?;
+        struct_node(&node)?;
+
+        Ok(StructReaderPy {
+            owner: self.owner.clone(),
+            node,
+            reader: self.reader.get_struct(None).map_err(Error::from)?,
+        })
+    }
+
+    fn as_text(&self) -> PyResult<String> {
+        Ok(self.reader.get_text(None).map_err(Error::from)?.to_string())
+    }
+
+    fn as_data(&self, py: Python) -> PyResult<PyObject> {
+        Ok(PyBytes::new(py, self.reader.get_data(None).map_err(Error::from)?).into())
+    }
+}
+
+#[pyproto]
+impl PyObjectProtocol for StructBuilderPy {
+    fn __repr__(&self) -> PyResult<String> {
+        let node = &self.node;
+        let prefix = node.get_display_name_prefix_length() as usize;
+
+        Ok(format!("<builder {}>", &node.get_display_name().map_err(Error::from)?[prefix..]))
+    }
+
+    fn __str__(&self) -> PyResult<String> {
+        Ok(text::print_struct(&self.node, &self.builder().as_reader(), false)?)
+    }
+}
+
+/// Reads a struct reader handle, or the current contents of a struct builder.
+pub fn struct_reader(value: &PyAny) -> Result<StructReaderPy, Error> {
+    if let Ok(x) = value.downcast_ref::<StructReaderPy>() {
+        return Ok(StructReaderPy { owner: x.owner.clone(), node: x.node.clone(), reader: x.reader });
+    }
+
+    Ok(value.downcast_ref::<StructBuilderPy>()?.as_reader()?)
+}
+
+pub fn node_ref(node: &NodePy) -> Result<NodeRef, Error> {
+    get_node(&node.i.arena, node.i.id)
+}
@@ -0,0 +1,358 @@
+use capnp::Word;
+use capnp::message::ReaderSegments;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::Error;
+
+// capnp/src/serialize.rs uses the same ceiling when reading a segment table
+pub const DEFAULT_MAX_SEGMENTS: usize = 512;
+// matches ReaderOptions::new().traversal_limit_in_words
+pub const DEFAULT_MAX_WORDS: usize = 8 * 1024 * 1024;
+
+/// A complete message as it came off the wire: all segments live in one
+/// contiguous allocation, `segments` holds (start, len) in words.
+#[derive(Clone)]
+pub struct Frame {
+    words: Vec<Word>,
+    segments: Vec<(usize, usize)>,
+}
+
+impl Frame {
+    /// Parses exactly one unpacked message.
+    pub fn from_bytes(data: &[u8]) -> Result<Frame, Error> {
+        let mut decoder = FrameDecoder::new(false, DEFAULT_MAX_SEGMENTS, DEFAULT_MAX_WORDS);
+        let mut frames = decoder.feed(data)?;
+        decoder.close()?;
+
+        match frames.len() {
+            1 => Ok(frames.pop().unwrap()),
+            x => Err(failed(format!("expected one message, got {}", x))),
+        }
+    }
+
+    /// Wraps a bare segment, e.g. a message in canonical form.
+    pub fn single_segment(data: &[u8]) -> Frame {
+        let mut words = Word::allocate_zeroed_vec(data.len() / 8);
+        Word::words_to_bytes_mut(&mut words).copy_from_slice(&data[..words.len() * 8]);
+
+        let len = words.len();
+
+        Frame { words, segments: vec![(0, len)] }
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn total_words(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn into_reader(self, options: capnp::message::ReaderOptions) -> capnp::message::Reader<Frame> {
+        capnp::message::Reader::new(self, options)
+    }
+
+    /// Serializes back into the standard (unpacked) stream framing.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let segments: Vec<&[Word]> = self.segments.iter()
+            .map(|&(start, len)| &self.words[start..start + len])
+            .collect();
+
+        write_segments(&segments)
+    }
+}
+
+pub fn write_segments(segments: &[&[Word]]) -> Vec<u8> {
+    let header = header_len(segments.len());
+    let total: usize = segments.iter().map(|x| x.len()).sum();
+    let mut r = Vec::with_capacity(header + total * 8);
+
+    r.extend_from_slice(&((segments.len() - 1) as u32).to_le_bytes());
+    for x in segments.iter() {
+        r.extend_from_slice(&(x.len() as u32).to_le_bytes());
+    }
+    r.resize(header, 0);
+    for x in segments.iter() {
+        r.extend_from_slice(Word::words_to_bytes(x));
+    }
+
+    r
+}
+
+impl ReaderSegments for Frame {
+    fn get_segment<'a>(&'a self, idx: u32) -> Option<&'a [Word]> {
+        self.segments.get(idx as usize).map(|&(start, len)| &self.words[start..start + len])
+    }
+}
+
+fn header_len(segment_count: usize) -> usize {
+    // u32 segment count, u32 per segment size, padded to a word boundary
+    let x = 4 + 4 * segment_count;
+    (x + 7) & !7
+}
+
+// every framing error is a capnp one, whatever layer noticed it
+fn failed(message: String) -> Error {
+    Error::Capnp(capnp::Error::failed(message))
+}
+
+fn read_u32(buf: &[u8], at: usize) -> usize {
+    let mut b = [0u8; 4];
+    b.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(b) as usize
+}
+
+/// Incremental inverse of `capnp::serialize_packed`. Only whole packed words
+/// are consumed; a tag whose payload has not fully arrived stays in `raw`.
+struct Unpacker {
+    raw: Vec<u8>,
+}
+
+impl Unpacker {
+    fn new() -> Self {
+        Unpacker { raw: Vec::new() }
+    }
+
+    /// Unpacks until `out` holds `limit` bytes; true if it stopped there
+    /// with more to go.
+    fn unpack_into(&mut self, out: &mut Vec<u8>, limit: usize) -> bool {
+        let raw = &self.raw;
+        let mut pos = 0;
+
+        while pos < raw.len() && out.len() < limit {
+            let tag = raw[pos];
+            let nonzero = tag.count_ones() as usize;
+
+            match tag {
+                0x00 => {
+                    if pos + 2 > raw.len() {
+                        break;
+                    }
+                    let run = raw[pos + 1] as usize;
+                    out.resize(out.len() + 8 * (1 + run), 0);
+                    pos += 2;
+                }
+                0xff => {
+                    if pos + 10 > raw.len() {
+                        break;
+                    }
+                    let run = raw[pos + 9] as usize;
+                    if pos + 10 + run * 8 > raw.len() {
+                        break;
+                    }
+                    out.extend_from_slice(&raw[pos + 1..pos + 9]);
+                    out.extend_from_slice(&raw[pos + 10..pos + 10 + run * 8]);
+                    pos += 10 + run * 8;
+                }
+                _ => {
+                    if pos + 1 + nonzero > raw.len() {
+                        break;
+                    }
+                    let mut src = pos + 1;
+                    for bit in 0..8 {
+                        if tag & (1 << bit) != 0 {
+                            out.push(raw[src]);
+                            src += 1;
+                        } else {
+                            out.push(0);
+                        }
+                    }
+                    pos = src;
+                }
+            }
+        }
+
+        self.raw.drain(..pos);
+
+        out.len() >= limit && !self.raw.is_empty()
+    }
+}
+
+/// Sans-IO decoder for the Cap'n Proto stream framing.
+///
+/// Bytes are pushed in with `feed` in whatever chunks the transport delivers
+/// them, complete messages come out. It never touches a socket, so the same
+/// object serves `asyncio.Protocol.data_received` and Rust async readers.
+pub struct FrameDecoder {
+    unpacker: Option<Unpacker>,
+    buf: Vec<u8>,
+    max_segments: usize,
+    max_words: usize,
+    failed: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(packed: bool, max_segments: usize, max_words: usize) -> Self {
+        FrameDecoder {
+            unpacker: if packed { Some(Unpacker::new()) } else { None },
+            buf: Vec::new(),
+            max_segments,
+            max_words,
+            failed: false,
+        }
+    }
+
+    /// Number of bytes received but not yet part of a returned frame.
+    pub fn buffered(&self) -> usize {
+        self.buf.len() + self.unpacker.as_ref().map(|x| x.raw.len()).unwrap_or(0)
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<Frame>, Error> {
+        if self.failed {
+            return Err(failed("decoder failed earlier, stream is out of sync".into()));
+        }
+
+        match self.unpacker {
+            Some(ref mut x) => x.raw.extend_from_slice(data),
+            None => self.buf.extend_from_slice(data),
+        }
+
+        let mut r = Vec::new();
+
+        match self.frames(&mut r) {
+            Ok(()) => Ok(r),
+            Err(x) => {
+                self.failed = true;
+                Err(x)
+            }
+        }
+    }
+
+    fn frames(&mut self, r: &mut Vec<Frame>) -> Result<(), Error> {
+        // a packed stream is unpacked no further than the largest message
+        // allowed, a run of zero words expands two bytes into two kilobytes
+        let limit = header_len(self.max_segments) + self.max_words * 8;
+
+        loop {
+            let more = match self.unpacker {
+                Some(ref mut x) => x.unpack_into(&mut self.buf, limit),
+                None => false,
+            };
+
+            let before = r.len();
+
+            while let Some(x) = self.next_frame()? {
+                r.push(x);
+            }
+
+            if !more {
+                return Ok(());
+            }
+
+            if r.len() == before {
+                return Err(failed(format!("message is too large (limit {} words)", self.max_words)));
+            }
+        }
+    }
+
+    /// Fails if the stream ended in the middle of a message.
+    pub fn close(&mut self) -> Result<(), Error> {
+        if self.buffered() > 0 {
+            self.failed = true;
+            return Err(failed(format!("stream closed with {} bytes of an incomplete message", self.buffered())));
+        }
+
+        Ok(())
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+
+        let segment_count = read_u32(&self.buf, 0) + 1;
+
+        if segment_count > self.max_segments {
+            return Err(failed(format!("too many segments: {} (limit {})", segment_count, self.max_segments)));
+        }
+
+        let header = header_len(segment_count);
+
+        if self.buf.len() < header {
+            return Ok(None);
+        }
+
+        let mut segments = Vec::with_capacity(segment_count);
+        let mut total = 0;
+
+        for i in 0..segment_count {
+            let len = read_u32(&self.buf, 4 + 4 * i);
+            segments.push((total, len));
+            total += len;
+        }
+
+        if total > self.max_words {
+            return Err(failed(format!("message is too large: {} words (limit {})", total, self.max_words)));
+        }
+
+        if self.buf.len() < header + total * 8 {
+            return Ok(None);
+        }
+
+        let mut words = Word::allocate_zeroed_vec(total);
+        Word::words_to_bytes_mut(&mut words).copy_from_slice(&self.buf[header..header + total * 8]);
+
+        self.buf.drain(..header + total * 8);
+
+        Ok(Some(Frame { words, segments }))
+    }
+}
+
+#[pyclass]
+pub struct FramePy {
+    pub i: Frame,
+}
+
+#[pymethods]
+impl FramePy {
+    #[getter]
+    fn segment_count(&self) -> PyResult<usize> {
+        Ok(self.i.segment_count())
+    }
+
+    #[getter]
+    fn total_words(&self) -> PyResult<usize> {
+        Ok(self.i.total_words())
+    }
+
+    fn to_bytes(&self, py: Python) -> PyResult<PyObject> {
+        Ok(PyBytes::new(py, &self.i.to_bytes()).into())
+    }
+}
+
+#[pyclass]
+pub struct FrameDecoderPy {
+    i: FrameDecoder,
+}
+
+#[pymethods]
+impl FrameDecoderPy {
+    #[new]
+    #[args(packed = false, max_segments = "DEFAULT_MAX_SEGMENTS", max_words = "DEFAULT_MAX_WORDS")]
+    fn __new__(obj: &PyRawObject, packed: bool, max_segments: usize, max_words: usize) -> PyResult<()> {
+        obj.init(FrameDecoderPy { i: FrameDecoder::new(packed, max_segments, max_words) });
+        Ok(())
+    }
+
+    fn feed(&mut self, py: Python, data: &PyBytes) -> PyResult<Vec<PyObject>> {
+        let frames = self.i.feed(data.as_bytes())?;
+
+        let mut r = Vec::with_capacity(frames.len());
+
+        for x in frames {
+            r.push(Py::new(py, FramePy { i: x })?.into());
+        }
+
+        Ok(r)
+    }
+
+    fn close(&mut self) -> PyResult<()> {
+        Ok(self.i.close()?)
+    }
+
+    #[getter]
+    fn buffered(&self) -> PyResult<usize> {
+        Ok(self.i.buffered())
+    }
+}
@@ -0,0 +1,615 @@
+//! JSON with the conventions of capnp's `JsonCodec` (capnp/compat/json.h).
+
+use std::rc::Rc;
+
+use capnp::private::layout::{ListBuilder, ListReader, PointerBuilder, PointerReader, StructBuilder, StructReader};
+use capnpc::schema_capnp;
+use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyDict, PyList, PyString};
+
+use crate::{Error, NodeArena};
+use crate::dynamic::{self, NodeRef, StructReaderPy, TypeReader};
+use crate::message;
+use crate::objs;
+
+// annotation ids from capnp/compat/json.capnp
+const NAME: u64 = 0xfa5b1fd61c2e7c3d;
+const FLATTEN: u64 = 0x82d3e852af0336bf;
+const DISCRIMINATOR: u64 = 0xcfa794e8d19a0162;
+const BASE64: u64 = 0xd7d879450a253e4b;
+const HEX: u64 = 0xf061e22f0ae5c7b5;
+
+const BASE64_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DataEncoding {
+    Array,
+    Base64,
+    Hex,
+}
+
+impl DataEncoding {
+    pub fn from_str(x: &str) -> Result<DataEncoding, Error> {
+        match x {
+            "array" => Ok(DataEncoding::Array),
+            "base64" => Ok(DataEncoding::Base64),
+            "hex" => Ok(DataEncoding::Hex),
+            x => Err(Error::Type(format!("unknown data encoding: {}", x))),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Discriminator {
+    name: Option<String>,
+    value_name: Option<String>,
+}
+
+/// The `$Json` annotations of a field or a node.
+#[derive(Default)]
+struct Annotations {
+    name: Option<String>,
+    flatten: Option<String>,
+    discriminator: Option<Discriminator>,
+    data: Option<DataEncoding>,
+}
+
+impl Annotations {
+    fn from_reader(
+        reader: &capnp::struct_list::Reader<schema_capnp::annotation::Owned>
+    ) -> Result<Annotations, Error> {
+        let x = objs::Annotations::from_reader(reader)?;
+        let mut r = Annotations::default();
+
+        if let Some(objs::Value::Text(name)) = x.get(NAME).map(|x| x.value()) {
+            r.name = Some(String::from_utf8_lossy(name).into_owned());
+        }
+
+        if let Some(objs::Value::Struct(options)) = x.get(FLATTEN).map(|x| x.value()) {
+            r.flatten = Some(options.text_field(0)?.unwrap_or_default());
+        }
+
+        if let Some(objs::Value::Struct(options)) = x.get(DISCRIMINATOR).map(|x| x.value()) {
+            r.discriminator = Some(Discriminator {
+                name: options.text_field(0)?,
+                value_name: options.text_field(1)?,
+            });
+        }
+
+        if x.get(BASE64).is_some() {
+            r.data = Some(DataEncoding::Base64);
+        } else if x.get(HEX).is_some() {
+            r.data = Some(DataEncoding::Hex);
+        }
+
+        Ok(r)
+    }
+
+    fn field(field: &dynamic::FieldReader) -> Result<Annotations, Error> {
+        Annotations::from_reader(&field.get_annotations()?)
+    }
+
+    fn json_name(&self, field: &dynamic::FieldReader) -> Result<String, Error> {
+        match self.name {
+            Some(ref x) => Ok(x.clone()),
+            None => Ok(field.get_name()?.to_string()),
+        }
+    }
+}
+
+fn enumerant_names(arena: &Rc<NodeArena>, id: u64) -> Result<Vec<String>, Error> {
+    match dynamic::get_node(arena, id)?.which()? {
+        schema_capnp::node::Enum(x) => {
+            let mut r = Vec::new();
+
+            for item in x.get_enumerants()?.iter() {
+                r.push(Annotations::from_reader(&item.get_annotations()?)?.name.unwrap_or(item.get_name()?.to_string()));
+            }
+
+            Ok(r)
+        }
+        _ => Err(Error::Type("not an enum".into()))
+    }
+}
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut r = String::with_capacity((data.len() + 2) / 3 * 4);
+
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                r.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                r.push('=');
+            }
+        }
+    }
+
+    r
+}
+
+pub fn base64_decode(text: &str) -> Result<Vec<u8>, Error> {
+    let data = text.trim_end_matches('=').as_bytes();
+    let padding = text.len() - data.len();
+
+    if text.len() % 4 != 0 || padding > 2 {
+        return Err(Error::Value(format!("invalid base64 length or padding: {:?}", text)));
+    }
+
+    let mut r = Vec::with_capacity(text.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut bits = 0;
+
+    for &c in data {
+        let value = match BASE64_ALPHABET.iter().position(|&x| x == c) {
+            Some(x) => x as u32,
+            None => return Err(Error::Value(format!("invalid base64 character: {:?}", c as char))),
+        };
+
+        acc = acc << 6 | value;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            r.push((acc >> bits) as u8);
+        }
+    }
+
+    // the bits of the last character past the data are zero in an encoding
+    if acc & ((1 << bits) - 1) != 0 {
+        return Err(Error::Value(format!("invalid base64 padding: {:?}", text)));
+    }
+
+    Ok(r)
+}
+
+fn hex_decode(text: &str) -> Result<Vec<u8>, Error> {
+    fn digit(c: u8) -> Option<u8> {
+        (c as char).to_digit(16).map(|x| x as u8)
+    }
+
+    let bytes = text.as_bytes();
+
+    if bytes.len() % 2 != 0 {
+        return Err(Error::Value("odd number of hex digits".into()));
+    }
+
+    bytes.chunks(2)
+        .map(|x| match (digit(x[0]), digit(x[1])) {
+            (Some(a), Some(b)) => Ok(a << 4 | b),
+            _ => Err(Error::Value(format!("invalid hex: {:?}", text))),
+        })
+        .collect()
+}
+
+struct Encoder<'a> {
+    py: Python<'a>,
+    arena: Rc<NodeArena>,
+    data: DataEncoding,
+}
+
+impl<'a> Encoder<'a> {
+    /// Adds the fields of `reader` to `obj`, names prefixed by a flattening parent.
+    fn struct_(
+        &self,
+        node: &NodeRef,
+        reader: &StructReader,
+        discriminator: Option<Discriminator>,
+        obj: &PyDict,
+        prefix: &str,
+    ) -> Result<(), Error> {
+        let active = dynamic::which_field(node, reader)?.map(|x| x.get_discriminant_value());
+
+        for field in dynamic::struct_node(node)?.get_fields()?.iter() {
+            let discriminant = field.get_discriminant_value();
+            let is_member = discriminant != schema_capnp::field::NO_DISCRIMINANT;
+
+            if is_member && Some(discriminant) != active {
+                continue;
+            }
+
+            let annotations = Annotations::field(&field)?;
+            let mut name = format!("{}{}", prefix, annotations.json_name(&field)?);
+
+            if let (true, Some(x)) = (is_member, discriminator.as_ref()) {
+                let key = format!("{}{}", prefix, x.name.clone().unwrap_or("which".into()));
+                obj.set_item(key, annotations.json_name(&field)?)?;
+
+                if let Some(ref value) = x.value_name {
+                    name = format!("{}{}", prefix, value);
+                }
+            }
+
+            match field.which()? {
+                schema_capnp::field::Group(x) => {
+                    let group = dynamic::get_node(&self.arena, x.get_type_id())?;
+                    let discriminator = annotations.discriminator.clone();
+
+                    match annotations.flatten {
+                        Some(ref flat) => {
+                            self.struct_(&group, reader, discriminator, obj, &format!("{}{}", prefix, flat))?;
+                        }
+                        None => {
+                            let inner = PyDict::new(self.py);
+                            self.struct_(&group, reader, discriminator, inner, "")?;
+                            obj.set_item(name, inner)?;
+                        }
+                    }
+                }
+                schema_capnp::field::Slot(x) => {
+                    let type_ = x.get_type()?;
+
+                    if !dynamic::is_pointer(&type_)? {
+                        let value = dynamic::read_data_slot(self.py, &self.arena, reader, &x)?;
+                        obj.set_item(name, self.primitive(&type_, value)?)?;
+                        continue;
+                    }
+
+                    let ptr = reader.get_pointer_field(x.get_offset() as usize);
+
+                    if ptr.is_null() {
+                        continue;
+                    }
+
+                    match (type_.which()?, annotations.flatten) {
+                        (schema_capnp::type_::Struct(y), Some(flat)) => {
+                            let inner = dynamic::get_node(&self.arena, y.get_type_id())?;
+                            let discriminator = Annotations::from_reader(&inner.get_annotations()?)?.discriminator;
+
+                            self.struct_(&inner, &ptr.get_struct(None)?, discriminator, obj, &format!("{}{}", prefix, flat))?;
+                        }
+                        _ => {
+                            obj.set_item(name, self.pointer(&ptr, &type_, annotations.data)?)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn top(&self, node: &NodeRef, reader: &StructReader) -> Result<PyObject, Error> {
+        let obj = PyDict::new(self.py);
+        let discriminator = Annotations::from_reader(&node.get_annotations()?)?.discriminator;
+
+        self.struct_(node, reader, discriminator, obj, "")?;
+
+        Ok(obj.to_object(self.py))
+    }
+
+    fn primitive(&self, type_: &TypeReader, value: PyObject) -> Result<PyObject, Error> {
+        use schema_capnp::type_ as T;
+
+        let py = self.py;
+
+        Ok(match type_.which()? {
+            // doubles lose precision past 2^53, so 64-bit integers are strings
+            T::Int64(()) => value.extract::<i64>(py)?.to_string().to_object(py),
+            T::Uint64(()) => value.extract::<u64>(py)?.to_string().to_object(py),
+            T::Float32(()) | T::Float64(()) => {
+                let x: f64 = value.extract(py)?;
+
+                if x.is_nan() {
+                    "NaN".to_object(py)
+                } else if x.is_infinite() {
+                    if x > 0.0 { "Infinity" } else { "-Infinity" }.to_object(py)
+                } else {
+                    value
+                }
+            }
+            T::Enum(x) => match value.extract::<u16>(py) {
+                // unknown enumerants are encoded as numbers, known ones were already named
+                Ok(_) => value,
+                Err(_) => {
+                    let name: String = value.extract(py)?;
+                    let names = enumerant_names(&self.arena, x.get_type_id())?;
+                    let idx = dynamic::enumerant_index(&self.arena, x.get_type_id(), &name)?;
+
+                    names[idx as usize].to_object(py)
+                }
+            },
+            _ => value,
+        })
+    }
+
+    fn data(&self, data: &[u8], encoding: Option<DataEncoding>) -> PyObject {
+        match encoding.unwrap_or(self.data) {
+            DataEncoding::Array => data.to_vec().to_object(self.py),
+            DataEncoding::Base64 => base64_encode(data).to_object(self.py),
+            DataEncoding::Hex => data.iter().map(|x| format!("{:02x}", x)).collect::<String>().to_object(self.py),
+        }
+    }
+
+    fn pointer(&self, ptr: &PointerReader, type_: &TypeReader, encoding: Option<DataEncoding>) -> Result<PyObject, Error> {
+        use schema_capnp::type_ as T;
+
+        Ok(match type_.which()? {
+            T::Text(()) => ptr.get_text(None)?.to_object(self.py),
+            T::Data(()) => self.data(ptr.get_data(None)?, encoding),
+            T::Struct(x) => self.top(&dynamic::get_node(&self.arena, x.get_type_id())?, &ptr.get_struct(None)?)?,
+            T::List(x) => {
+                let element = x.get_element_type()?;
+                self.list(&ptr.get_list(dynamic::element_size(&element)?, None)?, &element, encoding)?
+            }
+            T::Interface(_) => return Err(Error::Type("capabilities cannot be encoded as JSON".into())),
+            _ => return Err(Error::Type("AnyPointer cannot be encoded as JSON".into())),
+        })
+    }
+
+    fn list(&self, list: &ListReader, element: &TypeReader, encoding: Option<DataEncoding>) -> Result<PyObject, Error> {
+        use schema_capnp::type_ as T;
+
+        let r = PyList::empty(self.py);
+
+        for i in 0..list.len() {
+            let value = match element.which()? {
+                T::Struct(x) => self.top(&dynamic::get_node(&self.arena, x.get_type_id())?, &list.get_struct_element(i))?,
+                T::Text(()) | T::Data(()) | T::List(_) | T::AnyPointer(_) | T::Interface(_) => {
+                    self.pointer(&list.get_pointer_element(i), element, encoding)?
+                }
+                _ => self.primitive(element, dynamic::read_element(self.py, &self.arena, list, i, element)?)?,
+            };
+
+            r.append(value)?;
+        }
+
+        Ok(r.to_object(self.py))
+    }
+}
+
+struct Decoder<'a> {
+    py: Python<'a>,
+    arena: Rc<NodeArena>,
+}
+
+impl<'a> Decoder<'a> {
+    fn object<'b>(&self, value: &'b PyAny) -> Result<&'b PyDict, Error> {
+        value.downcast_ref::<PyDict>().map_err(|_| Error::Value("expected a JSON object".into()))
+    }
+
+    /// Reads the fields of `node` out of `obj`; flattened members come from
+    /// the same object with their prefix.
+    fn struct_(
+        &self,
+        node: &NodeRef,
+        builder: &mut StructBuilder,
+        discriminator: Option<Discriminator>,
+        obj: &PyDict,
+        prefix: &str,
+    ) -> Result<(), Error> {
+        let arm = match discriminator {
+            Some(ref x) => {
+                let key = format!("{}{}", prefix, x.name.clone().unwrap_or("which".into()));
+
+                match obj.get_item(key) {
+                    Some(x) if !x.is_none() => Some(x.extract::<String>()?),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+
+        for field in dynamic::struct_node(node)?.get_fields()?.iter() {
+            let is_member = field.get_discriminant_value() != schema_capnp::field::NO_DISCRIMINANT;
+            let annotations = Annotations::field(&field)?;
+            let json_name = annotations.json_name(&field)?;
+            let mut name = format!("{}{}", prefix, json_name);
+
+            if let (true, Some(x)) = (is_member, discriminator.as_ref()) {
+                if arm.as_ref() != Some(&json_name) {
+                    continue;
+                }
+
+                if let Some(ref value) = x.value_name {
+                    name = format!("{}{}", prefix, value);
+                }
+            }
+
+            let flatten = match annotations.flatten {
+                Some(ref x) => Some(format!("{}{}", prefix, x)),
+                None => None,
+            };
+
+            let value = match (flatten.is_some(), obj.get_item(&name)) {
+                (false, None) => {
+                    // the arm named by a discriminator may have no value of its own
+                    if is_member && arm.is_some() {
+                        dynamic::set_active(node, builder, &field)?;
+                    }
+                    continue;
+                }
+                (false, Some(x)) if x.is_none() => {
+                    // null selects Void members and leaves pointers unset
+                    if is_member {
+                        dynamic::set_active(node, builder, &field)?;
+                    }
+                    continue;
+                }
+                (_, x) => x,
+            };
+
+            match field.which()? {
+                schema_capnp::field::Group(x) => {
+                    let group = dynamic::get_node(&self.arena, x.get_type_id())?;
+                    let discriminator = annotations.discriminator.clone();
+
+                    dynamic::set_active(node, builder, &field)?;
+
+                    match (flatten, value) {
+                        (Some(flat), _) => self.struct_(&group, builder, discriminator, obj, &flat)?,
+                        (None, Some(x)) => self.struct_(&group, builder, discriminator, self.object(x)?, "")?,
+                        (None, None) => {}
+                    }
+                }
+                schema_capnp::field::Slot(x) => {
+                    let type_ = x.get_type()?;
+                    let offset = x.get_offset() as usize;
+
+                    if let (Some(flat), schema_capnp::type_::Struct(y)) = (flatten, type_.which()?) {
+                        dynamic::set_active(node, builder, &field)?;
+
+                        let inner = dynamic::get_node(&self.arena, y.get_type_id())?;
+                        let discriminator = Annotations::from_reader(&inner.get_annotations()?)?.discriminator;
+                        let mut child = builder.reborrow().get_pointer_field(offset)
+                            .init_struct(message::get_node_struct_size(&inner)?);
+
+                        self.struct_(&inner, &mut child, discriminator, obj, &flat)?;
+                        continue;
+                    }
+
+                    let value = match value {
+                        Some(x) => x,
+                        None => continue,
+                    };
+
+                    dynamic::set_active(node, builder, &field)?;
+
+                    if dynamic::is_pointer(&type_)? {
+                        self.pointer(builder.reborrow().get_pointer_field(offset), &type_, value, annotations.data)?;
+                    } else {
+                        let value = self.primitive(&type_, value)?;
+                        dynamic::write_data_slot(&self.arena, builder, &x, value.as_ref(self.py))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn top(&self, node: &NodeRef, builder: &mut StructBuilder, value: &PyAny) -> Result<(), Error> {
+        let discriminator = Annotations::from_reader(&node.get_annotations()?)?.discriminator;
+
+        self.struct_(node, builder, discriminator, self.object(value)?, "")
+    }
+
+    /// The Python value `write_data_slot`/`write_element` expect.
+    fn primitive(&self, type_: &TypeReader, value: &PyAny) -> Result<PyObject, Error> {
+        use schema_capnp::type_ as T;
+
+        let py = self.py;
+        let text = value.downcast_ref::<PyString>().ok().map(|x| x.to_string()).transpose()?;
+        let text = text.as_ref().map(|x| &**x);
+
+        Ok(match (type_.which()?, text) {
+            (T::Int64(()), Some(x)) => x.parse::<i64>().map_err(|_| Error::Value(format!("invalid Int64: {}", x)))?.to_object(py),
+            (T::Uint64(()), Some(x)) => x.parse::<u64>().map_err(|_| Error::Value(format!("invalid UInt64: {}", x)))?.to_object(py),
+            (T::Float32(()), Some(x)) | (T::Float64(()), Some(x)) => match x {
+                "NaN" => std::f64::NAN,
+                "Infinity" => std::f64::INFINITY,
+                "-Infinity" => std::f64::NEG_INFINITY,
+                _ => return Err(Error::Value(format!("invalid float: {}", x))),
+            }.to_object(py),
+            (T::Enum(x), Some(name)) => {
+                match enumerant_names(&self.arena, x.get_type_id())?.iter().position(|x| x == name) {
+                    Some(i) => (i as u16).to_object(py),
+                    None => return Err(Error::Value(format!("unknown enumerant: {}", name))),
+                }
+            }
+            _ => value.to_object(py),
+        })
+    }
+
+    fn data(&self, value: &PyAny, encoding: Option<DataEncoding>) -> Result<Vec<u8>, Error> {
+        if let Ok(x) = value.downcast_ref::<PyString>() {
+            let x = x.to_string()?;
+
+            return match encoding {
+                Some(DataEncoding::Hex) => hex_decode(&x),
+                _ => base64_decode(&x),
+            };
+        }
+
+        Ok(value.extract::<Vec<u8>>()?)
+    }
+
+    fn pointer(
+        &self,
+        mut ptr: PointerBuilder,
+        type_: &TypeReader,
+        value: &PyAny,
+        encoding: Option<DataEncoding>,
+    ) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        match type_.which()? {
+            T::Text(()) => ptr.set_text(&value.extract::<String>()?),
+            T::Data(()) => ptr.set_data(&self.data(value, encoding)?),
+            T::Struct(x) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                let mut builder = ptr.init_struct(message::get_node_struct_size(&node)?);
+
+                self.top(&node, &mut builder, value)?;
+            }
+            T::List(x) => {
+                let element = x.get_element_type()?;
+                let items: Vec<&PyAny> = value.extract()?;
+                let mut list = dynamic::init_list(&self.arena, ptr, &element, items.len() as u32)?;
+
+                for (i, item) in items.into_iter().enumerate() {
+                    self.element(&mut list, i as u32, &element, item, encoding)?;
+                }
+            }
+            _ => return Err(Error::Type("only data, text, structs and lists can be decoded from JSON".into())),
+        }
+
+        Ok(())
+    }
+
+    fn element(
+        &self,
+        list: &mut ListBuilder,
+        idx: u32,
+        element: &TypeReader,
+        value: &PyAny,
+        encoding: Option<DataEncoding>,
+    ) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        match element.which()? {
+            T::Struct(x) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                self.top(&node, &mut list.reborrow().get_struct_element(idx), value)
+            }
+            T::Text(()) | T::Data(()) | T::List(_) | T::AnyPointer(_) | T::Interface(_) => {
+                self.pointer(list.reborrow().get_pointer_element(idx), element, value, encoding)
+            }
+            _ => {
+                let value = self.primitive(element, value)?;
+                dynamic::write_element(&self.arena, list, idx, element, value.as_ref(self.py))
+            }
+        }
+    }
+}
+
+pub fn encode(py: Python, reader: &StructReaderPy, data: DataEncoding) -> Result<PyObject, Error> {
+    Encoder { py, arena: reader.node.rc(), data }.top(&reader.node, &reader.reader)
+}
+
+pub fn decode(py: Python, node: &NodeRef, builder: &mut StructBuilder, value: &PyAny) -> Result<(), Error> {
+    Decoder { py, arena: node.rc() }.top(node, builder, value)
+}
+
+#[pyclass]
+pub struct ToJsonFun {}
+
+#[pymethods]
+impl ToJsonFun {
+    /// `data` picks how Data fields without `$Json.base64`/`$Json.hex` are
+    /// written: "array" of byte values (the JsonCodec default), "base64" or "hex".
+    #[call]
+    #[args(data = "\"array\"", indent = "None")]
+    fn to_json(&self, py: Python, reader: &StructReaderPy, data: &str, indent: Option<usize>) -> PyResult<String> {
+        let value = encode(py, reader, DataEncoding::from_str(data)?)?;
+
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("allow_nan", false)?;
+        kwargs.set_item("indent", indent)?;
+
+        py.import("json")?.call("dumps", (value,), Some(kwargs))?.extract()
+    }
+}
@@ -1,20 +1,21 @@
 use std::io::{Error as IoError, ErrorKind};
-use pyo3::{create_exception, exceptions, PyObjectProtocol};
+use pyo3::{create_exception, exceptions, PyObjectProtocol, PyDowncastError};
 use pyo3::prelude::*;
 use capnp::{serialize, Error as _CapnpError, NotInSchema, Word};
 use capnp::serialize::OwnedSegments;
 use std::process::Command;
-use pyo3::types::{PyTuple, PyList, PyString, PyAny};
+use pyo3::types::{PyTuple, PyList, PyString, PyAny, PyBytes};
 use std::path::{PathBuf, Path};
 use capnpc::schema_capnp;
 use std::collections::{HashMap, VecDeque};
 use std::rc::Rc;
+use std::cell::RefCell;
 use owning_ref::OwningHandle;
 use std::any::Any;
 use capnpc::codegen_types::RustTypeInfo;
 use capnp::private::layout;
 use pyo3::class::basic::PyObjectGetAttrProtocol;
-use capnp::message::HeapAllocator;
+use capnp::message::{HeapAllocator, AllocationStrategy};
 use std::ops::{Deref, DerefMut};
 use std::marker::PhantomData;
 use crate::arena::Arena;
@@ -22,8 +23,24 @@ use crate::arena::Arena;
 pub mod objs;
 pub mod message;
 pub mod arena;
+pub mod framing;
+pub mod dynamic;
+pub mod canonical;
+pub mod diff;
+pub mod text;
+pub mod json;
+pub mod msgpack;
+pub mod csv;
+pub mod buffer;
+pub mod columns;
+pub mod capability;
+pub mod protocol;
+pub mod transport;
+pub mod rpc;
+pub mod trace;
 
 create_exception!(wrapper, CapnpError, pyo3::exceptions::Exception);
+create_exception!(wrapper, RpcError, pyo3::exceptions::Exception);
 
 pub enum Error {
     Capnp(_CapnpError),
@@ -33,6 +50,8 @@ pub enum Error {
     Text(String),
     Type(String),
     Attribute(String),
+    Value(String),
+    Rpc(String),
 }
 
 impl From<_CapnpError> for Error {
@@ -59,6 +78,12 @@ impl From<PyErr> for Error {
     }
 }
 
+impl From<PyDowncastError> for Error {
+    fn from(x: PyDowncastError) -> Error {
+        Error::Py(x.into())
+    }
+}
+
 impl From<Error> for PyErr {
     fn from(err: Error) -> PyErr {
         match err {
@@ -77,7 +102,13 @@ impl From<Error> for PyErr {
             ),
             Error::Attribute(x) => PyErr::new::<exceptions::AttributeError, String>(
                 x
-            )
+            ),
+            Error::Value(x) => PyErr::new::<exceptions::ValueError, String>(
+                x
+            ),
+            Error::Rpc(x) => PyErr::new::<RpcError, String>(
+                x
+            ),
         }
     }
 }
@@ -158,7 +189,8 @@ impl CompilerCommand {
 
 
             let arena = NodeArena {
-                items: oref
+                items: oref,
+                methods: RefCell::new(HashMap::new()),
             };
 
             Ok(Definition {
@@ -181,6 +213,25 @@ pub struct NodeArena {
         Box<capnp::message::Reader<OwnedSegments>>,
         Box<ArenaItem<'static>>
     >,
+    // method tables by interface id, see `objs::method_table`
+    methods: RefCell<HashMap<u64, Rc<Vec<objs::InterfaceMethod>>>>,
+}
+
+impl NodeArena {
+    /// The cached method table of interface `id`, built by `build` the first
+    /// time it is asked for.
+    pub fn method_table<F>(&self, id: u64, build: F) -> Result<Rc<Vec<objs::InterfaceMethod>>, Error>
+        where F: FnOnce() -> Result<Vec<objs::InterfaceMethod>, Error>
+    {
+        if let Some(x) = self.methods.borrow().get(&id) {
+            return Ok(x.clone());
+        }
+
+        let table = Rc::new(build()?);
+        self.methods.borrow_mut().insert(id, table.clone());
+
+        Ok(table)
+    }
 }
 
 
@@ -392,6 +443,107 @@ impl NodePy {
         inner(&self.i).map_err(PyErr::from)
     }
 
+    #[args(first_segment_words = "None", allocation_strategy = "\"grow\"", scratch = "None")]
+    fn new_message(
+        &self,
+        first_segment_words: Option<u32>,
+        allocation_strategy: &str,
+        scratch: Option<&ScratchSpacePy>,
+    ) -> PyResult<Builder> {
+        let inner = |this: &NodeInner| -> Result<Builder, Error> {
+            message::get_node_struct_size(this.get_reader())?;
+
+            let mut options = message::BuilderOptions::new();
+
+            options.allocation_strategy = match allocation_strategy {
+                "fixed" => AllocationStrategy::FixedSize,
+                "grow" => AllocationStrategy::GrowHeuristically,
+                x => return Err(Error::Type(format!("unknown allocation strategy: {}", x))),
+            };
+
+            if let Some(x) = first_segment_words {
+                options.first_segment_words = x;
+            }
+
+            if let Some(x) = scratch {
+                options.scratch = Some(x.i.clone());
+            }
+
+            let mut builder = message::Builder::new(this.id, this.arena.clone(), &options);
+            builder.init_root();
+
+            Ok(Builder { i: Rc::new(RefCell::new(builder)) })
+        };
+
+        inner(&self.i).map_err(PyErr::from)
+    }
+
+    /// Builds a message from the capnp text format, `(field = value, ...)`.
+    fn from_text(&self, text: &str) -> PyResult<Builder> {
+        let inner = |this: &NodeInner| -> Result<Builder, Error> {
+            let node = dynamic::get_node(&this.arena, this.id)?;
+            let mut builder = message::Builder::new(this.id, this.arena.clone(), &message::BuilderOptions::new());
+
+            text::parse_struct(&node, &mut builder.init_root(), text)?;
+
+            Ok(Builder { i: Rc::new(RefCell::new(builder)) })
+        };
+
+        inner(&self.i).map_err(PyErr::from)
+    }
+
+    /// Builds a message from JSON in the conventions of capnp's `JsonCodec`.
+    fn from_json(&self, py: Python, text: &str) -> PyResult<Builder> {
+        let inner = |this: &NodeInner| -> Result<Builder, Error> {
+            let value = py.import("json")?.call1("loads", (text,))?;
+            let node = dynamic::get_node(&this.arena, this.id)?;
+            let mut builder = message::Builder::new(this.id, this.arena.clone(), &message::BuilderOptions::new());
+
+            json::decode(py, &node, &mut builder.init_root(), value)?;
+
+            Ok(Builder { i: Rc::new(RefCell::new(builder)) })
+        };
+
+        inner(&self.i).map_err(PyErr::from)
+    }
+
+    /// Builds a message from MessagePack written by `to_msgpack`, with
+    /// either kind of keys and enums.
+    fn from_msgpack(&self, data: &PyBytes) -> PyResult<Builder> {
+        let inner = |this: &NodeInner| -> Result<Builder, Error> {
+            let node = dynamic::get_node(&this.arena, this.id)?;
+            let mut builder = message::Builder::new(this.id, this.arena.clone(), &message::BuilderOptions::new());
+
+            msgpack::decode(&node, &mut builder.init_root(), data.as_bytes())?;
+
+            Ok(Builder { i: Rc::new(RefCell::new(builder)) })
+        };
+
+        inner(&self.i).map_err(PyErr::from)
+    }
+
+    /// Reads a message whose root is this struct, from `bytes` or a `FramePy`.
+    /// Capability pointers read as placeholders for the entries of
+    /// `cap_table`, as given by `Builder.cap_table`; without one, for their
+    /// index on the wire.
+    #[args(cap_table = "None")]
+    fn read(&self, data: &PyAny, cap_table: Option<Vec<Option<u32>>>) -> PyResult<dynamic::StructReaderPy> {
+        let inner = |this: &NodeInner| -> Result<dynamic::StructReaderPy, Error> {
+            let frame = match data.downcast_ref::<framing::FramePy>() {
+                Ok(x) => x.i.clone(),
+                Err(_) => framing::Frame::from_bytes(data.downcast_ref::<PyBytes>()?.as_bytes())?,
+            };
+
+            let caps = cap_table.map(|x| x.into_iter()
+                .map(|x| x.map(capability::Entry::Index).unwrap_or(capability::Entry::Null))
+                .collect());
+
+            dynamic::StructReaderPy::read_root(frame, dynamic::get_node(&this.arena, this.id)?, caps)
+        };
+
+        inner(&self.i).map_err(PyErr::from)
+    }
+
     fn children(&self) -> PyResult<Vec<String>> {
         let inner = |this: &NodeInner| -> Result<Vec<String>, Error> {
             let me = this.arena.items.nodes.get(&this.id).ok_or(Error::Text("could not find me".to_string()))?;
@@ -409,6 +561,19 @@ impl NodePy {
         inner(&self.i).map_err(PyErr::from)
     }
 
+    /// The complete method table of an interface: its own methods, then the
+    /// ones it inherits, each with the interface that declares it.
+    #[getter]
+    fn methods(&self) -> PyResult<Vec<rpc::MethodPy>> {
+        let inner = |this: &NodeInner| -> Result<Vec<rpc::MethodPy>, Error> {
+            let node = dynamic::get_node(&this.arena, this.id)?;
+
+            Ok(rpc::methods(&this.arena, &node)?.into_iter().map(|i| rpc::MethodPy { i }).collect())
+        };
+
+        inner(&self.i).map_err(PyErr::from)
+    }
+
     fn __str__(&self, _py: Python) -> PyResult<String> {
         self.__repr__(_py)
     }
@@ -608,37 +773,91 @@ impl Definition {
 
 
 #[pyclass]
-struct Builder {
-    struct_size: ::capnp::private::layout::StructSize,
-    type_id: u64,
+pub struct ScratchSpacePy {
+    i: message::ScratchSpace,
+}
+
+#[pymethods]
+impl ScratchSpacePy {
+    #[new]
+    #[args(words = "capnp::message::SUGGESTED_FIRST_SEGMENT_WORDS")]
+    fn __new__(obj: &PyRawObject, words: u32) -> PyResult<()> {
+        obj.init(ScratchSpacePy { i: message::ScratchSpace::new(words) });
+        Ok(())
+    }
+
+    #[getter]
+    fn words(&self) -> PyResult<u32> {
+        Ok(self.i.words())
+    }
+}
+
+#[pyclass]
+pub struct Builder {
+    i: dynamic::MessageRc,
 }
 
 #[pymethods]
 impl Builder {
-    //fn goofy(&mut self) -> PyResult<bool> {
-//        use capnp::private::arena::BuilderArena;
-//        use capnp::any_pointer;
-//
-//        //let root = self.i.init_root().unwrap();
-//
-//        if self.arena.len() == 0 {
-//            self.arena.allocate_segment(1).expect("allocate root pointer");
-//            self.arena.allocate(0, 1).expect("allocate root pointer");
-//        }
-//        let (seg_start, _seg_len) = self.arena.get_segment_mut(0);
-//        let location: *mut Word = seg_start;
-//        let Builder { ref mut arena, struct_size, type_id } = *self;
-//
-//        let pointer_builder = layout::PointerBuilder::get_root(arena, 0, location);
-//
-//        let root = any_pointer::Builder::new(pointer_builder);
-//
-//        let struct_inited = pointer_builder.init_struct(self.struct_size);
-//
-//        self.arena.get_segments_for_output();
-//
-//        return Ok(true);
-    //}
+    fn init_root(&self) -> PyResult<dynamic::StructBuilderPy> {
+        self.i.borrow_mut().init_root();
+        Ok(dynamic::StructBuilderPy::new_root(&self.i)?)
+    }
+
+    #[getter]
+    fn root(&self) -> PyResult<dynamic::StructBuilderPy> {
+        Ok(dynamic::StructBuilderPy::new_root(&self.i)?)
+    }
+
+    /// A struct of type `node` allocated in this message but not attached to it.
+    fn new_orphan(&self, node: &NodePy) -> PyResult<dynamic::OrphanPy> {
+        Ok(dynamic::OrphanPy::new_struct(&self.i, dynamic::node_ref(node)?)?)
+    }
+
+    #[getter]
+    fn segment_count(&self) -> PyResult<usize> {
+        Ok(self.i.borrow().segment_count())
+    }
+
+    #[getter]
+    fn wasted_bytes(&self) -> PyResult<u64> {
+        Ok(self.i.borrow().wasted_bytes()?)
+    }
+
+    /// Drops unreachable objects from the message. Builders, readers and
+    /// orphans taken from it point into the storage that goes away, so this
+    /// fails while any of them is alive; take new ones from `root` after.
+    fn compact(&self) -> PyResult<()> {
+        // anything but this object holding the Rc is a handle into the arena
+        if Rc::strong_count(&self.i) > 1 {
+            return Err(Error::Value("the message still has builders or readers into it".into()).into());
+        }
+
+        Ok(self.i.borrow_mut().compact()?)
+    }
+
+    /// The cap table that goes beside the bytes: the index of the
+    /// placeholder written at each position, None where a capability was
+    /// cleared. `compact` renumbers it in the order pointers are reached;
+    /// `read` takes it back with the bytes.
+    #[getter]
+    fn cap_table(&self) -> PyResult<Vec<Option<u32>>> {
+        let inner = |this: &Self| -> Result<Vec<Option<u32>>, Error> {
+            this.i.borrow().cap_table()?.into_iter()
+                .map(|x| match x {
+                    capability::Entry::Index(i) => Ok(Some(i)),
+                    capability::Entry::Null => Ok(None),
+                    capability::Entry::Cap(_) => Err(Error::Type("the message holds live capabilities".into())),
+                })
+                .collect()
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    fn to_bytes(&self, py: Python) -> PyResult<PyObject> {
+        Ok(PyBytes::new(py, &self.i.borrow().to_bytes()).into())
+    }
 }
 
 #[pyclass]
@@ -696,7 +915,46 @@ impl CompileFun {
 fn wrapper(_py: Python, m: &PyModule) -> PyResult<()> {
     //m.add_class::<CompileFun>()?;
     m.add("compile", PyRef::new(_py, CompileFun {})?)?;
+    m.add("is_canonical", PyRef::new(_py, canonical::IsCanonicalFun {})?)?;
+    m.add("diff", PyRef::new(_py, diff::DiffFun {})?)?;
+    m.add("to_json", PyRef::new(_py, json::ToJsonFun {})?)?;
+    m.add("to_msgpack", PyRef::new(_py, msgpack::ToMsgpackFun {})?)?;
+    m.add("to_csv", PyRef::new(_py, csv::ToCsvFun {})?)?;
+    m.add("from_csv", PyRef::new(_py, csv::FromCsvFun {})?)?;
+    m.add("decode_columns", PyRef::new(_py, columns::DecodeColumnsFun {})?)?;
+    m.add("connect", PyRef::new(_py, rpc::ConnectFun {})?)?;
+    m.add("connect_fds", PyRef::new(_py, rpc::ConnectFdsFun {})?)?;
+    m.add("listen", PyRef::new(_py, rpc::ListenFun {})?)?;
+    m.add("loopback_pair", PyRef::new(_py, rpc::LoopbackPairFun {})?)?;
+    m.add("mock_server", PyRef::new(_py, rpc::MockServerFun {})?)?;
+    m.add("RpcError", _py.get_type::<RpcError>())?;
     m.add_class::<Definition>()?;
     m.add_class::<NodePy>()?;
+    m.add_class::<framing::FrameDecoderPy>()?;
+    m.add_class::<framing::FramePy>()?;
+    m.add_class::<Builder>()?;
+    m.add_class::<ScratchSpacePy>()?;
+    m.add_class::<dynamic::StructBuilderPy>()?;
+    m.add_class::<dynamic::ListBuilderPy>()?;
+    m.add_class::<dynamic::OrphanPy>()?;
+    m.add_class::<dynamic::GrowableListPy>()?;
+    m.add_class::<dynamic::StructReaderPy>()?;
+    m.add_class::<dynamic::ListReaderPy>()?;
+    m.add_class::<dynamic::AnyPointerReaderPy>()?;
+    m.add_class::<diff::PathPy>()?;
+    m.add_class::<diff::DiffPy>()?;
+    m.add_class::<capability::CapabilityPlaceholderPy>()?;
+    m.add_class::<rpc::MethodPy>()?;
+    m.add_class::<rpc::ConnectionPy>()?;
+    m.add_class::<rpc::CapabilityPy>()?;
+    m.add_class::<rpc::RequestPy>()?;
+    m.add_class::<rpc::PromisePy>()?;
+    m.add_class::<rpc::StreamPy>()?;
+    m.add_class::<rpc::PipelinePy>()?;
+    m.add_class::<rpc::ServerPy>()?;
+    m.add_class::<rpc::MockServerPy>()?;
+    m.add_class::<rpc::ListenerPy>()?;
+    m.add_class::<rpc::LoopbackPy>()?;
+    m.add_class::<trace::RpcTracerPy>()?;
     Ok(())
 }
@@ -1,16 +1,18 @@
 use capnp::any_pointer::Builder as AnyPointerBuilder;
 use capnp::private::layout::{PointerBuilder, PointerReader, StructBuilder, StructSize, ListBuilder};
 use capnp::private::arena::{BuilderArena as _BuilderArena, BuilderArenaImpl};
-use capnp::message::HeapAllocator;
+use capnp::message::{HeapAllocator, Allocator, AllocationStrategy};
 use capnp::Word;
 
 pub use super::NodeArena as NodeArena;
 use std::rc::Rc;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use capnpc::schema_capnp;
 use owning_ref::OwningHandle;
 use crate::arena::{Arena, ArenaRef, ArenaRc};
 use crate::Error;
+use crate::capability::{CapTable, Entry};
 use capnpc::codegen_types::RustTypeInfo;
 
 #[derive(Clone)]
@@ -25,7 +27,10 @@ pub enum Path {
     Void,
 
     Which(usize, ArenaRef<NodeArena>),
+    // index of the group field in its parent's field list
     Group(u32),
+    // index of the slot field in its parent's field list
+    Field(u32),
     // list at which pointer is selected
     List(usize),
     Index(usize),
@@ -34,14 +39,139 @@ pub enum Path {
     Text(usize),
 }
 
+#[derive(Clone)]
 pub struct PathBuilder {
     root: ArenaRef<NodeArena>,
     path: Vec<Path>,
 }
 
-type BA = BuilderArenaImpl<HeapAllocator>;
+// what the path points at after some of its steps
+enum Cursor {
+    Struct(ArenaRef<NodeArena>),
+    List(schema_capnp::type_::Reader<'static>),
+    Value,
+}
+
+impl Cursor {
+    fn of_type(arena: &Rc<NodeArena>, type_: &schema_capnp::type_::Reader<'static>) -> Result<Cursor, Error> {
+        Ok(match type_.which()? {
+            schema_capnp::type_::Struct(x) => Cursor::Struct(crate::dynamic::get_node(arena, x.get_type_id())?),
+            schema_capnp::type_::List(x) => Cursor::List(x.get_element_type()?),
+            _ => Cursor::Value,
+        })
+    }
+}
+
+type BA = BuilderArenaImpl<BuilderAllocator>;
 
-fn get_node_struct_size(node: &schema_capnp::node::Reader) -> Result<StructSize, Error> {
+/// A pool of zeroed first segments shared between builders.
+///
+/// Every builder created with the same `ScratchSpace` takes a buffer out of
+/// the pool and gives it back (re-zeroed) when it is dropped, so a loop that
+/// builds one small message at a time never hits the heap allocator.
+#[derive(Clone)]
+pub struct ScratchSpace {
+    words: u32,
+    free: Rc<RefCell<Vec<Vec<Word>>>>,
+}
+
+impl ScratchSpace {
+    pub fn new(words: u32) -> Self {
+        ScratchSpace { words, free: Rc::new(RefCell::new(Vec::new())) }
+    }
+
+    pub fn words(&self) -> u32 {
+        self.words
+    }
+
+    fn take(&self) -> Vec<Word> {
+        self.free.borrow_mut().pop().unwrap_or_else(|| Word::allocate_zeroed_vec(self.words as usize))
+    }
+
+    fn give_back(&self, buf: Vec<Word>) {
+        self.free.borrow_mut().push(buf);
+    }
+}
+
+#[derive(Clone)]
+pub struct BuilderOptions {
+    pub first_segment_words: u32,
+    pub allocation_strategy: AllocationStrategy,
+    pub scratch: Option<ScratchSpace>,
+}
+
+impl BuilderOptions {
+    pub fn new() -> Self {
+        BuilderOptions {
+            first_segment_words: capnp::message::SUGGESTED_FIRST_SEGMENT_WORDS,
+            allocation_strategy: capnp::message::SUGGESTED_ALLOCATION_STRATEGY,
+            scratch: None,
+        }
+    }
+
+    pub fn allocator(&self) -> BuilderAllocator {
+        let heap = HeapAllocator::new()
+            .first_segment_words(self.first_segment_words)
+            .allocation_strategy(self.allocation_strategy);
+
+        match self.scratch {
+            Some(ref x) => BuilderAllocator::Scratch {
+                space: x.clone(),
+                buf: Some(x.take()),
+                used: false,
+                fallback: heap,
+            },
+            None => BuilderAllocator::Heap(heap),
+        }
+    }
+}
+
+pub enum BuilderAllocator {
+    Heap(HeapAllocator),
+    // the first segment comes from `buf`, anything past it from `fallback`
+    Scratch {
+        space: ScratchSpace,
+        buf: Option<Vec<Word>>,
+        used: bool,
+        fallback: HeapAllocator,
+    },
+}
+
+unsafe impl Allocator for BuilderAllocator {
+    fn allocate_segment(&mut self, minimum_size: u32) -> (*mut Word, u32) {
+        match self {
+            BuilderAllocator::Heap(x) => x.allocate_segment(minimum_size),
+            BuilderAllocator::Scratch { buf, used, fallback, .. } => {
+                if let (false, Some(x)) = (*used, buf.as_mut()) {
+                    if x.len() >= minimum_size as usize {
+                        *used = true;
+                        return (x.as_mut_ptr(), x.len() as u32);
+                    }
+                }
+
+                fallback.allocate_segment(minimum_size)
+            }
+        }
+    }
+
+    fn pre_drop(&mut self, segment0_currently_allocated: u32) {
+        match self {
+            BuilderAllocator::Heap(x) => x.pre_drop(segment0_currently_allocated),
+            BuilderAllocator::Scratch { space, buf, used, .. } => {
+                if let Some(mut x) = buf.take() {
+                    if *used {
+                        for b in Word::words_to_bytes_mut(&mut x[..segment0_currently_allocated as usize]) {
+                            *b = 0;
+                        }
+                    }
+                    space.give_back(x);
+                }
+            }
+        }
+    }
+}
+
+pub fn get_node_struct_size(node: &schema_capnp::node::Reader) -> Result<StructSize, Error> {
     match node.which()? {
         schema_capnp::node::Which::Struct(x) => {
             let x: &schema_capnp::node::struct_::Reader = &x;
@@ -56,6 +186,85 @@ fn get_node_struct_size(node: &schema_capnp::node::Reader) -> Result<StructSize,
 }
 
 impl PathBuilder {
+    pub fn new(root: ArenaRef<NodeArena>) -> Self {
+        PathBuilder { root, path: Vec::new() }
+    }
+
+    pub fn steps(&self) -> &[Path] {
+        &self.path
+    }
+
+    /// Field names from the root, `a.b[3].c`; a trailing `Which` step reads
+    /// as `which`, other `Which` steps only pick the union arm of the next one.
+    pub fn render(&self) -> Result<String, Error> {
+        let arena = self.root.rc();
+        let mut r = String::new();
+        let mut cursor = Cursor::Struct(self.root.clone());
+        let mut arm = schema_capnp::field::NO_DISCRIMINANT;
+
+        for (i, step) in self.path.iter().enumerate() {
+            match step {
+                Path::Index(x) => {
+                    r.push_str(&format!("[{}]", x));
+
+                    cursor = match cursor {
+                        Cursor::List(ref x) => Cursor::of_type(&arena, x)?,
+                        _ => return Err(Error::Type("not a list".into())),
+                    };
+                    continue;
+                }
+                Path::Which(x, _) => {
+                    if i + 1 == self.path.len() {
+                        push_name(&mut r, "which");
+                    }
+                    arm = *x as u16;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let node = match cursor {
+                Cursor::Struct(ref x) => x.clone(),
+                _ => return Err(Error::Type("not a struct".into())),
+            };
+
+            let mut found = None;
+
+            for (idx, field) in crate::dynamic::struct_node(&node)?.get_fields()?.iter().enumerate() {
+                if field.get_discriminant_value() != arm {
+                    continue;
+                }
+
+                let matches = match (step, field.which()?) {
+                    (Path::Group(x), schema_capnp::field::Group(_)) |
+                    (Path::Field(x), schema_capnp::field::Slot(_)) => *x as usize == idx,
+                    _ => false,
+                };
+
+                if matches {
+                    found = Some(field);
+                    break;
+                }
+            }
+
+            let field = found.ok_or(Error::Type("path does not match the schema".into()))?;
+
+            push_name(&mut r, field.get_name()?);
+            arm = schema_capnp::field::NO_DISCRIMINANT;
+
+            cursor = match field.which()? {
+                schema_capnp::field::Group(x) => Cursor::Struct(crate::dynamic::get_node(&arena, x.get_type_id())?),
+                schema_capnp::field::Slot(x) => Cursor::of_type(&arena, &x.get_type()?)?,
+            };
+        }
+
+        if r.is_empty() {
+            r.push_str("<root>");
+        }
+
+        Ok(r)
+    }
+
     pub fn with_append(&self, path: Path) -> Self {
         let mut new_path = self.path.clone();
         new_path.push(path);
@@ -231,46 +440,193 @@ impl PathBuilder {
     }
 }
 
-pub enum Type<'a> {
-    Struct(StructBuilder<'a>),
-    List(ListBuilder<'a>),
+fn push_name(r: &mut String, name: &str) {
+    if !r.is_empty() {
+        r.push('.');
+    }
+    r.push_str(name);
+}
+
+fn word_at(segments: &[&[Word]], segment: u32, pos: u64) -> u64 {
+    let start = pos as usize * 8;
+
+    segments.get(segment as usize)
+        .and_then(|x| Word::words_to_bytes(x).get(start..start + 8))
+        .map(|x| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(x);
+            u64::from_le_bytes(bytes)
+        })
+        .unwrap_or(0)
+}
+
+/// Landing pad words of the far pointers in the tree behind the pointer at
+/// word `pos` of `segment`: one for a single far pointer, two for a double.
+fn landing_pads(segments: &[&[Word]], segment: u32, pos: u64) -> u64 {
+    let word = word_at(segments, segment, pos);
+
+    match word & 3 {
+        // struct or list in the same segment, the offset is signed
+        0 | 1 if word != 0 => {
+            let start = (pos as i64 + 1 + ((word as u32 as i32) >> 2) as i64) as u64;
+            object_pads(segments, segment, start, word)
+        }
+        2 => {
+            let pad_segment = (word >> 32) as u32;
+            let pad = (word >> 3) as u64 & 0x1fff_ffff;
+
+            if word & 4 == 0 {
+                return 1 + landing_pads(segments, pad_segment, pad);
+            }
+
+            // a far pointer to the content, then the tag describing it
+            let far = word_at(segments, pad_segment, pad);
+            let tag = word_at(segments, pad_segment, pad + 1);
+
+            2 + object_pads(segments, (far >> 32) as u32, (far >> 3) & 0x1fff_ffff, tag)
+        }
+        _ => 0,
+    }
+}
+
+/// Landing pads behind the pointers of the object at word `start` of
+/// `segment`, laid out as the struct or list pointer `word` says.
+fn object_pads(segments: &[&[Word]], segment: u32, start: u64, word: u64) -> u64 {
+    let pointers = |start: u64, count: u64| (0..count).map(|i| landing_pads(segments, segment, start + i)).sum::<u64>();
+
+    if word & 3 == 0 {
+        let data = (word >> 32) & 0xffff;
+        return pointers(start + data, word >> 48);
+    }
+
+    match (word >> 32) & 7 {
+        // pointer list
+        6 => pointers(start, word >> 35),
+        // inline composite, the tag counts the elements
+        7 => {
+            let tag = word_at(segments, segment, start);
+            let (data, ptrs) = ((tag >> 32) & 0xffff, tag >> 48);
+
+            (0..(tag >> 2) & 0x3fff_ffff)
+                .map(|i| pointers(start + 1 + i * (data + ptrs) + data, ptrs))
+                .sum()
+        }
+        _ => 0,
+    }
+}
+
+/// A detached pointer slot allocated in the message. Whatever it points to
+/// is not reachable from the root until it is adopted into a real field.
+#[derive(Clone, Copy)]
+pub struct Cell {
+    segment_id: u32,
+    offset: u32,
 }
 
-pub struct BuilderArena<'a> {
+pub struct BuilderArena {
     //        items: OwningHandle<
 //            Box<BuilderArenaImpl<HeapAllocator>>,
 //            Box<HashMap<u64, Type<'static>>>
 //        >,
-    arena: BuilderArenaImpl<HeapAllocator>,
-    items: Box<HashMap<u64, Type<'a>>>,
+    arena: BA,
+    items: Box<HashMap<u64, Cell>>,
     next_idx: u64,
+    // capabilities written into the message, see `capability`
+    caps: Box<CapTable>,
 }
 
-impl BuilderArena<'_> {
-    pub fn new() -> Self {
-        let arena = BuilderArenaImpl::new(HeapAllocator::new());
-//            let items = OwningHandle::new_with_fn(
-//                Box::new(arena),
-//                unsafe {
-//                    |arena| {
-//                        Box::new(HashMap::new())
-//                    }
-//                },
-//            );
-        //let arena = Box::new(arena);
+impl BuilderArena {
+    pub fn new(options: &BuilderOptions) -> Self {
+        let arena = BuilderArenaImpl::new(options.allocator());
+
+        // the root pointer always takes the first word of the first segment
+        arena.allocate_segment(1).expect("allocate root pointer");
+        arena.allocate(0, 1).expect("allocate root pointer");
+
         let items = Box::new(HashMap::new());
 
-//            BuilderArena { items, next_idx: 0 }
-        BuilderArena { arena, items, next_idx: 0 }
+        BuilderArena { arena, items, next_idx: 0, caps: CapTable::new(vec![]) }
     }
 
-//        pub fn arena(&self) -> &mut BuilderArenaImpl<HeapAllocator> {
-//            self.items.as_owner()
-//        }
+    fn pointer_at(&self, cell: Cell) -> PointerBuilder {
+        let (seg_start, _seg_len) = self.arena.get_segment_mut(cell.segment_id);
+        let location: *mut Word = unsafe { seg_start.offset(cell.offset as isize) };
+
+        let mut r = PointerBuilder::get_root(&self.arena, cell.segment_id, location);
+        self.caps.imbue_builder(&mut r);
+        r
+    }
+
+    pub fn root_pointer(&self) -> PointerBuilder {
+        self.pointer_at(Cell { segment_id: 0, offset: 0 })
+    }
+
+    pub fn new_cell(&mut self) -> u64 {
+        let (segment_id, offset) = self.arena.allocate_anywhere(1);
+
+        let idx = self.next_idx;
+        self.next_idx += 1;
+
+        self.items.insert(idx, Cell { segment_id, offset });
+
+        idx
+    }
+
+    pub fn cell(&self, idx: u64) -> Result<PointerBuilder, Error> {
+        let cell = self.get(&idx).ok_or(Error::Text("orphan has already been adopted".into()))?;
+
+        Ok(self.pointer_at(*cell))
+    }
+
+    pub fn release(&mut self, idx: u64) {
+        self.items.remove(&idx);
+    }
+
+    pub fn segments(&self) -> capnp::OutputSegments {
+        self.arena.get_segments_for_output()
+    }
+
+    pub fn allocated_words(&self) -> u64 {
+        self.arena.get_segments_for_output().iter().map(|x| x.len() as u64).sum()
+    }
+
+    /// Words reachable from the root pointer and the live orphans, counting
+    /// the pointer words themselves and the landing pads of far pointers,
+    /// which `total_size` leaves out.
+    pub fn reachable_words(&self) -> Result<u64, Error> {
+        let segments = self.segments();
+        let root = Cell { segment_id: 0, offset: 0 };
+
+        let mut r = 0;
+
+        for cell in std::iter::once(&root).chain(self.items.values()) {
+            r += 1 + self.pointer_at(*cell).as_reader().total_size()?.word_count;
+            r += landing_pads(&segments, cell.segment_id, cell.offset as u64);
+        }
+
+        Ok(r)
+    }
+
+    /// Deep-copies the root and every live orphan of `other`, keeping orphan indices.
+    fn copy_from(&mut self, other: &BuilderArena) -> Result<(), Error> {
+        self.root_pointer().copy_from(other.root_pointer().as_reader(), false)?;
+
+        for (idx, cell) in other.items.iter() {
+            let (segment_id, offset) = self.arena.allocate_anywhere(1);
+            let new = Cell { segment_id, offset };
+
+            self.pointer_at(new).copy_from(other.pointer_at(*cell).as_reader(), false)?;
+            self.items.insert(*idx, new);
+        }
+
+        self.next_idx = other.next_idx;
+
+        Ok(())
+    }
 }
 
-impl<'a> Arena for BuilderArena<'a> {
-    type Item = Type<'a>;
+impl Arena for BuilderArena {
+    type Item = Cell;
 
     fn get(&self, idx: &u64) -> Option<&Self::Item> {
         self.items.get(idx)
@@ -278,9 +634,9 @@ impl<'a> Arena for BuilderArena<'a> {
 }
 
 
-pub struct Building<'a> {
+pub struct Building {
     node: ArenaRef<NodeArena>,
-    builder: ArenaRef<BuilderArena<'a>>,
+    builder: ArenaRef<BuilderArena>,
 }
 
 //    impl Building<'_> {
@@ -338,22 +694,132 @@ pub struct Building<'a> {
 //    }
 
 pub struct Builder {
-    arena: BuilderArenaImpl<HeapAllocator>,
+    arena: BuilderArena,
+    options: BuilderOptions,
     node_arena: Rc<NodeArena>,
     node_id: u64,
     initialized: bool,
 }
 
 impl Builder {
-    pub fn new(id: u64, arena: Rc<NodeArena>) -> Self {
+    pub fn new(id: u64, arena: Rc<NodeArena>, options: &BuilderOptions) -> Self {
         Builder {
-            arena: BuilderArenaImpl::new(HeapAllocator::new()),
+            arena: BuilderArena::new(options),
+            options: options.clone(),
             node_arena: arena.clone(),
             node_id: id,
             initialized: false,
         }
     }
 
+    /// Bytes taken by objects that are no longer reachable: values replaced
+    /// by a setter, disowned-and-dropped orphans, lists that were regrown.
+    pub fn wasted_bytes(&self) -> Result<u64, Error> {
+        Ok(self.arena.allocated_words().saturating_sub(self.reachable_words()?) * 8)
+    }
+
+    fn reachable_words(&self) -> Result<u64, Error> {
+        if !self.initialized {
+            return Ok(self.arena.allocated_words());
+        }
+
+        self.arena.reachable_words()
+    }
+
+    /// Rewrites the message into a fresh arena that holds only reachable
+    /// objects. Raw builders into the old arena dangle afterwards, the caller
+    /// makes sure there are none.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        let mut arena = BuilderArena::new(&self.options);
+        arena.copy_from(&self.arena)?;
+
+        self.arena = arena;
+
+        Ok(())
+    }
+
+    fn root_pointer(&self) -> PointerBuilder {
+        self.arena.root_pointer()
+    }
+
+    pub fn node_arena(&self) -> &Rc<NodeArena> {
+        &self.node_arena
+    }
+
+    pub fn node_id(&self) -> u64 {
+        self.node_id
+    }
+
+    pub fn init_root(&mut self) -> StructBuilder {
+        self.initialized = true;
+        let ss = self.struct_size();
+        self.root_pointer().init_struct(ss)
+    }
+
+    pub fn get_root(&mut self) -> Result<StructBuilder, Error> {
+        if !self.initialized {
+            return Ok(self.init_root());
+        }
+        let ss = self.struct_size();
+        Ok(self.root_pointer().get_struct(ss, None)?)
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.arena.arena.len()
+    }
+
+    /// What the capability pointers in the message refer to, by position.
+    pub fn cap_table(&self) -> Result<Vec<Entry>, Error> {
+        self.arena.caps.entries()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        crate::framing::write_segments(&*self.arena.arena.get_segments_for_output())
+    }
+
+    /// Allocates a struct of `node` that is not attached anywhere yet.
+    pub fn new_orphan(&mut self, node: &ArenaRef<NodeArena>) -> Result<u64, Error> {
+        let size = get_node_struct_size(node)?;
+        let idx = self.arena.new_cell();
+
+        self.arena.cell(idx)?.init_struct(size);
+
+        Ok(idx)
+    }
+
+    /// An empty orphan; the caller initializes it through `orphan(idx)`.
+    pub fn new_orphan_cell(&mut self) -> u64 {
+        self.arena.new_cell()
+    }
+
+    /// Moves whatever `ptr` points to into a fresh orphan, leaving `ptr` null.
+    pub fn disown(&mut self, ptr: &mut PointerBuilder) -> Result<u64, Error> {
+        let idx = self.arena.new_cell();
+        let orphan = ptr.disown();
+
+        self.arena.cell(idx)?.adopt(orphan);
+
+        Ok(idx)
+    }
+
+    /// Attaches orphan `idx` at `ptr`; whatever `ptr` pointed to before is abandoned.
+    pub fn adopt(&mut self, idx: u64, ptr: &mut PointerBuilder) -> Result<(), Error> {
+        let orphan = self.arena.cell(idx)?.disown();
+        ptr.adopt(orphan);
+
+        self.arena.release(idx);
+
+        Ok(())
+    }
+
+    pub fn orphan(&self, idx: u64) -> Result<PointerBuilder, Error> {
+        self.arena.cell(idx)
+    }
+
+    pub fn release_orphan(&mut self, idx: u64) {
+        self.arena.release(idx);
+    }
+
     fn me<'a, 'b>(&'a self) -> schema_capnp::node::struct_::Reader<'b> {
         let node = self.node_arena.items.nodes.get(&self.node_id).expect("this must exist");
 
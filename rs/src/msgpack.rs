@@ -0,0 +1,646 @@
+//! Schema-driven MessagePack conversion.
+
+use std::rc::Rc;
+
+use capnp::private::layout::{ListBuilder, ListReader, PointerBuilder, PointerReader, StructBuilder, StructReader};
+use capnpc::schema_capnp;
+use pyo3::prelude::*;
+use pyo3::types::{PyAny, PyBytes};
+
+use crate::{Error, NodeArena};
+use crate::dynamic::{self, FieldReader, NodeRef, TypeReader};
+use crate::message;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Keys {
+    Name,
+    Index,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Enums {
+    Int,
+    Name,
+}
+
+#[derive(Clone, Copy)]
+pub struct Options {
+    pub keys: Keys,
+    pub enums: Enums,
+}
+
+impl Options {
+    pub fn new(keys: &str, enums: &str) -> Result<Options, Error> {
+        Ok(Options {
+            keys: match keys {
+                "name" => Keys::Name,
+                "index" => Keys::Index,
+                x => return Err(Error::Type(format!("unknown key mode: {}", x))),
+            },
+            enums: match enums {
+                "int" => Enums::Int,
+                "name" => Enums::Name,
+                x => return Err(Error::Type(format!("unknown enum mode: {}", x))),
+            },
+        })
+    }
+}
+
+/// A decoded MessagePack object.
+enum Value {
+    Nil,
+    Bool(bool),
+    // every integer family, wide enough for uint64 and int64
+    Int(i128),
+    Float(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "str",
+            Value::Bin(_) => "bin",
+            Value::Array(_) => "array",
+            Value::Map(_) => "map",
+        }
+    }
+}
+
+fn mismatch<T>(expected: &str, value: &Value) -> Result<T, Error> {
+    Err(Error::Value(format!("expected {}, got {}", expected, value.kind())))
+}
+
+struct Output {
+    buf: Vec<u8>,
+}
+
+impl Output {
+    fn nil(&mut self) {
+        self.buf.push(0xc0);
+    }
+
+    fn bool(&mut self, x: bool) {
+        self.buf.push(if x { 0xc3 } else { 0xc2 });
+    }
+
+    fn uint(&mut self, x: u64) {
+        if x < 0x80 {
+            self.buf.push(x as u8);
+        } else if x <= 0xff {
+            self.buf.push(0xcc);
+            self.buf.push(x as u8);
+        } else if x <= 0xffff {
+            self.buf.push(0xcd);
+            self.buf.extend_from_slice(&(x as u16).to_be_bytes());
+        } else if x <= 0xffff_ffff {
+            self.buf.push(0xce);
+            self.buf.extend_from_slice(&(x as u32).to_be_bytes());
+        } else {
+            self.buf.push(0xcf);
+            self.buf.extend_from_slice(&x.to_be_bytes());
+        }
+    }
+
+    fn int(&mut self, x: i64) {
+        if x >= 0 {
+            self.uint(x as u64);
+        } else if x >= -32 {
+            self.buf.push(x as u8);
+        } else if x >= i8::min_value() as i64 {
+            self.buf.push(0xd0);
+            self.buf.push(x as u8);
+        } else if x >= i16::min_value() as i64 {
+            self.buf.push(0xd1);
+            self.buf.extend_from_slice(&(x as i16).to_be_bytes());
+        } else if x >= i32::min_value() as i64 {
+            self.buf.push(0xd2);
+            self.buf.extend_from_slice(&(x as i32).to_be_bytes());
+        } else {
+            self.buf.push(0xd3);
+            self.buf.extend_from_slice(&x.to_be_bytes());
+        }
+    }
+
+    fn f32(&mut self, x: f32) {
+        self.buf.push(0xca);
+        self.buf.extend_from_slice(&x.to_bits().to_be_bytes());
+    }
+
+    fn f64(&mut self, x: f64) {
+        self.buf.push(0xcb);
+        self.buf.extend_from_slice(&x.to_bits().to_be_bytes());
+    }
+
+    // fix, 8, 16 and 32 bit length headers; `fix` is None where there is no fix form
+    fn header(&mut self, len: usize, fix: Option<(u8, usize)>, tags: [u8; 3]) {
+        match fix {
+            Some((tag, limit)) if len < limit => self.buf.push(tag | len as u8),
+            _ => if len <= 0xff && tags[0] != 0 {
+                self.buf.push(tags[0]);
+                self.buf.push(len as u8);
+            } else if len <= 0xffff {
+                self.buf.push(tags[1]);
+                self.buf.extend_from_slice(&(len as u16).to_be_bytes());
+            } else {
+                self.buf.push(tags[2]);
+                self.buf.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+        }
+    }
+
+    fn str(&mut self, x: &str) {
+        self.header(x.len(), Some((0xa0, 32)), [0xd9, 0xda, 0xdb]);
+        self.buf.extend_from_slice(x.as_bytes());
+    }
+
+    fn bin(&mut self, x: &[u8]) {
+        self.header(x.len(), None, [0xc4, 0xc5, 0xc6]);
+        self.buf.extend_from_slice(x);
+    }
+
+    fn array(&mut self, len: usize) {
+        self.header(len, Some((0x90, 16)), [0, 0xdc, 0xdd]);
+    }
+
+    fn map(&mut self, len: usize) {
+        self.header(len, Some((0x80, 16)), [0, 0xde, 0xdf]);
+    }
+}
+
+struct Encoder {
+    arena: Rc<NodeArena>,
+    options: Options,
+    out: Output,
+}
+
+impl Encoder {
+    /// Fields that are written: primitives always, pointers when set, and
+    /// only the active member of a union.
+    fn present(&self, node: &NodeRef, reader: &StructReader) -> Result<Vec<(usize, FieldReader)>, Error> {
+        let active = dynamic::which_field(node, reader)?.map(|x| x.get_discriminant_value());
+        let mut r = Vec::new();
+
+        for (idx, field) in dynamic::struct_node(node)?.get_fields()?.iter().enumerate() {
+            let discriminant = field.get_discriminant_value();
+
+            if discriminant != schema_capnp::field::NO_DISCRIMINANT && Some(discriminant) != active {
+                continue;
+            }
+
+            if let schema_capnp::field::Slot(x) = field.which()? {
+                if dynamic::is_pointer(&x.get_type()?)? && reader.get_pointer_field(x.get_offset() as usize).is_null() {
+                    continue;
+                }
+            }
+
+            r.push((idx, field));
+        }
+
+        Ok(r)
+    }
+
+    fn struct_(&mut self, node: &NodeRef, reader: &StructReader) -> Result<(), Error> {
+        let fields = self.present(node, reader)?;
+
+        self.out.map(fields.len());
+
+        for (idx, field) in fields {
+            match self.options.keys {
+                Keys::Name => self.out.str(field.get_name()?),
+                Keys::Index => self.out.uint(idx as u64),
+            }
+
+            match field.which()? {
+                schema_capnp::field::Group(x) => {
+                    let group = dynamic::get_node(&self.arena, x.get_type_id())?;
+                    self.struct_(&group, reader)?;
+                }
+                schema_capnp::field::Slot(x) => {
+                    let type_ = x.get_type()?;
+                    let offset = x.get_offset() as usize;
+
+                    if dynamic::is_pointer(&type_)? {
+                        self.pointer(&reader.get_pointer_field(offset), &type_)?;
+                    } else {
+                        let mask = dynamic::default_bits(x.get_default_value()?)?;
+                        let bits = match dynamic::element_size(&type_)? {
+                            capnp::private::layout::ElementSize::Void => 0,
+                            capnp::private::layout::ElementSize::Bit => reader.get_bool_field(offset) as u64,
+                            capnp::private::layout::ElementSize::Byte => reader.get_data_field::<u8>(offset) as u64,
+                            capnp::private::layout::ElementSize::TwoBytes => reader.get_data_field::<u16>(offset) as u64,
+                            capnp::private::layout::ElementSize::FourBytes => reader.get_data_field::<u32>(offset) as u64,
+                            _ => reader.get_data_field::<u64>(offset),
+                        };
+
+                        self.primitive(&type_, bits ^ mask)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn primitive(&mut self, type_: &TypeReader, bits: u64) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        match type_.which()? {
+            T::Void(()) => self.out.nil(),
+            T::Bool(()) => self.out.bool(bits != 0),
+            T::Int8(()) => self.out.int(bits as u8 as i8 as i64),
+            T::Int16(()) => self.out.int(bits as u16 as i16 as i64),
+            T::Int32(()) => self.out.int(bits as u32 as i32 as i64),
+            T::Int64(()) => self.out.int(bits as i64),
+            T::Uint8(()) | T::Uint16(()) | T::Uint32(()) | T::Uint64(()) => self.out.uint(bits),
+            T::Float32(()) => self.out.f32(f32::from_bits(bits as u32)),
+            T::Float64(()) => self.out.f64(f64::from_bits(bits)),
+            T::Enum(x) => {
+                let name = match self.options.enums {
+                    Enums::Name => dynamic::enumerant_name(&self.arena, x.get_type_id(), bits as u16)?,
+                    Enums::Int => None,
+                };
+
+                match name {
+                    Some(x) => self.out.str(&x),
+                    None => self.out.uint(bits),
+                }
+            }
+            _ => return Err(Error::Type("not a primitive type".into())),
+        }
+
+        Ok(())
+    }
+
+    fn pointer(&mut self, ptr: &PointerReader, type_: &TypeReader) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        match type_.which()? {
+            T::Text(()) => self.out.str(ptr.get_text(None)?),
+            T::Data(()) => self.out.bin(ptr.get_data(None)?),
+            T::Struct(x) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                self.struct_(&node, &ptr.get_struct(None)?)?;
+            }
+            T::List(x) => {
+                let element = x.get_element_type()?;
+                self.list(&ptr.get_list(dynamic::element_size(&element)?, None)?, &element)?;
+            }
+            _ => return Err(Error::Type("AnyPointer and capabilities have no MessagePack form".into())),
+        }
+
+        Ok(())
+    }
+
+    fn list(&mut self, list: &ListReader, element: &TypeReader) -> Result<(), Error> {
+        use capnp::private::layout::PrimitiveElement;
+        use schema_capnp::type_ as T;
+
+        self.out.array(list.len() as usize);
+
+        for i in 0..list.len() {
+            match element.which()? {
+                T::Struct(x) => {
+                    let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                    self.struct_(&node, &list.get_struct_element(i))?;
+                }
+                T::Text(()) | T::Data(()) | T::List(_) | T::AnyPointer(_) | T::Interface(_) => {
+                    let ptr = list.get_pointer_element(i);
+
+                    // null elements cannot be left out of an array
+                    if ptr.is_null() {
+                        self.out.nil();
+                    } else {
+                        self.pointer(&ptr, element)?;
+                    }
+                }
+                _ => {
+                    let bits = match dynamic::element_size(element)? {
+                        capnp::private::layout::ElementSize::Void => 0,
+                        capnp::private::layout::ElementSize::Bit => <bool as PrimitiveElement>::get(list, i) as u64,
+                        capnp::private::layout::ElementSize::Byte => <u8 as PrimitiveElement>::get(list, i) as u64,
+                        capnp::private::layout::ElementSize::TwoBytes => <u16 as PrimitiveElement>::get(list, i) as u64,
+                        capnp::private::layout::ElementSize::FourBytes => <u32 as PrimitiveElement>::get(list, i) as u64,
+                        _ => <u64 as PrimitiveElement>::get(list, i),
+                    };
+
+                    self.primitive(element, bits)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// as deep as capnp's default nesting limit lets a message go
+const MAX_DEPTH: usize = 64;
+
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+    // arrays and maps we are inside of
+    depth: usize,
+}
+
+impl<'a> Input<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.pos + n > self.data.len() {
+            return Err(Error::Value(format!("truncated MessagePack at byte {}", self.pos)));
+        }
+
+        let r = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+
+        Ok(r)
+    }
+
+    fn be(&mut self, n: usize) -> Result<u64, Error> {
+        Ok(self.take(n)?.iter().fold(0u64, |acc, &x| acc << 8 | x as u64))
+    }
+
+    fn string(&mut self, len: usize) -> Result<Value, Error> {
+        let pos = self.pos;
+
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(x) => Ok(Value::Str(x.to_string())),
+            Err(_) => Err(Error::Value(format!("invalid UTF-8 in str at byte {}", pos))),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), Error> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::Value(format!("MessagePack nested deeper than {} at byte {}", MAX_DEPTH, self.pos)));
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn items(&mut self, len: usize) -> Result<Value, Error> {
+        self.enter()?;
+        let mut r = Vec::with_capacity(len.min(1024));
+
+        for _ in 0..len {
+            r.push(self.value()?);
+        }
+
+        self.depth -= 1;
+        Ok(Value::Array(r))
+    }
+
+    fn pairs(&mut self, len: usize) -> Result<Value, Error> {
+        self.enter()?;
+        let mut r = Vec::with_capacity(len.min(1024));
+
+        for _ in 0..len {
+            let k = self.value()?;
+            r.push((k, self.value()?));
+        }
+
+        self.depth -= 1;
+        Ok(Value::Map(r))
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        let pos = self.pos;
+        let tag = self.take(1)?[0];
+
+        Ok(match tag {
+            0x00..=0x7f => Value::Int(tag as i128),
+            0x80..=0x8f => self.pairs((tag & 0x0f) as usize)?,
+            0x90..=0x9f => self.items((tag & 0x0f) as usize)?,
+            0xa0..=0xbf => self.string((tag & 0x1f) as usize)?,
+            0xc0 => Value::Nil,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4 | 0xc5 | 0xc6 => {
+                let len = self.be(1 << (tag - 0xc4))? as usize;
+                Value::Bin(self.take(len)?.to_vec())
+            }
+            0xca => Value::Float(f32::from_bits(self.be(4)? as u32) as f64),
+            0xcb => Value::Float(f64::from_bits(self.be(8)?)),
+            0xcc..=0xcf => Value::Int(self.be(1 << (tag - 0xcc))? as i128),
+            0xd0 => Value::Int(self.be(1)? as u8 as i8 as i128),
+            0xd1 => Value::Int(self.be(2)? as u16 as i16 as i128),
+            0xd2 => Value::Int(self.be(4)? as u32 as i32 as i128),
+            0xd3 => Value::Int(self.be(8)? as i64 as i128),
+            0xd9 | 0xda | 0xdb => {
+                let len = self.be(1 << (tag - 0xd9))? as usize;
+                self.string(len)?
+            }
+            0xdc | 0xdd => {
+                let len = self.be(2 << (tag - 0xdc))? as usize;
+                self.items(len)?
+            }
+            0xde | 0xdf => {
+                let len = self.be(2 << (tag - 0xde))? as usize;
+                self.pairs(len)?
+            }
+            0xe0..=0xff => Value::Int(tag as i8 as i128),
+            _ => return Err(Error::Value(format!("unsupported MessagePack type 0x{:02x} at byte {}", tag, pos))),
+        })
+    }
+}
+
+struct Decoder {
+    arena: Rc<NodeArena>,
+}
+
+impl Decoder {
+    fn struct_(&self, node: &NodeRef, builder: &mut StructBuilder, value: &Value) -> Result<(), Error> {
+        let pairs = match value {
+            Value::Map(x) => x,
+            x => return mismatch("map", x),
+        };
+
+        let fields = dynamic::struct_node(node)?.get_fields()?;
+
+        for (k, v) in pairs {
+            // either key form is accepted, whichever the encoder was told to use
+            let field = match k {
+                Value::Str(name) => dynamic::find_field(node, name)?,
+                Value::Int(idx) if *idx >= 0 && (*idx as u64) < fields.len() as u64 => fields.get(*idx as u32),
+                Value::Int(idx) => return Err(Error::Value(format!("no field with index {}", idx))),
+                x => return mismatch("str or int key", x),
+            };
+
+            dynamic::set_active(node, builder, &field)?;
+
+            match field.which()? {
+                schema_capnp::field::Group(x) => {
+                    let group = dynamic::get_node(&self.arena, x.get_type_id())?;
+                    self.struct_(&group, builder, v)?;
+                }
+                schema_capnp::field::Slot(x) => {
+                    let type_ = x.get_type()?;
+                    let offset = x.get_offset() as usize;
+
+                    if dynamic::is_pointer(&type_)? {
+                        self.pointer(builder.reborrow().get_pointer_field(offset), &type_, v)?;
+                        continue;
+                    }
+
+                    let bits = self.primitive(&type_, v)? ^ dynamic::default_bits(x.get_default_value()?)?;
+
+                    match dynamic::element_size(&type_)? {
+                        capnp::private::layout::ElementSize::Void => {}
+                        capnp::private::layout::ElementSize::Bit => builder.set_bool_field(offset, bits != 0),
+                        capnp::private::layout::ElementSize::Byte => builder.set_data_field::<u8>(offset, bits as u8),
+                        capnp::private::layout::ElementSize::TwoBytes => builder.set_data_field::<u16>(offset, bits as u16),
+                        capnp::private::layout::ElementSize::FourBytes => builder.set_data_field::<u32>(offset, bits as u32),
+                        _ => builder.set_data_field::<u64>(offset, bits),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn primitive(&self, type_: &TypeReader, value: &Value) -> Result<u64, Error> {
+        use schema_capnp::type_ as T;
+
+        let int = |min: i128, max: i128| -> Result<u64, Error> {
+            match value {
+                Value::Int(x) if *x >= min && *x <= max => Ok(*x as u64),
+                Value::Int(x) => Err(Error::Value(format!("integer out of range: {}", x))),
+                x => mismatch("int", x),
+            }
+        };
+
+        let float = || -> Result<f64, Error> {
+            match value {
+                Value::Float(x) => Ok(*x),
+                Value::Int(x) => Ok(*x as f64),
+                x => mismatch("float", x),
+            }
+        };
+
+        Ok(match type_.which()? {
+            T::Void(()) => 0,
+            T::Bool(()) => match value {
+                Value::Bool(x) => *x as u64,
+                x => return mismatch("bool", x),
+            },
+            T::Int8(()) => int(i8::min_value() as i128, i8::max_value() as i128)? & 0xff,
+            T::Int16(()) => int(i16::min_value() as i128, i16::max_value() as i128)? & 0xffff,
+            T::Int32(()) => int(i32::min_value() as i128, i32::max_value() as i128)? & 0xffff_ffff,
+            T::Int64(()) => int(i64::min_value() as i128, i64::max_value() as i128)?,
+            T::Uint8(()) => int(0, u8::max_value() as i128)?,
+            T::Uint16(()) => int(0, u16::max_value() as i128)?,
+            T::Uint32(()) => int(0, u32::max_value() as i128)?,
+            T::Uint64(()) => int(0, u64::max_value() as i128)?,
+            T::Float32(()) => (float()? as f32).to_bits() as u64,
+            T::Float64(()) => float()?.to_bits(),
+            T::Enum(x) => match value {
+                Value::Str(name) => dynamic::enumerant_index(&self.arena, x.get_type_id(), name)? as u64,
+                _ => int(0, u16::max_value() as i128)?,
+            },
+            _ => return Err(Error::Type("not a primitive type".into())),
+        })
+    }
+
+    fn pointer(&self, mut ptr: PointerBuilder, type_: &TypeReader, value: &Value) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        match (type_.which()?, value) {
+            (_, Value::Nil) => ptr.clear(),
+            (T::Text(()), Value::Str(x)) => ptr.set_text(x),
+            (T::Data(()), Value::Bin(x)) => ptr.set_data(x),
+            (T::Struct(x), _) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                let mut builder = ptr.init_struct(message::get_node_struct_size(&node)?);
+
+                self.struct_(&node, &mut builder, value)?;
+            }
+            (T::List(x), Value::Array(items)) => {
+                let element = x.get_element_type()?;
+                let mut list = dynamic::init_list(&self.arena, ptr, &element, items.len() as u32)?;
+
+                for (i, item) in items.iter().enumerate() {
+                    self.element(&mut list, i as u32, &element, item)?;
+                }
+            }
+            (T::Text(()), x) => return mismatch("str", x),
+            (T::Data(()), x) => return mismatch("bin", x),
+            (T::List(_), x) => return mismatch("array", x),
+            _ => return Err(Error::Type("AnyPointer and capabilities have no MessagePack form".into())),
+        }
+
+        Ok(())
+    }
+
+    fn element(&self, list: &mut ListBuilder, idx: u32, element: &TypeReader, value: &Value) -> Result<(), Error> {
+        use capnp::private::layout::PrimitiveElement;
+        use schema_capnp::type_ as T;
+
+        match element.which()? {
+            T::Struct(x) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                return self.struct_(&node, &mut list.reborrow().get_struct_element(idx), value);
+            }
+            T::Text(()) | T::Data(()) | T::List(_) | T::AnyPointer(_) | T::Interface(_) => {
+                return self.pointer(list.reborrow().get_pointer_element(idx), element, value);
+            }
+            _ => {}
+        }
+
+        let bits = self.primitive(element, value)?;
+
+        match dynamic::element_size(element)? {
+            capnp::private::layout::ElementSize::Void => {}
+            capnp::private::layout::ElementSize::Bit => <bool as PrimitiveElement>::set(list, idx, bits != 0),
+            capnp::private::layout::ElementSize::Byte => <u8 as PrimitiveElement>::set(list, idx, bits as u8),
+            capnp::private::layout::ElementSize::TwoBytes => <u16 as PrimitiveElement>::set(list, idx, bits as u16),
+            capnp::private::layout::ElementSize::FourBytes => <u32 as PrimitiveElement>::set(list, idx, bits as u32),
+            _ => <u64 as PrimitiveElement>::set(list, idx, bits),
+        }
+
+        Ok(())
+    }
+}
+
+pub fn encode(node: &NodeRef, reader: &StructReader, options: Options) -> Result<Vec<u8>, Error> {
+    let mut encoder = Encoder { arena: node.rc(), options, out: Output { buf: Vec::new() } };
+
+    encoder.struct_(node, reader)?;
+
+    Ok(encoder.out.buf)
+}
+
+pub fn decode(node: &NodeRef, builder: &mut StructBuilder, data: &[u8]) -> Result<(), Error> {
+    let mut input = Input { data, pos: 0, depth: 0 };
+    let value = input.value()?;
+
+    if input.pos != data.len() {
+        return Err(Error::Value(format!("{} trailing bytes after the MessagePack object", data.len() - input.pos)));
+    }
+
+    Decoder { arena: node.rc() }.struct_(node, builder, &value)
+}
+
+#[pyclass]
+pub struct ToMsgpackFun {}
+
+#[pymethods]
+impl ToMsgpackFun {
+    /// Map keys are field names or, with `keys="index"`, positions in the
+    /// schema's field list; enums are written as ints or, with
+    /// `enums="name"`, as enumerant names.
+    #[call]
+    #[args(keys = "\"name\"", enums = "\"int\"")]
+    fn to_msgpack(&self, py: Python, value: &PyAny, keys: &str, enums: &str) -> PyResult<PyObject> {
+        let reader = dynamic::struct_reader(value)?;
+        let data = encode(&reader.node, &reader.reader, Options::new(keys, enums)?)?;
+
+        Ok(PyBytes::new(py, &data).into())
+    }
+}
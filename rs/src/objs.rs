@@ -1,9 +1,11 @@
 use capnp::private::layout;
 use capnpc::schema_capnp;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use capnp::Word;
-use crate::Error;
+use crate::{Error, NodeArena};
+use crate::dynamic;
 use std::ops::Deref;
 
 type Id = u64;
@@ -29,7 +31,7 @@ pub struct BrandScope {
     kind: BrandScopeKind,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Brand {
     scopes: Vec<BrandScope>,
 }
@@ -73,6 +75,49 @@ impl Brand {
 
         Ok(Brand { scopes: r })
     }
+
+    /// What parameter `index` of scope `scope_id` is bound to; None when it
+    /// is unbound, which makes it an AnyPointer.
+    pub fn binding(&self, scope_id: Id, index: u16) -> Option<&Type> {
+        let scope = self.scopes.iter().find(|x| x.scope_id == scope_id)?;
+
+        match &scope.kind {
+            BrandScopeKind::Bind(x) => match x.get(index as usize) {
+                Some(BrandBinding::Type(x)) => Some(x),
+                _ => None,
+            },
+            BrandScopeKind::Inherit => None,
+        }
+    }
+
+    /// This brand as written inside a scope that is itself branded with
+    /// `outer`: parameters of the enclosing scopes are replaced with what
+    /// `outer` binds them to, inherited scopes are taken from it.
+    pub fn substitute(&self, outer: &Brand) -> Brand {
+        let mut r = Vec::with_capacity(self.scopes.len());
+
+        for x in self.scopes.iter() {
+            match &x.kind {
+                BrandScopeKind::Bind(bindings) => {
+                    let bindings = bindings.iter()
+                        .map(|x| match x {
+                            BrandBinding::Type(x) => BrandBinding::Type(x.substitute(outer)),
+                            BrandBinding::Unbound => BrandBinding::Unbound,
+                        })
+                        .collect();
+
+                    r.push(BrandScope { scope_id: x.scope_id, kind: BrandScopeKind::Bind(bindings) });
+                }
+                BrandScopeKind::Inherit => {
+                    if let Some(y) = outer.scopes.iter().find(|y| y.scope_id == x.scope_id) {
+                        r.push(y.clone());
+                    }
+                }
+            }
+        }
+
+        Brand { scopes: r }
+    }
 }
 
 #[derive(Clone)]
@@ -83,21 +128,34 @@ pub struct Annotation {
 }
 
 impl Annotation {
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
     fn from_reader(
         reader: &schema_capnp::annotation::Reader
     ) -> Result<Annotation, Error> {
         Ok(Annotation {
             id: reader.get_id(),
             brand: Brand::from_reader(&reader.get_brand()?)?,
-            value: Value::from_reader(reader.get_value()?)?,
+            value: Value::from_reader(&reader.get_value()?)?,
         })
     }
 }
 
-struct Annotations(Vec<Annotation>);
+#[derive(Clone)]
+pub struct Annotations(Vec<Annotation>);
 
 impl Annotations {
-    fn from_reader(
+    pub fn get(&self, id: Id) -> Option<&Annotation> {
+        self.0.iter().find(|x| x.id == id)
+    }
+
+    pub fn from_reader(
         reader: &capnp::struct_list::Reader<schema_capnp::annotation::Owned>
     ) -> Result<Annotations, Error> {
         let mut r = Vec::with_capacity(reader.len() as usize);
@@ -162,17 +220,136 @@ pub struct Method {
     annotations: Annotations,
 }
 
+impl Method {
+    pub fn from_reader(
+        reader: &schema_capnp::method::Reader
+    ) -> Result<Method, Error> {
+        Ok(Method {
+            name: reader.get_name()?.into(),
+            code_order: reader.get_code_order(),
+            implicit_parameters: Parameters::from_reader(&reader.get_implicit_parameters()?)?,
+            param_type: reader.get_param_struct_type(),
+            param_brand: Brand::from_reader(&reader.get_param_brand()?)?,
+            result_type: reader.get_result_struct_type(),
+            result_brand: Brand::from_reader(&reader.get_result_brand()?)?,
+            annotations: Annotations::from_reader(&reader.get_annotations()?)?,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The struct node holding the params, possibly generated for a named
+    /// parameter list, which is then in no scope's `nestedNodes`.
+    pub fn param_type(&self) -> NodeId {
+        self.param_type
+    }
+
+    pub fn result_type(&self) -> NodeId {
+        self.result_type
+    }
+
+    /// Type parameters listed in `[]` after the method name. They are bound
+    /// by each call, by whatever the caller passes, never by a brand of the
+    /// interface; on the wire they are AnyPointers.
+    pub fn implicit_parameters(&self) -> &Parameters {
+        &self.implicit_parameters
+    }
+
+    /// Type of the params as seen from the declaring interface branded
+    /// with `brand`.
+    pub fn params(&self, brand: &Brand) -> Type {
+        Type::Struct { id: self.param_type, brand: self.param_brand.substitute(brand) }
+    }
+
+    pub fn results(&self, brand: &Brand) -> Type {
+        Type::Struct { id: self.result_type, brand: self.result_brand.substitute(brand) }
+    }
+}
+
 #[derive(Clone)]
 pub struct Superclass {
     id: NodeId,
     brand: Brand,
 }
 
+impl Superclass {
+    pub fn from_reader(
+        reader: &schema_capnp::superclass::Reader
+    ) -> Result<Superclass, Error> {
+        Ok(Superclass { id: reader.get_id(), brand: Brand::from_reader(&reader.get_brand()?)? })
+    }
+}
+
+/// A row of the complete method table of an interface: a method the
+/// interface declares or inherits.
+#[derive(Clone)]
+pub struct InterfaceMethod {
+    /// The interface declaring the method; calls are addressed to it.
+    pub interface_id: NodeId,
+    /// Position of the method in the declaring interface.
+    pub ordinal: u16,
+    /// How the parameters of the declaring interface are bound, as seen
+    /// from the interface the table belongs to.
+    pub brand: Brand,
+    pub method: Method,
+}
+
+/// The complete method table of interface `id`: its own methods in
+/// ordinal order, then the tables of its superclasses in declaration
+/// order. An interface reached twice, as in a diamond, is listed once.
+/// Tables are built once per arena and shared.
+pub fn method_table(arena: &Rc<NodeArena>, id: NodeId) -> Result<Rc<Vec<InterfaceMethod>>, Error> {
+    fn walk(
+        arena: &Rc<NodeArena>,
+        id: NodeId,
+        brand: Brand,
+        seen: &mut HashSet<NodeId>,
+        r: &mut Vec<InterfaceMethod>,
+    ) -> Result<(), Error> {
+        if !seen.insert(id) {
+            return Ok(());
+        }
+
+        let interface = dynamic::interface_node(&dynamic::get_node(arena, id)?)?;
+
+        for (i, x) in interface.get_methods()?.iter().enumerate() {
+            r.push(InterfaceMethod {
+                interface_id: id,
+                ordinal: i as u16,
+                brand: brand.clone(),
+                method: Method::from_reader(&x)?,
+            });
+        }
+
+        for x in interface.get_superclasses()? {
+            let superclass = Superclass::from_reader(&x)?;
+            walk(arena, superclass.id, superclass.brand.substitute(&brand), seen, r)?;
+        }
+
+        Ok(())
+    }
+
+    arena.method_table(id, || {
+        let mut r = Vec::new();
+        walk(arena, id, Brand::default(), &mut HashSet::new(), &mut r)?;
+        Ok(r)
+    })
+}
+
 #[derive(Clone)]
 pub struct Parameter {
     name: VarName
 }
 
+impl Parameter {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Clone)]
 pub struct Parameters(Vec<Parameter>);
 
 impl Parameters {
@@ -297,7 +474,7 @@ pub enum Type {
 }
 
 impl Type {
-    fn from_reader(
+    pub fn from_reader(
         reader: &schema_capnp::type_::Reader
     ) -> Result<Type, Error> {
         let r = match reader.which()? {
@@ -329,12 +506,12 @@ impl Type {
             schema_capnp::type_::Struct(x) => {
                 let x: &schema_capnp::type_::struct_::Reader = &x;
 
-                Type::Struct { id: x.get_type_id(), brand: Brand::from_reader(x.get_brand()?)? }
+                Type::Struct { id: x.get_type_id(), brand: Brand::from_reader(&x.get_brand()?)? }
             }
             schema_capnp::type_::Interface(x) => {
                 let x: &schema_capnp::type_::interface::Reader = &x;
 
-                Type::Interface { id: x.get_type_id(), brand: Brand::from_reader(x.get_brand()?)? }
+                Type::Interface { id: x.get_type_id(), brand: Brand::from_reader(&x.get_brand()?)? }
             }
             schema_capnp::type_::AnyPointer(x) => {
                 let x: &schema_capnp::type_::any_pointer::Reader = &x;
@@ -376,6 +553,113 @@ impl Type {
 
         Ok(r)
     }
+
+    pub fn is_pointer(&self) -> bool {
+        match self {
+            Type::Text | Type::Data | Type::List { .. } | Type::Struct { .. } |
+            Type::Interface { .. } | Type::AnyPointer(_) => true,
+            _ => false,
+        }
+    }
+
+    /// This type as written inside a scope branded with `outer`, see
+    /// `Brand::substitute`.
+    pub fn substitute(&self, outer: &Brand) -> Type {
+        match self {
+            Type::List { element } => Type::List { element: Box::new(element.substitute(outer)) },
+            Type::Enum { id, brand } => Type::Enum { id: *id, brand: brand.substitute(outer) },
+            Type::Struct { id, brand } => Type::Struct { id: *id, brand: brand.substitute(outer) },
+            Type::Interface { id, brand } => Type::Interface { id: *id, brand: brand.substitute(outer) },
+            Type::AnyPointer(AnyPointerType::Parameter { scope_id, index }) => {
+                outer.binding(*scope_id, *index).cloned().unwrap_or_else(|| self.clone())
+            }
+            x => x.clone(),
+        }
+    }
+
+    /// The type as it would be written in a schema, e.g. `List(Text)` or
+    /// `GenericBase(Data)`; unbound parameters print as their name, the
+    /// implicit ones as named in `implicit`.
+    pub fn render(&self, arena: &Rc<NodeArena>, implicit: &[Parameter]) -> Result<String, Error> {
+        let named = |id: Id, brand: &Brand| -> Result<String, Error> {
+            let node = dynamic::get_node(arena, id)?;
+            let name = node.get_display_name()?[node.get_display_name_prefix_length() as usize..].to_string();
+
+            let parameters = node.get_parameters()?;
+            if parameters.len() == 0 {
+                return Ok(name);
+            }
+
+            let mut args = Vec::with_capacity(parameters.len() as usize);
+            for (i, x) in parameters.iter().enumerate() {
+                args.push(match brand.binding(id, i as u16) {
+                    Some(x) => x.render(arena, implicit)?,
+                    None => x.get_name()?.to_string(),
+                });
+            }
+
+            Ok(format!("{}({})", name, args.join(", ")))
+        };
+
+        Ok(match self {
+            Type::Void => "Void".into(),
+            Type::Bool => "Bool".into(),
+            Type::Int8 => "Int8".into(),
+            Type::Int16 => "Int16".into(),
+            Type::Int32 => "Int32".into(),
+            Type::Int64 => "Int64".into(),
+            Type::Uint8 => "UInt8".into(),
+            Type::Uint16 => "UInt16".into(),
+            Type::Uint32 => "UInt32".into(),
+            Type::Uint64 => "UInt64".into(),
+            Type::Float32 => "Float32".into(),
+            Type::Float64 => "Float64".into(),
+            Type::Text => "Text".into(),
+            Type::Data => "Data".into(),
+            Type::List { element } => format!("List({})", element.render(arena, implicit)?),
+            Type::Enum { id, brand } | Type::Struct { id, brand } | Type::Interface { id, brand } => named(*id, brand)?,
+            Type::AnyPointer(AnyPointerType::Parameter { scope_id, index }) => {
+                dynamic::get_node(arena, *scope_id)?.get_parameters()?.get(*index as u32).get_name()?.to_string()
+            }
+            Type::AnyPointer(AnyPointerType::ImplicitMethodParamater { index }) => match implicit.get(*index as usize) {
+                Some(x) => x.name.clone(),
+                None => "AnyPointer".into(),
+            },
+            Type::AnyPointer(AnyPointerType::Struct) => "AnyStruct".into(),
+            Type::AnyPointer(AnyPointerType::List) => "AnyList".into(),
+            Type::AnyPointer(AnyPointerType::Capability) => "Capability".into(),
+            Type::AnyPointer(_) => "AnyPointer".into(),
+        })
+    }
+
+    /// Whether a value of type `other` can be stored where `self` is expected:
+    /// the same wire type and, for named types, the same node. Brands are not
+    /// compared; AnyPointer (including generic parameters) takes any pointer
+    /// of the kind it is constrained to. A value typed AnyPointer passes for
+    /// any pointer, the caller checks what it actually holds.
+    pub fn is_compatible(&self, other: &Type) -> bool {
+        match (self, other) {
+            (Type::List { element: a }, Type::List { element: b }) => a.is_compatible(b),
+            (Type::Enum { id: a, .. }, Type::Enum { id: b, .. }) |
+            (Type::Struct { id: a, .. }, Type::Struct { id: b, .. }) |
+            (Type::Interface { id: a, .. }, Type::Interface { id: b, .. }) => a == b,
+            (_, Type::AnyPointer(_)) => self.is_pointer(),
+            (Type::AnyPointer(AnyPointerType::Struct), x) => match x {
+                Type::Struct { .. } => true,
+                _ => false,
+            },
+            (Type::AnyPointer(AnyPointerType::List), x) => match x {
+                Type::List { .. } | Type::Text | Type::Data => true,
+                _ => false,
+            },
+            (Type::AnyPointer(AnyPointerType::Capability), x) => match x {
+                Type::Interface { .. } => true,
+                _ => false,
+            },
+            (Type::AnyPointer(_), x) => x.is_pointer(),
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -411,11 +695,71 @@ pub enum Value {
     AnyPointer(AnyPointerValue),
 }
 
+impl Value {
+    pub fn from_reader(
+        reader: &schema_capnp::value::Reader
+    ) -> Result<Value, Error> {
+        use schema_capnp::value as V;
+
+        let r = match reader.which()? {
+            V::Void(()) => Value::Void,
+            V::Bool(x) => Value::Bool(x),
+            V::Int8(x) => Value::Int8(x),
+            V::Int16(x) => Value::Int16(x),
+            V::Int32(x) => Value::Int32(x),
+            V::Int64(x) => Value::Int64(x),
+            V::Uint8(x) => Value::Uint8(x),
+            V::Uint16(x) => Value::Uint16(x),
+            V::Uint32(x) => Value::Uint32(x),
+            V::Uint64(x) => Value::Uint64(x),
+            V::Float32(x) => Value::Float32(x),
+            V::Float64(x) => Value::Float64(x),
+            V::Text(x) => Value::Text(x?.as_bytes().to_vec()),
+            V::Data(x) => Value::Data(x?.to_vec()),
+            V::List(x) => Value::List(AnyPointerValue::List(AnyPointerValue::canonical(&x)?)),
+            V::Enum(x) => Value::Enum(x),
+            V::Struct(x) => Value::Struct(AnyPointerValue::Struct(AnyPointerValue::canonical(&x)?)),
+            V::Interface(()) => Value::Interface(()),
+            V::AnyPointer(x) => Value::AnyPointer(AnyPointerValue::Any(AnyPointerValue::canonical(&x)?)),
+        };
+
+        Ok(r)
+    }
+}
+
+/// Pointer values of constants, defaults and annotations, held as their
+/// canonical encoding (a bare single segment).
 #[derive(Clone)]
 pub enum AnyPointerValue {
-    Struct(),
-    List(),
+    Struct(Vec<u8>),
+    List(Vec<u8>),
     Interface(),
+    Any(Vec<u8>),
+}
+
+impl AnyPointerValue {
+    fn canonical(reader: &capnp::any_pointer::Reader) -> Result<Vec<u8>, Error> {
+        crate::canonical::canonicalize_pointer(reader.get_as::<crate::dynamic::RootPointer>()?.0)
+    }
+
+    /// Text in pointer slot `idx` of a struct value, `None` when it is null.
+    pub fn text_field(&self, idx: usize) -> Result<Option<String>, Error> {
+        let data = match self {
+            AnyPointerValue::Struct(x) => x,
+            _ => return Err(Error::Type("not a struct value".into())),
+        };
+
+        let message = crate::framing::Frame::single_segment(data)
+            .into_reader(capnp::message::ReaderOptions::new());
+        let root: crate::dynamic::RootPointer = message.get_root()?;
+        let ptr = root.0.get_struct(None)?.get_pointer_field(idx);
+
+        if ptr.is_null() {
+            return Ok(None);
+        }
+
+        Ok(Some(ptr.get_text(None)?.to_string()))
+    }
 }
 
 pub struct Arena {
@@ -0,0 +1,441 @@
+//! The parts of rpc.capnp that two-party RPC uses, as raw struct layouts.
+//!
+//! There is no generated code for rpc.capnp in this crate, and the dynamic
+//! layer needs a compiled schema, so messages are read and written by
+//! offset. Data offsets count in units of the field's own width, like the
+//! generated accessors; bools count in bits.
+
+use capnp::private::layout::{ElementSize, PointerBuilder, PointerReader, StructBuilder, StructReader, StructSize};
+
+use crate::Error;
+use crate::dynamic::RootBuilder;
+use crate::framing;
+
+pub mod message {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 1 };
+    pub const WHICH: usize = 0;
+    // every arm is a pointer in the same slot
+    pub const BODY: usize = 0;
+
+    pub mod which {
+        pub const UNIMPLEMENTED: u16 = 0;
+        pub const ABORT: u16 = 1;
+        pub const CALL: u16 = 2;
+        pub const RETURN: u16 = 3;
+        pub const FINISH: u16 = 4;
+        pub const RESOLVE: u16 = 5;
+        pub const RELEASE: u16 = 6;
+        pub const OBSOLETE_SAVE: u16 = 7;
+        pub const BOOTSTRAP: u16 = 8;
+        pub const OBSOLETE_DELETE: u16 = 9;
+        pub const PROVIDE: u16 = 10;
+        pub const ACCEPT: u16 = 11;
+        pub const JOIN: u16 = 12;
+        pub const DISEMBARGO: u16 = 13;
+    }
+}
+
+pub mod bootstrap {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 1 };
+    pub const QUESTION_ID: usize = 0;
+}
+
+pub mod call {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 3, pointers: 3 };
+    pub const QUESTION_ID: usize = 0;
+    pub const TARGET: usize = 0;
+    pub const INTERFACE_ID: usize = 1;
+    pub const METHOD_ID: usize = 2;
+    pub const PARAMS: usize = 1;
+    pub const SEND_RESULTS_TO: usize = 3;
+    pub const ALLOW_THIRD_PARTY_TAIL_CALL: usize = 128;
+    pub const NO_PROMISE_PIPELINING: usize = 129;
+    pub const ONLY_PROMISE_PIPELINE: usize = 130;
+
+    pub mod send_results_to {
+        pub const CALLER: u16 = 0;
+        pub const YOURSELF: u16 = 1;
+        pub const THIRD_PARTY: u16 = 2;
+    }
+}
+
+pub mod return_ {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 2, pointers: 1 };
+    pub const ANSWER_ID: usize = 0;
+    // default true, stored inverted
+    pub const RELEASE_PARAM_CAPS: usize = 32;
+    pub const NO_FINISH_NEEDED: usize = 33;
+    pub const WHICH: usize = 3;
+    pub const BODY: usize = 0;
+    pub const TAKE_FROM_OTHER_QUESTION: usize = 2;
+
+    pub mod which {
+        pub const RESULTS: u16 = 0;
+        pub const EXCEPTION: u16 = 1;
+        pub const CANCELED: u16 = 2;
+        pub const RESULTS_SENT_ELSEWHERE: u16 = 3;
+        pub const TAKE_FROM_OTHER_QUESTION: u16 = 4;
+        pub const ACCEPT_FROM_THIRD_PARTY: u16 = 5;
+    }
+}
+
+pub mod finish {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 0 };
+    pub const QUESTION_ID: usize = 0;
+    // both default true, stored inverted
+    pub const RELEASE_RESULT_CAPS: usize = 32;
+    pub const REQUIRE_EARLY_CANCELLATION_WORKAROUND: usize = 33;
+}
+
+pub mod resolve {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 1 };
+    pub const PROMISE_ID: usize = 0;
+    pub const WHICH: usize = 2;
+    pub const BODY: usize = 0;
+
+    pub mod which {
+        pub const CAP: u16 = 0;
+        pub const EXCEPTION: u16 = 1;
+    }
+}
+
+pub mod release {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 0 };
+    pub const ID: usize = 0;
+    pub const REFERENCE_COUNT: usize = 1;
+}
+
+pub mod disembargo {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 1 };
+    pub const TARGET: usize = 0;
+    // senderLoopback, receiverLoopback and provide share the slot
+    pub const ID: usize = 0;
+    pub const WHICH: usize = 2;
+
+    pub mod which {
+        pub const SENDER_LOOPBACK: u16 = 0;
+        pub const RECEIVER_LOOPBACK: u16 = 1;
+        pub const ACCEPT: u16 = 2;
+        pub const PROVIDE: u16 = 3;
+    }
+}
+
+pub mod message_target {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 1 };
+    pub const IMPORTED_CAP: usize = 0;
+    pub const PROMISED_ANSWER: usize = 0;
+    pub const WHICH: usize = 2;
+
+    pub mod which {
+        pub const IMPORTED_CAP: u16 = 0;
+        pub const PROMISED_ANSWER: u16 = 1;
+    }
+}
+
+pub mod promised_answer {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 1 };
+    pub const QUESTION_ID: usize = 0;
+    pub const TRANSFORM: usize = 0;
+}
+
+pub mod op {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 0 };
+    pub const WHICH: usize = 0;
+    pub const GET_POINTER_FIELD: usize = 1;
+
+    pub mod which {
+        pub const NOOP: u16 = 0;
+        pub const GET_POINTER_FIELD: u16 = 1;
+    }
+}
+
+pub mod payload {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 0, pointers: 2 };
+    pub const CONTENT: usize = 0;
+    pub const CAP_TABLE: usize = 1;
+}
+
+pub mod cap_descriptor {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 1 };
+    pub const WHICH: usize = 0;
+    // senderHosted, senderPromise and receiverHosted share the slot
+    pub const ID: usize = 1;
+    pub const RECEIVER_ANSWER: usize = 0;
+    // default 255, stored inverted
+    pub const ATTACHED_FD: usize = 2;
+
+    pub mod which {
+        pub const NONE: u16 = 0;
+        pub const SENDER_HOSTED: u16 = 1;
+        pub const SENDER_PROMISE: u16 = 2;
+        pub const RECEIVER_HOSTED: u16 = 3;
+        pub const RECEIVER_ANSWER: u16 = 4;
+        pub const THIRD_PARTY_HOSTED: u16 = 5;
+    }
+}
+
+pub mod exception {
+    use super::StructSize;
+
+    pub const SIZE: StructSize = StructSize { data: 1, pointers: 2 };
+    pub const REASON: usize = 0;
+    pub const TYPE: usize = 2;
+    pub const TRACE: usize = 1;
+
+    pub mod type_ {
+        pub const FAILED: u16 = 0;
+        pub const OVERLOADED: u16 = 1;
+        pub const DISCONNECTED: u16 = 2;
+        pub const UNIMPLEMENTED: u16 = 3;
+    }
+}
+
+/// An rpc.capnp `Exception`, the error half of every answer.
+#[derive(Clone, Debug)]
+pub struct Exception {
+    pub type_: u16,
+    pub reason: String,
+}
+
+impl Exception {
+    pub fn failed(reason: String) -> Exception {
+        Exception { type_: exception::type_::FAILED, reason }
+    }
+
+    pub fn disconnected(reason: String) -> Exception {
+        Exception { type_: exception::type_::DISCONNECTED, reason }
+    }
+
+    pub fn unimplemented(reason: String) -> Exception {
+        Exception { type_: exception::type_::UNIMPLEMENTED, reason }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self.type_ {
+            exception::type_::FAILED => "failed",
+            exception::type_::OVERLOADED => "overloaded",
+            exception::type_::DISCONNECTED => "disconnected",
+            exception::type_::UNIMPLEMENTED => "unimplemented",
+            _ => "unknown",
+        }
+    }
+
+    pub fn read(reader: &StructReader) -> Result<Exception, Error> {
+        let reason = reader.get_pointer_field(exception::REASON);
+
+        Ok(Exception {
+            type_: reader.get_data_field::<u16>(exception::TYPE),
+            reason: if reason.is_null() { String::new() } else { reason.get_text(None)?.to_string() },
+        })
+    }
+
+    pub fn write(&self, builder: StructBuilder) {
+        builder.set_data_field::<u16>(exception::TYPE, self.type_);
+        builder.get_pointer_field(exception::REASON).set_text(&self.reason);
+    }
+
+    pub fn to_error(&self) -> Error {
+        Error::Rpc(format!("{}: {}", self.type_name(), self.reason))
+    }
+}
+
+/// What a call is addressed to: a capability the peer exported to us, or a
+/// capability inside the results of one of our questions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Import(u32),
+    Answer(u32, Vec<u16>),
+}
+
+fn read_transform(reader: &StructReader) -> Result<Vec<u16>, Error> {
+    let ops = reader.get_pointer_field(promised_answer::TRANSFORM).get_list(ElementSize::InlineComposite, None)?;
+    let mut r = Vec::with_capacity(ops.len() as usize);
+
+    for i in 0..ops.len() {
+        let op = ops.get_struct_element(i);
+
+        match op.get_data_field::<u16>(op::WHICH) {
+            op::which::NOOP => {}
+            op::which::GET_POINTER_FIELD => r.push(op.get_data_field::<u16>(op::GET_POINTER_FIELD)),
+            x => return Err(Error::Rpc(format!("unknown pipeline op {}", x))),
+        }
+    }
+
+    Ok(r)
+}
+
+/// Reads a `PromisedAnswer`: the question and the pointer-field path into
+/// its results.
+pub fn read_promised_answer(reader: &StructReader) -> Result<(u32, Vec<u16>), Error> {
+    Ok((reader.get_data_field::<u32>(promised_answer::QUESTION_ID), read_transform(reader)?))
+}
+
+pub fn write_promised_answer(ptr: PointerBuilder, question: u32, transform: &[u16]) {
+    let builder = ptr.init_struct(promised_answer::SIZE);
+    builder.set_data_field::<u32>(promised_answer::QUESTION_ID, question);
+
+    let mut ops = builder.get_pointer_field(promised_answer::TRANSFORM)
+        .init_struct_list(transform.len() as u32, op::SIZE);
+
+    for (i, x) in transform.iter().enumerate() {
+        let op = ops.reborrow().get_struct_element(i as u32);
+        op.set_data_field::<u16>(op::WHICH, op::which::GET_POINTER_FIELD);
+        op.set_data_field::<u16>(op::GET_POINTER_FIELD, *x);
+    }
+}
+
+impl Target {
+    pub fn read(reader: &StructReader) -> Result<Target, Error> {
+        match reader.get_data_field::<u16>(message_target::WHICH) {
+            message_target::which::IMPORTED_CAP => {
+                Ok(Target::Import(reader.get_data_field::<u32>(message_target::IMPORTED_CAP)))
+            }
+            message_target::which::PROMISED_ANSWER => {
+                let answer = reader.get_pointer_field(message_target::PROMISED_ANSWER).get_struct(None)?;
+                let (question, transform) = read_promised_answer(&answer)?;
+
+                Ok(Target::Answer(question, transform))
+            }
+            x => Err(Error::Rpc(format!("unknown message target {}", x))),
+        }
+    }
+
+    pub fn write(&self, ptr: PointerBuilder) {
+        let builder = ptr.init_struct(message_target::SIZE);
+
+        match self {
+            Target::Import(id) => {
+                builder.set_data_field::<u16>(message_target::WHICH, message_target::which::IMPORTED_CAP);
+                builder.set_data_field::<u32>(message_target::IMPORTED_CAP, *id);
+            }
+            Target::Answer(question, transform) => {
+                builder.set_data_field::<u16>(message_target::WHICH, message_target::which::PROMISED_ANSWER);
+                write_promised_answer(builder.get_pointer_field(message_target::PROMISED_ANSWER), *question, transform);
+            }
+        }
+    }
+}
+
+/// An entry of a payload's cap table, from the sender's point of view.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CapDescriptor {
+    None,
+    SenderHosted(u32),
+    SenderPromise(u32),
+    ReceiverHosted(u32),
+    ReceiverAnswer(u32, Vec<u16>),
+    ThirdPartyHosted,
+}
+
+impl CapDescriptor {
+    pub fn read(reader: &StructReader) -> Result<CapDescriptor, Error> {
+        use self::cap_descriptor::which as W;
+
+        let id = reader.get_data_field::<u32>(cap_descriptor::ID);
+
+        Ok(match reader.get_data_field::<u16>(cap_descriptor::WHICH) {
+            W::NONE => CapDescriptor::None,
+            W::SENDER_HOSTED => CapDescriptor::SenderHosted(id),
+            W::SENDER_PROMISE => CapDescriptor::SenderPromise(id),
+            W::RECEIVER_HOSTED => CapDescriptor::ReceiverHosted(id),
+            W::RECEIVER_ANSWER => {
+                let answer = reader.get_pointer_field(cap_descriptor::RECEIVER_ANSWER).get_struct(None)?;
+                let (question, transform) = read_promised_answer(&answer)?;
+
+                CapDescriptor::ReceiverAnswer(question, transform)
+            }
+            W::THIRD_PARTY_HOSTED => CapDescriptor::ThirdPartyHosted,
+            x => return Err(Error::Rpc(format!("unknown capability descriptor {}", x))),
+        })
+    }
+
+    pub fn write(&self, builder: StructBuilder) {
+        use self::cap_descriptor::which as W;
+
+        let (which, id) = match self {
+            CapDescriptor::None => (W::NONE, 0),
+            CapDescriptor::SenderHosted(x) => (W::SENDER_HOSTED, *x),
+            CapDescriptor::SenderPromise(x) => (W::SENDER_PROMISE, *x),
+            CapDescriptor::ReceiverHosted(x) => (W::RECEIVER_HOSTED, *x),
+            CapDescriptor::ReceiverAnswer(question, transform) => {
+                builder.set_data_field::<u16>(cap_descriptor::WHICH, W::RECEIVER_ANSWER);
+                write_promised_answer(builder.get_pointer_field(cap_descriptor::RECEIVER_ANSWER), *question, transform);
+                return;
+            }
+            CapDescriptor::ThirdPartyHosted => (W::THIRD_PARTY_HOSTED, 0),
+        };
+
+        builder.set_data_field::<u16>(cap_descriptor::WHICH, which);
+        builder.set_data_field::<u32>(cap_descriptor::ID, id);
+    }
+}
+
+pub fn read_cap_table(payload: &StructReader) -> Result<Vec<CapDescriptor>, Error> {
+    let list = payload.get_pointer_field(payload::CAP_TABLE).get_list(ElementSize::InlineComposite, None)?;
+
+    (0..list.len()).map(|i| CapDescriptor::read(&list.get_struct_element(i))).collect()
+}
+
+pub fn write_cap_table(payload: StructBuilder, caps: &[CapDescriptor]) {
+    if caps.is_empty() {
+        return;
+    }
+
+    let mut list = payload.get_pointer_field(payload::CAP_TABLE)
+        .init_struct_list(caps.len() as u32, cap_descriptor::SIZE);
+
+    for (i, x) in caps.iter().enumerate() {
+        x.write(list.reborrow().get_struct_element(i as u32));
+    }
+}
+
+/// Serializes a `Message` whose active arm is `which`; `fill` writes the
+/// arm's pointer.
+pub fn build<F>(which: u16, fill: F) -> Result<Vec<u8>, Error>
+    where F: FnOnce(PointerBuilder) -> Result<(), Error>
+{
+    let mut message = capnp::message::Builder::new_default();
+
+    {
+        let root: RootBuilder = message.init_root();
+        let root = root.0.init_struct(message::SIZE);
+
+        root.set_data_field::<u16>(message::WHICH, which);
+        fill(root.get_pointer_field(message::BODY))?;
+    }
+
+    Ok(framing::write_segments(&*message.get_segments_for_output()))
+}
+
+/// The active arm of a `Message` and its pointer.
+pub fn open<'a>(root: PointerReader<'a>) -> Result<(u16, PointerReader<'a>), Error> {
+    let root = root.get_struct(None)?;
+
+    Ok((root.get_data_field::<u16>(message::WHICH), root.get_pointer_field(message::BODY)))
+}
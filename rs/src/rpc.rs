@@ -0,0 +1,1758 @@
+//! Two-party RPC over dynamic schemas: capabilities are typed by interface
+//! nodes, params are filled in with struct builders and results come back
+//! as struct readers. Interfaces are served from Python objects.
+//!
+//! Capabilities in the results of a call can be called before the results
+//! arrive: those calls are addressed to the pending answer (promise
+//! pipelining) and the peer queues them behind it.
+//!
+//! Methods declared `-> stream` are called through a window: calls go out
+//! without waiting for their returns as long as the unacknowledged ones fit
+//! in it. A server holds those returns back while it is paused, which is
+//! how a Python implementation pushes back on a fast client.
+//!
+//! A connection is driven from the calling thread. Waiting for an answer
+//! reads and handles incoming messages until it arrives; the GIL is only
+//! released while blocked on the transport.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::RawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::rc::{Rc, Weak};
+
+use capnp::private::layout::{PointerBuilder, PointerReader, StructBuilder, StructReader};
+use pyo3::prelude::*;
+use pyo3::exceptions;
+use pyo3::types::{PyAny, PyDict};
+
+use crate::{Error, NodeArena, NodeInner, NodePy};
+use crate::capability::{self, CapabilityPlaceholderPy, CapTable, Entry};
+use crate::dynamic::{self, MessageRc, NodeRef, Owner, RootPointer, Source, StructBuilderPy, StructReaderPy};
+use crate::framing::Frame;
+use crate::message;
+use crate::objs;
+use crate::protocol::{self, CapDescriptor, Exception, Target};
+use crate::protocol::message::which as M;
+use crate::transport::{Link, Pump, StreamTransport, Transport};
+
+/// `StreamResult` of /capnp/stream.capnp, the results of `-> stream` methods.
+const STREAM_RESULT: u64 = 0x995f_9a33_77c0_b16e;
+
+/// A method of an interface, with everything a call needs.
+#[derive(Clone)]
+pub struct Method {
+    /// The interface declaring the method, see `objs::InterfaceMethod`.
+    pub interface_id: u64,
+    pub id: u16,
+    pub name: String,
+    pub params: NodeRef,
+    pub results: NodeRef,
+    pub brand: objs::Brand,
+    pub schema: objs::Method,
+}
+
+impl Method {
+    fn new(arena: &Rc<NodeArena>, x: &objs::InterfaceMethod) -> Result<Method, Error> {
+        Ok(Method {
+            interface_id: x.interface_id,
+            id: x.ordinal,
+            name: x.method.name().to_string(),
+            params: dynamic::get_node(arena, x.method.param_type())?,
+            results: dynamic::get_node(arena, x.method.result_type())?,
+            brand: x.brand.clone(),
+            schema: x.method.clone(),
+        })
+    }
+
+    /// Whether the method is declared `-> stream`.
+    pub fn is_streaming(&self) -> bool {
+        self.results.get_id() == STREAM_RESULT
+    }
+}
+
+/// Every method `node` declares or inherits, see `objs::method_table`.
+pub fn methods(arena: &Rc<NodeArena>, node: &NodeRef) -> Result<Vec<Method>, Error> {
+    objs::method_table(arena, node.get_id())?.iter().map(|x| Method::new(arena, x)).collect()
+}
+
+/// The method called `name` that `node` declares or inherits.
+pub fn find_method(arena: &Rc<NodeArena>, node: &NodeRef, name: &str) -> Result<Method, Error> {
+    for x in objs::method_table(arena, node.get_id())?.iter() {
+        if x.method.name() == name {
+            return Method::new(arena, x);
+        }
+    }
+
+    Err(Error::Attribute(name.to_string()))
+}
+
+/// Method `id` of interface `interface_id`, which is `node` or one of the
+/// interfaces it extends.
+pub fn method_by_id(arena: &Rc<NodeArena>, node: &NodeRef, interface_id: u64, id: u16) -> Result<Option<Method>, Error> {
+    for x in objs::method_table(arena, node.get_id())?.iter() {
+        if x.interface_id == interface_id && x.ordinal == id {
+            return Ok(Some(Method::new(arena, x)?));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Best-effort text of an error, for exceptions sent to the peer.
+pub fn describe(error: &Error) -> String {
+    match error {
+        Error::Capnp(x) => x.to_string(),
+        Error::Io(x) => x.to_string(),
+        Error::NotInSchema(x) => format!("not in schema: {}", x),
+        Error::Py(_) => "python exception".to_string(),
+        Error::Text(x) | Error::Type(x) | Error::Attribute(x) | Error::Value(x) | Error::Rpc(x) => x.clone(),
+    }
+}
+
+/// The exception a peer sees for an error raised while serving a call:
+/// `NotImplementedError` is unimplemented, `ConnectionError` disconnected,
+/// anything else failed.
+pub fn exception(py: Python, error: Error) -> Exception {
+    let error = match error {
+        Error::Py(x) => x,
+        x => return Exception::failed(describe(&x)),
+    };
+
+    let type_ = if error.is_instance::<exceptions::NotImplementedError>(py) {
+        protocol::exception::type_::UNIMPLEMENTED
+    } else if error.is_instance::<exceptions::ConnectionError>(py) {
+        protocol::exception::type_::DISCONNECTED
+    } else {
+        protocol::exception::type_::FAILED
+    };
+
+    let value = error.to_object(py);
+    let value = value.as_ref(py);
+    let text = value.str().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+
+    Exception { type_, reason: format!("{}: {}", value.get_type().name(), text) }
+}
+
+/// Stand-in answers for a mocked interface: each method returns its fixture,
+/// a dict of results or a callable taking the params reader, or else the
+/// default results. Every call is recorded with its params.
+pub struct Mock {
+    fixtures: HashMap<String, PyObject>,
+    calls: RefCell<Vec<(String, PyObject)>>,
+}
+
+impl Mock {
+    fn answer(&self, py: Python, method: &Method, params: PyObject) -> Result<PyObject, Error> {
+        self.calls.borrow_mut().push((method.name.clone(), params.clone_ref(py)));
+
+        Ok(match self.fixtures.get(&method.name) {
+            Some(x) if x.as_ref(py).is_callable() => x.call1(py, (params,))?,
+            Some(x) => x.clone_ref(py),
+            None => py.None(),
+        })
+    }
+}
+
+enum Implementation {
+    Object(PyObject),
+    Mock(Rc<Mock>),
+}
+
+/// An interface implemented in Python: each method of the schema is the
+/// object's attribute of the same name, called with the params reader.
+pub struct LocalCap {
+    node: NodeRef,
+    implementation: Implementation,
+    // returns of streaming calls held back while paused, see `ServerPy.pause`
+    held: RefCell<Option<Vec<(Weak<Connection>, Vec<u8>)>>>,
+}
+
+impl LocalCap {
+    fn new(node: NodeRef, implementation: Implementation) -> LocalCap {
+        LocalCap { node, implementation, held: RefCell::new(None) }
+    }
+
+    /// Keeps the `Return` of a streaming call for `resume` while paused,
+    /// otherwise gives it back to be sent.
+    fn hold(&self, conn: Weak<Connection>, message: Vec<u8>) -> Option<Vec<u8>> {
+        match *self.held.borrow_mut() {
+            Some(ref mut x) => {
+                x.push((conn, message));
+                None
+            }
+            None => Some(message),
+        }
+    }
+
+    fn resume(&self, py: Python) -> Result<(), Error> {
+        let held = self.held.borrow_mut().take().unwrap_or_default();
+
+        for (conn, message) in held {
+            // answers to connections that went away are dropped with them
+            match conn.upgrade() {
+                Some(ref x) if x.check_open().is_ok() => x.transport.send(py, message)?,
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn call(&self, py: Python, interface_id: u64, method_id: u16, params: &Rc<Payload>) -> Result<(Method, PyObject), Exception> {
+        let method = match method_by_id(&self.node.rc(), &self.node, interface_id, method_id) {
+            Ok(Some(x)) => x,
+            Ok(None) => return Err(Exception::unimplemented(
+                format!("method {} of interface 0x{:x} is not implemented", method_id, interface_id)
+            )),
+            Err(x) => return Err(exception(py, x)),
+        };
+
+        let function = match self.implementation {
+            Implementation::Object(ref x) => Some(x.getattr(py, method.name.as_str())
+                .map_err(|_| Exception::unimplemented(format!("{} is not implemented", method.name)))?),
+            Implementation::Mock(_) => None,
+        };
+
+        let inner = || -> Result<PyObject, Error> {
+            let reader: PyObject = Py::new(py, Payload::root(params, method.params.clone())?)?.into();
+
+            match (function, &self.implementation) {
+                (Some(x), _) => Ok(x.call1(py, (reader,))?),
+                (None, Implementation::Mock(x)) => x.answer(py, &method, reader),
+                (None, Implementation::Object(_)) => unreachable!(),
+            }
+        };
+
+        let value = inner().map_err(|x| exception(py, x))?;
+
+        Ok((method, value))
+    }
+}
+
+/// Writes what a method implementation returned as the method's results:
+/// None for none, a dict of fields, a struct reader or builder of the
+/// results type, or for results with a single field, that field's value.
+/// Interface fields take capabilities of the peer or `ServerPy` objects.
+fn write_results(method: &Method, ptr: PointerBuilder, value: &PyAny) -> Result<(), Error> {
+    let arena = method.results.rc();
+    let node = &method.results;
+
+    if let Some(source) = Source::from_py(value)? {
+        return match source {
+            Source::Struct(id, reader) if id == node.get_id() => Ok(ptr.set_struct(&reader, false)?),
+            _ => Err(Error::Type(format!("{} returned a value of the wrong type", method.name))),
+        };
+    }
+
+    let mut builder = ptr.init_struct(message::get_node_struct_size(node)?);
+
+    if value.is_none() {
+        return Ok(());
+    }
+
+    if let Ok(x) = value.downcast_ref::<PyDict>() {
+        for (k, v) in x.iter() {
+            dynamic::set_field(&arena, node, &mut builder, k.extract()?, v)?;
+        }
+
+        return Ok(());
+    }
+
+    let fields = dynamic::struct_node(node)?.get_fields()?;
+
+    if fields.len() != 1 {
+        return Err(Error::Type(format!("{} must return a dict of its results", method.name)));
+    }
+
+    dynamic::set_field(&arena, node, &mut builder, fields.get(0).get_name()?, value)
+}
+
+/// Writes the content of a payload with `fill` and then its cap table, with
+/// `describe` telling the peer what each capability written stands for.
+fn write_payload<F, D>(mut payload: StructBuilder, fill: F, describe: D) -> Result<(), Error>
+    where F: FnOnce(PointerBuilder) -> Result<(), Error>,
+          D: FnMut(&Entry) -> Result<CapDescriptor, Error>
+{
+    let table = CapTable::new(vec![]);
+    let mut content = payload.reborrow().get_pointer_field(protocol::payload::CONTENT);
+    table.imbue_builder(&mut content);
+
+    fill(content)?;
+
+    let caps = table.entries()?.iter().map(describe).collect::<Result<Vec<_>, Error>>()?;
+    protocol::write_cap_table(payload, &caps);
+
+    Ok(())
+}
+
+/// A `Return` of answer `id` whose results `fill` writes.
+fn build_results<F, D>(id: u32, fill: F, describe: D) -> Result<Vec<u8>, Error>
+    where F: FnOnce(PointerBuilder) -> Result<(), Error>,
+          D: FnMut(&Entry) -> Result<CapDescriptor, Error>
+{
+    use crate::protocol::return_;
+
+    protocol::build(M::RETURN, |ptr| {
+        let mut builder = ptr.init_struct(return_::SIZE);
+        builder.set_data_field::<u32>(return_::ANSWER_ID, id);
+        // param caps are released one by one as their imports drop, so the
+        // peer must not release them all with the return
+        builder.set_bool_field(return_::RELEASE_PARAM_CAPS, true);
+        builder.set_data_field::<u16>(return_::WHICH, return_::which::RESULTS);
+
+        let payload = builder.get_pointer_field(return_::BODY).init_struct(protocol::payload::SIZE);
+        write_payload(payload, fill, describe)
+    })
+}
+
+fn build_exception(id: u32, exception: &Exception) -> Result<Vec<u8>, Error> {
+    use crate::protocol::return_;
+
+    protocol::build(M::RETURN, |ptr| {
+        let mut builder = ptr.init_struct(return_::SIZE);
+        builder.set_data_field::<u32>(return_::ANSWER_ID, id);
+        builder.set_bool_field(return_::RELEASE_PARAM_CAPS, true);
+        builder.set_data_field::<u16>(return_::WHICH, return_::which::EXCEPTION);
+        exception.write(builder.reborrow().get_pointer_field(return_::BODY).init_struct(protocol::exception::SIZE));
+
+        Ok(())
+    })
+}
+
+/// A capability the peer exported to us. The peer counts how often it named
+/// it; the count is given back in one `Release` when the last handle drops.
+pub struct Import {
+    conn: Weak<Connection>,
+    id: u32,
+    count: Cell<u32>,
+}
+
+impl Drop for Import {
+    fn drop(&mut self) {
+        let conn = match self.conn.upgrade() {
+            Some(x) => x,
+            None => return,
+        };
+
+        let gil = GILGuard::acquire();
+        let (id, count) = (self.id, self.count.get());
+
+        // a closed connection has nothing left to release
+        let _ = conn.send(gil.python(), M::RELEASE, |ptr| {
+            let builder = ptr.init_struct(protocol::release::SIZE);
+            builder.set_data_field::<u32>(protocol::release::ID, id);
+            builder.set_data_field::<u32>(protocol::release::REFERENCE_COUNT, count);
+            Ok(())
+        });
+    }
+}
+
+/// Something calls can be addressed to.
+#[derive(Clone)]
+pub enum Cap {
+    Import(Rc<Import>),
+    /// The capability at `transform` in the results of a question, which
+    /// may not have come back yet.
+    Promised(Rc<QuestionRef>, Vec<u16>),
+    /// One of our own, as written into a message by a server.
+    Local(Rc<LocalCap>),
+}
+
+impl Cap {
+    fn target(&self) -> Result<Target, Error> {
+        match self {
+            Cap::Import(x) => Ok(Target::Import(x.id)),
+            Cap::Promised(x, transform) => Ok(Target::Answer(x.id, transform.clone())),
+            Cap::Local(_) => Err(Error::Rpc("a local capability is not called over a connection".into())),
+        }
+    }
+
+    fn connection(&self) -> Result<Rc<Connection>, Error> {
+        let conn = match self {
+            Cap::Import(x) => x.conn.upgrade(),
+            Cap::Promised(x, _) => x.conn.upgrade(),
+            Cap::Local(_) => None,
+        };
+
+        conn.ok_or(Error::Rpc("the capability has no connection".into()))
+    }
+}
+
+/// The server behind a `ServerPy` or `MockServerPy`.
+fn local_cap(value: &PyAny) -> Result<Rc<LocalCap>, Error> {
+    if let Ok(x) = value.downcast_ref::<ServerPy>() {
+        return Ok(x.i.clone());
+    }
+
+    if let Ok(x) = value.downcast_ref::<MockServerPy>() {
+        return Ok(x.i.clone());
+    }
+
+    Err(Error::Type(format!("expected a server, got {}", value.get_type().name())))
+}
+
+/// What a capability, server or placeholder written into a message stands
+/// for.
+pub fn entry_from_py(value: &PyAny) -> Result<Entry, Error> {
+    if let Ok(x) = value.downcast_ref::<CapabilityPy>() {
+        return Ok(Entry::Cap(x.cap.clone()));
+    }
+
+    if let Ok(x) = value.downcast_ref::<CapabilityPlaceholderPy>() {
+        return Ok(Entry::Index(x.index));
+    }
+
+    Ok(Entry::Cap(Cap::Local(local_cap(value)?)))
+}
+
+/// The Python side of a capability pointer read from a message: a
+/// capability of the peer typed as `node`, the server for one of ours, a
+/// placeholder for a message without a connection, or None.
+pub fn read_cap(py: Python, node: &NodeRef, ptr: &PointerReader) -> Result<PyObject, Error> {
+    Ok(match capability::read(ptr)? {
+        None | Some(Entry::Null) => py.None(),
+        Some(Entry::Cap(Cap::Local(x))) => Py::new(py, ServerPy { i: x })?.into(),
+        Some(Entry::Cap(x)) => Py::new(py, CapabilityPy { conn: x.connection()?, node: node.clone(), cap: x })?.into(),
+        Some(Entry::Index(index)) => Py::new(py, CapabilityPlaceholderPy { index })?.into(),
+    })
+}
+
+/// The params or results of a call as received: the message and the content
+/// pointer, imbued with a cap table of what the capabilities stand for on
+/// this side.
+pub struct Payload {
+    #[allow(dead_code)]
+    message: Rc<capnp::message::Reader<Frame>>,
+    #[allow(dead_code)]
+    table: Box<CapTable>,
+    pub content: PointerReader<'static>,
+}
+
+impl Payload {
+    /// The capability a pointer inside the content refers to.
+    pub fn cap(&self, ptr: &PointerReader) -> Result<Option<Cap>, Error> {
+        match capability::read(ptr)? {
+            Some(Entry::Cap(x)) => Ok(Some(x)),
+            Some(Entry::Index(_)) => Err(Error::Rpc("invalid capability pointer".into())),
+            None | Some(Entry::Null) => Ok(None),
+        }
+    }
+
+    /// The capability `transform` leads to from the content, as in a
+    /// promised answer.
+    fn walk(&self, transform: &[u16]) -> Result<Option<Cap>, Error> {
+        let mut ptr = self.content;
+
+        for x in transform {
+            if ptr.is_null() {
+                return Ok(None);
+            }
+
+            ptr = ptr.get_struct(None)?.get_pointer_field(*x as usize);
+        }
+
+        self.cap(&ptr)
+    }
+
+    pub fn root(this: &Rc<Payload>, node: NodeRef) -> Result<StructReaderPy, Error> {
+        dynamic::struct_node(&node)?;
+
+        Ok(StructReaderPy {
+            owner: Owner::Payload(this.clone()),
+            node,
+            reader: this.content.get_struct(None)?,
+        })
+    }
+}
+
+struct Question {
+    result: Option<Result<Rc<Payload>, Exception>>,
+    // Finish went out before the Return came in, which is then dropped
+    finished: bool,
+}
+
+/// Our side of a question; dropping it sends `Finish`.
+pub struct QuestionRef {
+    conn: Weak<Connection>,
+    pub id: u32,
+}
+
+impl Drop for QuestionRef {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.upgrade() {
+            conn.finish(self.id);
+        }
+    }
+}
+
+/// A capability we exported, with the number of times the peer was told
+/// about it and has not released yet.
+struct Export {
+    cap: Rc<LocalCap>,
+    count: u32,
+}
+
+/// What we returned for a call of the peer, kept until it finishes the
+/// question for calls pipelined on it, with the exports in the results.
+struct Answer {
+    result: Result<Rc<Payload>, Exception>,
+    exports: Vec<u32>,
+}
+
+#[derive(Default)]
+struct State {
+    next_question: u32,
+    questions: HashMap<u32, Question>,
+    imports: HashMap<u32, Weak<Import>>,
+    next_export: u32,
+    exports: HashMap<u32, Export>,
+    answers: HashMap<u32, Answer>,
+    closed: Option<Exception>,
+}
+
+pub struct Connection {
+    transport: Box<dyn Transport>,
+    state: RefCell<State>,
+    bootstrap: Option<Rc<LocalCap>>,
+    this: RefCell<Weak<Connection>>,
+}
+
+impl Connection {
+    pub fn new(transport: Box<dyn Transport>, bootstrap: Option<Rc<LocalCap>>) -> Rc<Connection> {
+        let r = Rc::new(Connection {
+            transport,
+            state: RefCell::new(State::default()),
+            bootstrap,
+            this: RefCell::new(Weak::new()),
+        });
+
+        *r.this.borrow_mut() = Rc::downgrade(&r);
+        r
+    }
+
+    fn weak(&self) -> Weak<Connection> {
+        self.this.borrow().clone()
+    }
+
+    /// Sends a message without looking at the connection state, so handles
+    /// may release what they hold at any time.
+    fn send<F>(&self, py: Python, which: u16, fill: F) -> Result<(), Error>
+        where F: FnOnce(PointerBuilder) -> Result<(), Error>
+    {
+        self.transport.send(py, protocol::build(which, fill)?)
+    }
+
+    fn check_open(&self) -> Result<(), Error> {
+        match self.state.borrow().closed {
+            Some(ref x) => Err(x.to_error()),
+            None => Ok(()),
+        }
+    }
+
+    fn ask(&self) -> Rc<QuestionRef> {
+        let mut state = self.state.borrow_mut();
+        let id = state.next_question;
+
+        state.next_question += 1;
+        state.questions.insert(id, Question { result: None, finished: false });
+
+        Rc::new(QuestionRef { conn: self.weak(), id })
+    }
+
+    fn answer(&self, id: u32) -> Option<Result<Rc<Payload>, Exception>> {
+        self.state.borrow().questions.get(&id).and_then(|x| x.result.clone())
+    }
+
+    pub fn bootstrap(&self, py: Python) -> Result<Cap, Error> {
+        self.check_open()?;
+
+        let question = self.ask();
+
+        self.send(py, M::BOOTSTRAP, |ptr| {
+            let builder = ptr.init_struct(protocol::bootstrap::SIZE);
+            builder.set_data_field::<u32>(protocol::bootstrap::QUESTION_ID, question.id);
+            Ok(())
+        })?;
+
+        let payload = self.wait(py, &question)?;
+
+        payload.cap(&payload.content)?.ok_or(Error::Rpc("the peer offers no bootstrap capability".into()))
+    }
+
+    /// How the peer is told about a capability we write into a message.
+    fn describe(&self, entry: &Entry) -> Result<CapDescriptor, Error> {
+        match entry {
+            Entry::Null => Ok(CapDescriptor::None),
+            Entry::Index(_) => Err(Error::Rpc("capability placeholders cannot be sent".into())),
+            Entry::Cap(Cap::Local(x)) => Ok(CapDescriptor::SenderHosted(self.export(x))),
+            Entry::Cap(x) => {
+                if &*x.connection()? as *const Connection != self as *const Connection {
+                    return Err(Error::Rpc("a capability of another connection".into()));
+                }
+
+                Ok(match x.target()? {
+                    Target::Import(x) => CapDescriptor::ReceiverHosted(x),
+                    Target::Answer(x, transform) => CapDescriptor::ReceiverAnswer(x, transform),
+                })
+            }
+        }
+    }
+
+    pub fn call(&self, py: Python, cap: &Cap, method: &Method, params: &StructReader) -> Result<Rc<QuestionRef>, Error> {
+        self.check_open()?;
+
+        let question = self.ask();
+
+        self.send(py, M::CALL, |ptr| {
+            use crate::protocol::call;
+
+            let mut builder = ptr.init_struct(call::SIZE);
+            builder.set_data_field::<u32>(call::QUESTION_ID, question.id);
+            builder.set_data_field::<u64>(call::INTERFACE_ID, method.interface_id);
+            builder.set_data_field::<u16>(call::METHOD_ID, method.id);
+            cap.target()?.write(builder.reborrow().get_pointer_field(call::TARGET));
+
+            let payload = builder.get_pointer_field(call::PARAMS).init_struct(protocol::payload::SIZE);
+            write_payload(payload, |ptr| Ok(ptr.set_struct(params, false)?), |x| self.describe(x))
+        })?;
+
+        Ok(question)
+    }
+
+    /// Handles incoming messages until `question` is answered.
+    pub fn wait(&self, py: Python, question: &QuestionRef) -> Result<Rc<Payload>, Error> {
+        loop {
+            if let Some(x) = self.answer(question.id) {
+                return x.map_err(|x| x.to_error());
+            }
+
+            self.check_open()?;
+            self.step(py)?;
+        }
+    }
+
+    /// Handles incoming messages until the peer closes the connection.
+    pub fn run(&self, py: Python) -> Result<(), Error> {
+        while self.state.borrow().closed.is_none() {
+            self.step(py)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads and handles one message.
+    pub fn step(&self, py: Python) -> Result<(), Error> {
+        let frame = match self.transport.receive(py) {
+            Ok(Some(x)) => x,
+            Ok(None) => {
+                self.shutdown(Exception::disconnected("the peer closed the connection".into()));
+                return Ok(());
+            }
+            Err(x) => {
+                self.shutdown(Exception::disconnected(describe(&x)));
+                return Err(x);
+            }
+        };
+
+        if let Err(x) = self.handle(py, frame) {
+            self.abort(py, Exception::failed(describe(&x)));
+            return Err(x);
+        }
+
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        self.shutdown(Exception::disconnected("the connection was closed".into()));
+    }
+
+    fn abort(&self, py: Python, exception: Exception) {
+        let _ = self.send(py, M::ABORT, |ptr| {
+            exception.write(ptr.init_struct(protocol::exception::SIZE));
+            Ok(())
+        });
+
+        self.shutdown(exception);
+    }
+
+    fn shutdown(&self, exception: Exception) {
+        {
+            let mut state = self.state.borrow_mut();
+
+            if state.closed.is_some() {
+                return;
+            }
+
+            for x in state.questions.values_mut() {
+                if x.result.is_none() {
+                    x.result = Some(Err(exception.clone()));
+                }
+            }
+
+            state.closed = Some(exception);
+        }
+
+        self.transport.close();
+    }
+
+    fn finish(&self, id: u32) {
+        {
+            let mut state = match self.state.try_borrow_mut() {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+
+            if state.closed.is_some() {
+                state.questions.remove(&id);
+                return;
+            }
+
+            match state.questions.get_mut(&id) {
+                Some(x) if x.result.is_none() => x.finished = true,
+                Some(_) => {
+                    state.questions.remove(&id);
+                }
+                None => return,
+            }
+        }
+
+        let gil = GILGuard::acquire();
+
+        let _ = self.send(gil.python(), M::FINISH, |ptr| {
+            let builder = ptr.init_struct(protocol::finish::SIZE);
+            builder.set_data_field::<u32>(protocol::finish::QUESTION_ID, id);
+            // result caps were counted as imports and are released one by one
+            builder.set_bool_field(protocol::finish::RELEASE_RESULT_CAPS, true);
+            Ok(())
+        });
+    }
+
+    fn import(&self, id: u32) -> Rc<Import> {
+        let mut state = self.state.borrow_mut();
+
+        if let Some(x) = state.imports.get(&id).and_then(|x| x.upgrade()) {
+            x.count.set(x.count.get() + 1);
+            return x;
+        }
+
+        let r = Rc::new(Import { conn: self.weak(), id, count: Cell::new(1) });
+        state.imports.insert(id, Rc::downgrade(&r));
+        r
+    }
+
+    fn export(&self, cap: &Rc<LocalCap>) -> u32 {
+        let mut state = self.state.borrow_mut();
+
+        if let Some((id, x)) = state.exports.iter_mut().find(|(_, x)| Rc::ptr_eq(&x.cap, cap)) {
+            x.count += 1;
+            return *id;
+        }
+
+        let id = state.next_export;
+        state.next_export += 1;
+        state.exports.insert(id, Export { cap: cap.clone(), count: 1 });
+
+        id
+    }
+
+    fn release(&self, id: u32, count: u32) -> Result<(), Error> {
+        let released = {
+            let mut state = self.state.borrow_mut();
+
+            let x = state.exports.get_mut(&id).ok_or(Error::Rpc(format!("release of unknown export {}", id)))?;
+            x.count = x.count.saturating_sub(count);
+
+            if x.count == 0 { state.exports.remove(&id) } else { None }
+        };
+
+        // the object may run arbitrary code when it goes, outside the borrow
+        drop(released);
+
+        Ok(())
+    }
+
+    fn receive_cap(&self, descriptor: &CapDescriptor) -> Result<Option<Cap>, Error> {
+        match descriptor {
+            CapDescriptor::None => Ok(None),
+            CapDescriptor::SenderHosted(x) | CapDescriptor::SenderPromise(x) => Ok(Some(Cap::Import(self.import(*x)))),
+            CapDescriptor::ReceiverHosted(x) => match self.state.borrow().exports.get(x) {
+                Some(x) => Ok(Some(Cap::Local(x.cap.clone()))),
+                None => Err(Error::Rpc(format!("the peer named unknown export {}", x))),
+            },
+            CapDescriptor::ReceiverAnswer(x, transform) => self.answered_cap(*x, transform),
+            x => Err(Error::Rpc(format!("unsupported capability descriptor {:?}", x))),
+        }
+    }
+
+    /// The capability at `transform` in our answer to question `id` of the
+    /// peer.
+    fn answered_cap(&self, id: u32, transform: &[u16]) -> Result<Option<Cap>, Error> {
+        let result = match self.state.borrow().answers.get(&id) {
+            Some(x) => x.result.clone(),
+            None => return Err(Error::Rpc(format!("the peer named unknown answer {}", id))),
+        };
+
+        match result {
+            Ok(x) => x.walk(transform),
+            Err(x) => Err(x.to_error()),
+        }
+    }
+
+    fn payload(&self, message: &Rc<capnp::message::Reader<Frame>>, reader: &StructReader) -> Result<Payload, Error> {
+        let mut caps = Vec::new();
+
+        for x in protocol::read_cap_table(reader)? {
+            caps.push(self.receive_cap(&x)?.map(Entry::Cap).unwrap_or(Entry::Null));
+        }
+
+        let table = CapTable::new(caps);
+
+        // the reader borrows from `message`, which the payload keeps alive
+        let mut content = unsafe {
+            dynamic::detach_pointer_reader(reader.get_pointer_field(protocol::payload::CONTENT))
+        };
+        table.imbue_reader(&mut content);
+
+        Ok(Payload { message: message.clone(), table, content })
+    }
+
+    fn handle(&self, py: Python, frame: Frame) -> Result<(), Error> {
+        let message = Rc::new(frame.into_reader(capnp::message::ReaderOptions::new()));
+
+        // copying a capability out of a message without a cap table fails
+        // instead of dereferencing a null table
+        let empty = CapTable::new(vec![]);
+        let root: RootPointer = message.get_root()?;
+        let mut root = root.0;
+        empty.imbue_reader(&mut root);
+
+        let (which, body) = protocol::open(root)?;
+
+        match which {
+            M::ABORT => {
+                let exception = Exception::read(&body.get_struct(None)?)?;
+                self.shutdown(exception.clone());
+                Err(exception.to_error())
+            }
+            M::RETURN => self.handle_return(&message, &body.get_struct(None)?),
+            M::UNIMPLEMENTED => self.handle_unimplemented(body),
+            M::BOOTSTRAP => {
+                let id = body.get_struct(None)?.get_data_field::<u32>(protocol::bootstrap::QUESTION_ID);
+                self.handle_bootstrap(py, id)
+            }
+            M::CALL => self.handle_call(py, &message, &body.get_struct(None)?),
+            M::RESOLVE => {
+                let resolve = body.get_struct(None)?;
+
+                // imports are used as they were named, the resolution is
+                // taken and given straight back
+                if resolve.get_data_field::<u16>(protocol::resolve::WHICH) == protocol::resolve::which::CAP {
+                    let descriptor = resolve.get_pointer_field(protocol::resolve::BODY).get_struct(None)?;
+                    self.receive_cap(&CapDescriptor::read(&descriptor)?)?;
+                }
+
+                Ok(())
+            }
+            M::RELEASE => {
+                let release = body.get_struct(None)?;
+
+                self.release(
+                    release.get_data_field::<u32>(protocol::release::ID),
+                    release.get_data_field::<u32>(protocol::release::REFERENCE_COUNT),
+                )
+            }
+            M::FINISH => {
+                let finish = body.get_struct(None)?;
+
+                self.handle_finish(
+                    finish.get_data_field::<u32>(protocol::finish::QUESTION_ID),
+                    !finish.get_bool_field(protocol::finish::RELEASE_RESULT_CAPS),
+                )
+            }
+            _ => {
+                self.send(py, M::UNIMPLEMENTED, |ptr| {
+                    ptr.set_struct(&root.get_struct(None)?, false)?;
+                    Ok(())
+                })
+            }
+        }
+    }
+
+    fn handle_return(&self, message: &Rc<capnp::message::Reader<Frame>>, reader: &StructReader) -> Result<(), Error> {
+        use crate::protocol::return_::{self, which as W};
+
+        let id = reader.get_data_field::<u32>(return_::ANSWER_ID);
+        let body = reader.get_pointer_field(return_::BODY);
+
+        let result = match reader.get_data_field::<u16>(return_::WHICH) {
+            W::RESULTS => Ok(Rc::new(self.payload(message, &body.get_struct(None)?)?)),
+            W::EXCEPTION => Err(Exception::read(&body.get_struct(None)?)?),
+            W::CANCELED => Err(Exception::failed("the call was canceled".into())),
+            x => Err(Exception::unimplemented(format!("unsupported return kind {}", x))),
+        };
+
+        self.settle(id, result)
+    }
+
+    fn settle(&self, id: u32, result: Result<Rc<Payload>, Exception>) -> Result<(), Error> {
+        let mut state = self.state.borrow_mut();
+
+        let finished = match state.questions.get_mut(&id) {
+            Some(x) if x.finished => true,
+            Some(x) => {
+                x.result = Some(result);
+                false
+            }
+            None => return Err(Error::Rpc(format!("return for unknown question {}", id))),
+        };
+
+        if finished {
+            state.questions.remove(&id);
+        }
+
+        Ok(())
+    }
+
+    /// The peer did not understand one of our messages; a question it
+    /// carried fails.
+    fn handle_unimplemented(&self, body: PointerReader) -> Result<(), Error> {
+        let (which, inner) = protocol::open(body)?;
+
+        let id = match which {
+            M::BOOTSTRAP => inner.get_struct(None)?.get_data_field::<u32>(protocol::bootstrap::QUESTION_ID),
+            M::CALL => inner.get_struct(None)?.get_data_field::<u32>(protocol::call::QUESTION_ID),
+            _ => return Ok(()),
+        };
+
+        self.settle(id, Err(Exception::unimplemented("the peer does not implement this message".into())))
+    }
+
+    /// The peer is done with an answer; with `release` it no longer holds
+    /// the capabilities in the results either.
+    fn handle_finish(&self, id: u32, release: bool) -> Result<(), Error> {
+        let answer = self.state.borrow_mut().answers.remove(&id);
+
+        if let (Some(answer), true) = (answer, release) {
+            for x in answer.exports {
+                self.release(x, 1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_bootstrap(&self, py: Python, id: u32) -> Result<(), Error> {
+        let result = match self.bootstrap {
+            Some(ref cap) => Ok(Cap::Local(cap.clone())),
+            None => Err(Exception::failed("this side offers no bootstrap capability".into())),
+        };
+
+        self.respond(py, id, result.map(|cap| move |ptr: PointerBuilder| {
+            capability::write(&ptr, Entry::Cap(cap));
+            Ok(())
+        }), None)
+    }
+
+    /// Answers question `id` with results `fill` writes, or with an
+    /// exception, and keeps the answer for pipelined calls. The `Return`
+    /// goes out unless `held_by`, a paused server, holds it back.
+    fn respond<F>(&self, py: Python, id: u32, result: Result<F, Exception>, held_by: Option<&LocalCap>) -> Result<(), Error>
+        where F: FnOnce(PointerBuilder) -> Result<(), Error>
+    {
+        let mut exports = Vec::new();
+
+        let built = result.and_then(|fill| {
+            let describe = |x: &Entry| -> Result<CapDescriptor, Error> {
+                let r = self.describe(x)?;
+
+                if let CapDescriptor::SenderHosted(x) = r {
+                    exports.push(x);
+                }
+
+                Ok(r)
+            };
+
+            build_results(id, fill, describe).map_err(|x| exception(py, x))
+        });
+
+        let (message, result) = match built {
+            Ok(x) => {
+                let payload = self.own_payload(&x)?;
+                (x, Ok(payload))
+            }
+            Err(x) => {
+                // exports of results that could not be sent are not held
+                for x in exports.drain(..) {
+                    self.release(x, 1)?;
+                }
+
+                (build_exception(id, &x)?, Err(x))
+            }
+        };
+
+        self.state.borrow_mut().answers.insert(id, Answer { result, exports });
+
+        let message = match held_by {
+            Some(x) => x.hold(self.weak(), message),
+            None => Some(message),
+        };
+
+        match message {
+            Some(x) => self.transport.send(py, x),
+            None => Ok(()),
+        }
+    }
+
+    /// The results of one of our own `Return` messages, read back to
+    /// resolve calls pipelined on them.
+    fn own_payload(&self, message: &[u8]) -> Result<Rc<Payload>, Error> {
+        let message = Rc::new(Frame::from_bytes(message)?.into_reader(capnp::message::ReaderOptions::new()));
+
+        let root: RootPointer = message.get_root()?;
+        let (_, body) = protocol::open(root.0)?;
+        let results = body.get_struct(None)?.get_pointer_field(protocol::return_::BODY).get_struct(None)?;
+
+        // descriptors name our exports, which stand for themselves here
+        let mut caps = Vec::new();
+
+        for x in protocol::read_cap_table(&results)? {
+            caps.push(match x {
+                CapDescriptor::SenderHosted(x) => match self.state.borrow().exports.get(&x) {
+                    Some(x) => Entry::Cap(Cap::Local(x.cap.clone())),
+                    None => Entry::Null,
+                },
+                CapDescriptor::ReceiverHosted(x) => match self.state.borrow().imports.get(&x).and_then(|x| x.upgrade()) {
+                    Some(x) => Entry::Cap(Cap::Import(x)),
+                    None => Entry::Null,
+                },
+                _ => Entry::Null,
+            });
+        }
+
+        let table = CapTable::new(caps);
+        let mut content = unsafe {
+            dynamic::detach_pointer_reader(results.get_pointer_field(protocol::payload::CONTENT))
+        };
+        table.imbue_reader(&mut content);
+
+        Ok(Rc::new(Payload { message, table, content }))
+    }
+
+    /// Runs the implementation of a call and answers with its results, or
+    /// with the exception it raised.
+    fn handle_call(&self, py: Python, message: &Rc<capnp::message::Reader<Frame>>, reader: &StructReader) -> Result<(), Error> {
+        use crate::protocol::call;
+
+        let id = reader.get_data_field::<u32>(call::QUESTION_ID);
+        let interface_id = reader.get_data_field::<u64>(call::INTERFACE_ID);
+        let method_id = reader.get_data_field::<u16>(call::METHOD_ID);
+        let target = Target::read(&reader.get_pointer_field(call::TARGET).get_struct(None)?)?;
+        let params = Rc::new(self.payload(message, &reader.get_pointer_field(call::PARAMS).get_struct(None)?)?);
+
+        let server = match target {
+            Target::Import(x) => Ok(self.state.borrow().exports.get(&x).map(|x| Cap::Local(x.cap.clone()))),
+            Target::Answer(x, ref transform) => self.answered_cap(x, transform).map_err(|x| exception(py, x)),
+        };
+
+        let (result, held_by) = match server {
+            Ok(Some(Cap::Local(x))) => {
+                let result = x.call(py, interface_id, method_id, &params);
+
+                // only the returns of streaming calls are held back
+                match result {
+                    Ok((ref method, _)) if method.is_streaming() => (result, Some(x)),
+                    _ => (result, None),
+                }
+            }
+            Ok(_) => (Err(Exception::failed(format!("call to an unknown capability {:?}", target))), None),
+            Err(x) => (Err(x), None),
+        };
+
+        self.respond(py, id, result.map(|(method, value)| move |ptr: PointerBuilder| {
+            write_results(&method, ptr, value.as_ref(py))
+        }), held_by.as_ref().map(|x| &**x))
+    }
+}
+
+/// A method of an interface's complete method table, see `NodePy.methods`.
+#[pyclass]
+pub struct MethodPy {
+    pub i: Method,
+}
+
+#[pymethods]
+impl MethodPy {
+    #[getter]
+    fn name(&self) -> PyResult<String> {
+        Ok(self.i.name.clone())
+    }
+
+    /// Id of the interface declaring the method, inherited or not.
+    #[getter]
+    fn interface_id(&self) -> PyResult<u64> {
+        Ok(self.i.interface_id)
+    }
+
+    /// Position of the method in the declaring interface.
+    #[getter]
+    fn ordinal(&self) -> PyResult<u16> {
+        Ok(self.i.id)
+    }
+
+    /// What the generic parameters of the declaring interface are bound to,
+    /// by name, e.g. `{'T': 'Data'}`; unbound parameters are left out.
+    #[getter]
+    fn bindings(&self) -> PyResult<HashMap<String, String>> {
+        let inner = |this: &Self| -> Result<HashMap<String, String>, Error> {
+            let arena = this.i.params.rc();
+            let node = dynamic::get_node(&arena, this.i.interface_id)?;
+            let mut r = HashMap::new();
+
+            for (i, x) in node.get_parameters()?.iter().enumerate() {
+                if let Some(type_) = this.i.brand.binding(this.i.interface_id, i as u16) {
+                    r.insert(x.get_name()?.to_string(), type_.render(&arena, &[])?);
+                }
+            }
+
+            Ok(r)
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// The struct node of the params, to build them with `new_message`.
+    #[getter]
+    fn params(&self) -> PyResult<NodePy> {
+        Ok(NodePy { i: NodeInner { arena: self.i.params.rc(), id: self.i.params.get_id(), nested: Vec::new() } })
+    }
+
+    #[getter]
+    fn results(&self) -> PyResult<NodePy> {
+        Ok(NodePy { i: NodeInner { arena: self.i.results.rc(), id: self.i.results.get_id(), nested: Vec::new() } })
+    }
+
+    /// Type of the params as written in the schema, with the bindings of
+    /// the declaring interface applied, e.g. `TestGenerics(T, U)`.
+    #[getter]
+    fn param_type(&self) -> PyResult<String> {
+        let type_ = self.i.schema.params(&self.i.brand);
+        Ok(type_.render(&self.i.params.rc(), self.i.schema.implicit_parameters())?)
+    }
+
+    #[getter]
+    fn result_type(&self) -> PyResult<String> {
+        let type_ = self.i.schema.results(&self.i.brand);
+        Ok(type_.render(&self.i.results.rc(), self.i.schema.implicit_parameters())?)
+    }
+
+    /// Whether the method is declared `-> stream`, see `CapabilityPy.stream`.
+    #[getter]
+    fn streaming(&self) -> PyResult<bool> {
+        Ok(self.i.is_streaming())
+    }
+
+    /// Names of the method's own type parameters, see
+    /// `objs::Method::implicit_parameters` for how they are bound.
+    #[getter]
+    fn implicit_parameters(&self) -> PyResult<Vec<String>> {
+        Ok(self.i.schema.implicit_parameters().iter().map(|x| x.name().to_string()).collect())
+    }
+}
+
+#[pyclass]
+pub struct ConnectionPy {
+    pub i: Rc<Connection>,
+}
+
+#[pymethods]
+impl ConnectionPy {
+    /// The capability the peer offers as its bootstrap interface, typed as
+    /// the interface `node`.
+    fn bootstrap(&self, py: Python, node: &NodePy) -> PyResult<CapabilityPy> {
+        let inner = |this: &Self| -> Result<CapabilityPy, Error> {
+            let node = dynamic::node_ref(node)?;
+            dynamic::interface_node(&node)?;
+
+            Ok(CapabilityPy { conn: this.i.clone(), node, cap: this.i.bootstrap(py)? })
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// Handles incoming messages until the peer closes the connection.
+    fn run(&self, py: Python) -> PyResult<()> {
+        Ok(self.i.run(py)?)
+    }
+
+    fn close(&self) -> PyResult<()> {
+        self.i.close();
+        Ok(())
+    }
+
+    #[getter]
+    fn closed(&self) -> PyResult<bool> {
+        Ok(self.i.state.borrow().closed.is_some())
+    }
+}
+
+/// A capability of the peer, typed as an interface node.
+#[pyclass]
+pub struct CapabilityPy {
+    conn: Rc<Connection>,
+    node: NodeRef,
+    cap: Cap,
+}
+
+#[pymethods]
+impl CapabilityPy {
+    /// A call of method `name` whose params are filled in through `params`
+    /// before `send()`.
+    fn request(&self, name: &str) -> PyResult<RequestPy> {
+        let inner = |this: &Self| -> Result<RequestPy, Error> {
+            let arena = this.node.rc();
+            let method = find_method(&arena, &this.node, name)?;
+
+            let mut params = message::Builder::new(method.params.get_id(), arena, &message::BuilderOptions::new());
+            params.init_root();
+
+            Ok(RequestPy {
+                conn: this.conn.clone(),
+                cap: this.cap.clone(),
+                method,
+                params: Rc::new(RefCell::new(params)),
+            })
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// Sends a call of method `name` with params set from keyword arguments.
+    #[args(kwargs = "**")]
+    fn call(&self, py: Python, name: &str, kwargs: Option<&PyDict>) -> PyResult<PromisePy> {
+        let request = self.request(name)?;
+
+        let inner = |request: &RequestPy| -> Result<(), Error> {
+            let arena = self.node.rc();
+            let mut message = request.params.borrow_mut();
+            let mut root = message.get_root()?;
+
+            for (k, v) in kwargs.iter().flat_map(|x| x.iter()) {
+                dynamic::set_field(&arena, &request.method.params, &mut root, k.extract()?, v)?;
+            }
+
+            Ok(())
+        };
+
+        inner(&request)?;
+        request.send(py)
+    }
+
+    /// Calls of the streaming method `name`, sent through a window of
+    /// `window` bytes of params, see `StreamPy`.
+    #[args(window = 65536)]
+    fn stream(&self, name: &str, window: usize) -> PyResult<StreamPy> {
+        let inner = |this: &Self| -> Result<StreamPy, Error> {
+            let method = find_method(&this.node.rc(), &this.node, name)?;
+
+            if !method.is_streaming() {
+                return Err(Error::Type(format!("{} is not a streaming method", name)));
+            }
+
+            Ok(StreamPy {
+                conn: this.conn.clone(),
+                cap: this.cap.clone(),
+                method,
+                window,
+                in_flight: VecDeque::new(),
+                failed: None,
+            })
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+}
+
+/// Calls of a streaming method. `send()` returns as soon as the call is
+/// out, unless the params of the calls not acknowledged yet take up the
+/// whole window; then it handles incoming messages until returns make room.
+/// The first call that fails breaks the stream: it is raised by the next
+/// `send()` or `wait()`, and so is every call after it.
+#[pyclass]
+pub struct StreamPy {
+    conn: Rc<Connection>,
+    cap: Cap,
+    method: Method,
+    window: usize,
+    // calls not acknowledged yet, oldest first, with the size of their params
+    in_flight: VecDeque<(Rc<QuestionRef>, usize)>,
+    failed: Option<Exception>,
+}
+
+impl StreamPy {
+    /// Forgets the calls that returned and raises the first failure.
+    fn settle(&mut self) -> Result<(), Error> {
+        let conn = &self.conn;
+        let failed = &mut self.failed;
+
+        self.in_flight.retain(|(question, _)| match conn.answer(question.id) {
+            None => true,
+            Some(Ok(_)) => false,
+            Some(Err(x)) => {
+                failed.get_or_insert(x);
+                false
+            }
+        });
+
+        match self.failed {
+            Some(ref x) => Err(x.to_error()),
+            None => Ok(()),
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.in_flight.iter().map(|x| x.1).sum()
+    }
+
+    /// Handles incoming messages until fewer than `limit` bytes are in
+    /// flight, or none at all.
+    fn drain(&mut self, py: Python, limit: usize) -> Result<(), Error> {
+        loop {
+            self.settle()?;
+
+            if self.in_flight.is_empty() || self.bytes() < limit {
+                return Ok(());
+            }
+
+            self.conn.check_open()?;
+            self.conn.step(py)?;
+        }
+    }
+}
+
+#[pymethods]
+impl StreamPy {
+    /// Sends a call with params set from keyword arguments, once the window
+    /// has room for it.
+    #[args(kwargs = "**")]
+    fn send(&mut self, py: Python, kwargs: Option<&PyDict>) -> PyResult<()> {
+        let inner = |this: &mut Self| -> Result<(), Error> {
+            this.drain(py, this.window)?;
+
+            let arena = this.method.params.rc();
+            let mut params = message::Builder::new(this.method.params.get_id(), arena.clone(), &message::BuilderOptions::new());
+            let mut root = params.init_root();
+
+            for (k, v) in kwargs.iter().flat_map(|x| x.iter()) {
+                dynamic::set_field(&arena, &this.method.params, &mut root, k.extract()?, v)?;
+            }
+
+            let question = this.conn.call(py, &this.cap, &this.method, &root.as_reader())?;
+            this.in_flight.push_back((question, params.to_bytes().len()));
+
+            Ok(())
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// Handles incoming messages until every call sent has returned.
+    fn wait(&mut self, py: Python) -> PyResult<()> {
+        Ok(self.drain(py, 0)?)
+    }
+
+    #[getter]
+    fn window(&self) -> PyResult<usize> {
+        Ok(self.window)
+    }
+
+    /// Number of calls sent and not known to have returned.
+    #[getter]
+    fn pending(&mut self) -> PyResult<usize> {
+        self.settle()?;
+        Ok(self.in_flight.len())
+    }
+}
+
+#[pyclass]
+pub struct RequestPy {
+    conn: Rc<Connection>,
+    cap: Cap,
+    method: Method,
+    params: MessageRc,
+}
+
+#[pymethods]
+impl RequestPy {
+    #[getter]
+    fn params(&self) -> PyResult<StructBuilderPy> {
+        Ok(StructBuilderPy::new_root(&self.params)?)
+    }
+
+    fn send(&self, py: Python) -> PyResult<PromisePy> {
+        let inner = |this: &Self| -> Result<PromisePy, Error> {
+            let mut params = this.params.borrow_mut();
+            let root = params.get_root()?;
+            let question = this.conn.call(py, &this.cap, &this.method, &root.as_reader())?;
+
+            Ok(PromisePy { conn: this.conn.clone(), question, results: this.method.results.clone() })
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+}
+
+/// The answer to a call, once it arrives.
+#[pyclass]
+pub struct PromisePy {
+    conn: Rc<Connection>,
+    question: Rc<QuestionRef>,
+    results: NodeRef,
+}
+
+#[pymethods]
+impl PromisePy {
+    /// Handles incoming messages until the answer arrives; returns the
+    /// results or raises `RpcError`.
+    fn wait(&self, py: Python) -> PyResult<StructReaderPy> {
+        let inner = |this: &Self| -> Result<StructReaderPy, Error> {
+            let payload = this.conn.wait(py, &this.question)?;
+            Payload::root(&payload, this.results.clone())
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+
+    /// The results as they will be, for calling the capabilities in them
+    /// before they arrive.
+    #[getter]
+    fn pipeline(&self) -> PyResult<PipelinePy> {
+        Ok(PipelinePy {
+            conn: self.conn.clone(),
+            question: self.question.clone(),
+            node: self.results.clone(),
+            transform: vec![],
+        })
+    }
+}
+
+/// A struct inside the results of a pending call. Its struct fields are
+/// pipelines in turn, its interface fields capabilities that can be called
+/// right away.
+#[pyclass]
+pub struct PipelinePy {
+    conn: Rc<Connection>,
+    question: Rc<QuestionRef>,
+    node: NodeRef,
+    transform: Vec<u16>,
+}
+
+#[pymethods]
+impl PipelinePy {
+    fn get(&self, py: Python, name: &str) -> PyResult<PyObject> {
+        use capnpc::schema_capnp::{field, type_ as T};
+
+        let inner = |this: &Self| -> Result<PyObject, Error> {
+            let arena = this.node.rc();
+            let field = dynamic::find_field(&this.node, name)?;
+
+            let slot = match field.which()? {
+                field::Group(x) => return Ok(Py::new(py, PipelinePy {
+                    conn: this.conn.clone(),
+                    question: this.question.clone(),
+                    node: dynamic::get_node(&arena, x.get_type_id())?,
+                    transform: this.transform.clone(),
+                })?.into()),
+                field::Slot(x) => x,
+            };
+
+            let mut transform = this.transform.clone();
+            transform.push(slot.get_offset() as u16);
+
+            Ok(match slot.get_type()?.which()? {
+                T::Struct(x) => Py::new(py, PipelinePy {
+                    conn: this.conn.clone(),
+                    question: this.question.clone(),
+                    node: dynamic::get_node(&arena, x.get_type_id())?,
+                    transform,
+                })?.into(),
+                T::Interface(x) => Py::new(py, CapabilityPy {
+                    conn: this.conn.clone(),
+                    node: dynamic::get_node(&arena, x.get_type_id())?,
+                    cap: Cap::Promised(this.question.clone(), transform),
+                })?.into(),
+                _ => return Err(Error::Type(format!("{} is neither a struct nor an interface, it cannot be pipelined", name))),
+            })
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+}
+
+#[pyclass]
+pub struct ConnectFun {}
+
+#[pymethods]
+impl ConnectFun {
+    /// Connects to a two-party RPC peer listening on a Unix socket; the peer
+    /// may bootstrap to `bootstrap` in turn.
+    #[call]
+    #[args(bootstrap = "None")]
+    fn connect(&self, path: &str, bootstrap: Option<&PyAny>) -> PyResult<ConnectionPy> {
+        let inner = || -> Result<ConnectionPy, Error> {
+            let bootstrap = bootstrap.map(local_cap).transpose()?;
+            let transport = StreamTransport::from_socket(UnixStream::connect(path)?)?;
+            Ok(ConnectionPy { i: Connection::new(Box::new(transport), bootstrap) })
+        };
+
+        inner().map_err(PyErr::from)
+    }
+}
+
+#[pyclass]
+pub struct ConnectFdsFun {}
+
+#[pymethods]
+impl ConnectFdsFun {
+    /// Speaks two-party RPC over a pair of file descriptors, e.g. pipes or
+    /// one end of `socket.socketpair()` twice. The descriptors are
+    /// duplicated, the caller still closes its own.
+    #[call]
+    #[args(bootstrap = "None")]
+    fn connect_fds(&self, read_fd: RawFd, write_fd: RawFd, bootstrap: Option<&PyAny>) -> PyResult<ConnectionPy> {
+        let inner = || -> Result<ConnectionPy, Error> {
+            let bootstrap = bootstrap.map(local_cap).transpose()?;
+            let transport = StreamTransport::from_fds(read_fd, write_fd)?;
+            Ok(ConnectionPy { i: Connection::new(Box::new(transport), bootstrap) })
+        };
+
+        inner().map_err(PyErr::from)
+    }
+}
+
+/// A connection over end `side` of `link`.
+fn loopback_end(link: &Rc<RefCell<Link>>, side: usize, bootstrap: Option<Rc<LocalCap>>) -> Rc<Connection> {
+    let this: Rc<RefCell<Weak<Connection>>> = Rc::new(RefCell::new(Weak::new()));
+
+    let pump: Pump = {
+        let this = this.clone();
+
+        Rc::new(move |py: Python| {
+            let conn = this.borrow().upgrade();
+
+            match conn {
+                Some(x) => x.step(py),
+                None => Err(Error::Rpc("connection is closed".into())),
+            }
+        })
+    };
+
+    let r = Connection::new(Box::new(Link::end(link, side, pump)), bootstrap);
+    *this.borrow_mut() = Rc::downgrade(&r);
+
+    r
+}
+
+/// A client and a server connected in-process; see `loopback_pair`.
+#[pyclass]
+pub struct LoopbackPy {
+    link: Rc<RefCell<Link>>,
+    client: Rc<Connection>,
+    server: Rc<Connection>,
+}
+
+#[pymethods]
+impl LoopbackPy {
+    #[getter]
+    fn client(&self) -> PyResult<ConnectionPy> {
+        Ok(ConnectionPy { i: self.client.clone() })
+    }
+
+    #[getter]
+    fn server(&self) -> PyResult<ConnectionPy> {
+        Ok(ConnectionPy { i: self.server.clone() })
+    }
+
+    /// Simulated seconds since the pair was made.
+    #[getter]
+    fn clock(&self) -> PyResult<f64> {
+        Ok(self.link.borrow().now)
+    }
+
+    #[getter]
+    fn delivered(&self) -> PyResult<u64> {
+        Ok(self.link.borrow().delivered)
+    }
+
+    #[getter]
+    fn dropped(&self) -> PyResult<u64> {
+        Ok(self.link.borrow().dropped)
+    }
+}
+
+#[pyclass]
+pub struct LoopbackPairFun {}
+
+#[pymethods]
+impl LoopbackPairFun {
+    /// Connects a client to a server offering `bootstrap` in this process,
+    /// through in-memory queues. Whichever side waits runs the other, so no
+    /// thread or socket is involved. Messages take `latency` simulated
+    /// seconds, each is lost with probability `drop_rate` as drawn from a
+    /// generator seeded with `seed`.
+    #[call]
+    #[args(bootstrap = "None", latency = "0.0", drop_rate = "0.0", seed = "0")]
+    fn loopback_pair(&self, bootstrap: Option<&PyAny>, latency: f64, drop_rate: f64, seed: u64) -> PyResult<LoopbackPy> {
+        let inner = || -> Result<LoopbackPy, Error> {
+            let bootstrap = bootstrap.map(local_cap).transpose()?;
+            let link = Link::new(latency, drop_rate, seed);
+
+            Ok(LoopbackPy {
+                client: loopback_end(&link, 0, None),
+                server: loopback_end(&link, 1, bootstrap),
+                link,
+            })
+        };
+
+        inner().map_err(PyErr::from)
+    }
+}
+
+/// An interface implemented in Python, to be offered as a bootstrap
+/// capability.
+#[pyclass]
+pub struct ServerPy {
+    i: Rc<LocalCap>,
+}
+
+#[pymethods]
+impl ServerPy {
+    #[new]
+    fn __new__(obj: &PyRawObject, node: &NodePy, implementation: PyObject) -> PyResult<()> {
+        let node = dynamic::node_ref(node)?;
+        dynamic::interface_node(&node)?;
+
+        obj.init(ServerPy { i: Rc::new(LocalCap::new(node, Implementation::Object(implementation))) });
+        Ok(())
+    }
+
+    /// Holds back the returns of streaming calls until `resume()`. The
+    /// calls still run, but a client's window fills up with them and its
+    /// `send()` blocks: an implementation pauses its server for as long as
+    /// it cannot take more, e.g. while a buffer is full.
+    fn pause(&self) -> PyResult<()> {
+        let mut held = self.i.held.borrow_mut();
+
+        if held.is_none() {
+            *held = Some(Vec::new());
+        }
+
+        Ok(())
+    }
+
+    /// Sends the returns held back since `pause()`.
+    fn resume(&self, py: Python) -> PyResult<()> {
+        Ok(self.i.resume(py)?)
+    }
+
+    #[getter]
+    fn paused(&self) -> PyResult<bool> {
+        Ok(self.i.held.borrow().is_some())
+    }
+
+    /// Number of returns held back.
+    #[getter]
+    fn held(&self) -> PyResult<usize> {
+        Ok(self.i.held.borrow().as_ref().map_or(0, |x| x.len()))
+    }
+}
+
+/// A server for tests, see `mock_server`; it goes wherever a `ServerPy` does.
+#[pyclass]
+pub struct MockServerPy {
+    i: Rc<LocalCap>,
+    mock: Rc<Mock>,
+}
+
+#[pymethods]
+impl MockServerPy {
+    /// `(method name, params reader)` of every call so far, in order.
+    #[getter]
+    fn calls(&self, py: Python) -> PyResult<Vec<(String, PyObject)>> {
+        Ok(self.mock.calls.borrow().iter().map(|(k, v)| (k.clone(), v.clone_ref(py))).collect())
+    }
+}
+
+#[pyclass]
+pub struct MockServerFun {}
+
+#[pymethods]
+impl MockServerFun {
+    /// Serves the interface `node` with canned answers: default results, or
+    /// the fixture given for the method as a keyword argument, a dict of
+    /// results or a callable taking the params reader. Calls are recorded
+    /// in `calls`.
+    #[call]
+    #[args(fixtures = "**")]
+    fn mock_server(&self, node: &NodePy, fixtures: Option<&PyDict>) -> PyResult<MockServerPy> {
+        let inner = || -> Result<MockServerPy, Error> {
+            let node = dynamic::node_ref(node)?;
+            dynamic::interface_node(&node)?;
+
+            let mut mock = Mock { fixtures: HashMap::new(), calls: RefCell::new(Vec::new()) };
+
+            for (k, v) in fixtures.iter().flat_map(|x| x.iter()) {
+                let name: String = k.extract()?;
+
+                find_method(&node.rc(), &node, &name)?;
+
+                mock.fixtures.insert(name, v.to_object(v.py()));
+            }
+
+            let mock = Rc::new(mock);
+
+            Ok(MockServerPy { i: Rc::new(LocalCap::new(node, Implementation::Mock(mock.clone()))), mock })
+        };
+
+        inner().map_err(PyErr::from)
+    }
+}
+
+#[pyclass]
+pub struct ListenerPy {
+    listener: UnixListener,
+    bootstrap: Rc<LocalCap>,
+}
+
+#[pymethods]
+impl ListenerPy {
+    /// Waits for a peer; serve it with `run()` on the returned connection.
+    fn accept(&self, py: Python) -> PyResult<ConnectionPy> {
+        let inner = |this: &Self| -> Result<ConnectionPy, Error> {
+            let listener = &this.listener;
+            let (socket, _) = py.allow_threads(|| listener.accept())?;
+            let transport = StreamTransport::from_socket(socket)?;
+
+            Ok(ConnectionPy { i: Connection::new(Box::new(transport), Some(this.bootstrap.clone())) })
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+}
+
+#[pyclass]
+pub struct ListenFun {}
+
+#[pymethods]
+impl ListenFun {
+    /// Listens on a Unix socket at `path`, offering `bootstrap` to every
+    /// peer that connects.
+    #[call]
+    fn listen(&self, path: &str, bootstrap: &PyAny) -> PyResult<ListenerPy> {
+        let inner = || -> Result<ListenerPy, Error> {
+            Ok(ListenerPy { listener: UnixListener::bind(path)?, bootstrap: local_cap(bootstrap)? })
+        };
+
+        inner().map_err(PyErr::from)
+    }
+}
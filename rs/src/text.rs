@@ -0,0 +1,879 @@
+//! The Cap'n Proto text format, as printed by `kj::str()` on dynamic values,
+//! and a parser for it.
+
+use std::rc::Rc;
+
+use capnp::private::layout::{
+    ElementSize, ListBuilder, ListReader, PointerBuilder, PointerReader, PrimitiveElement, StructBuilder,
+    StructReader,
+};
+use capnpc::schema_capnp;
+
+use crate::{Error, NodeArena};
+use crate::dynamic::{self, NodeRef, TypeReader};
+use crate::message;
+
+// capnp/stringify.c++ keeps items on one line while they stay this short
+const INLINE_LIMIT: usize = 24;
+
+// what is printed for values the text format has no syntax for; the parser
+// refuses them rather than lose the value
+const OPAQUE_POINTER: &str = "<opaque pointer>";
+const EXTERNAL_CAPABILITY: &str = "<external capability>";
+
+#[derive(Clone, Copy)]
+enum Mode {
+    Bare,
+    Parenthesized,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    List,
+    Record,
+}
+
+/// Indentation level of the pretty mode, `None` prints everything on one line.
+#[derive(Clone, Copy)]
+struct Indent(Option<usize>);
+
+impl Indent {
+    fn next(self) -> Indent {
+        Indent(self.0.map(|x| x + 1))
+    }
+
+    fn delimit(self, items: Vec<String>, mode: Mode, kind: Kind) -> String {
+        let amount = match self.0 {
+            Some(x) if !can_print_all_inline(&items, kind) => x,
+            _ => return items.join(", "),
+        };
+
+        let delim = format!(",\n{}", " ".repeat(amount * 2));
+
+        // a bare value starts right after its bracket, anything else on a new line
+        let prefix = match mode {
+            Mode::Bare => " ",
+            Mode::Parenthesized => &delim[1..],
+        };
+
+        format!("{}{} ", prefix, items.join(&delim))
+    }
+}
+
+fn can_print_all_inline(items: &[String], kind: Kind) -> bool {
+    let mut total = 0;
+
+    for x in items {
+        if x.len() > INLINE_LIMIT || x.contains('\n') {
+            return false;
+        }
+
+        if kind == Kind::Record {
+            total += x.len();
+
+            if total > INLINE_LIMIT {
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
+/// `kj::encodeCEscape`; with `ascii` bytes above 0x7f are escaped as well,
+/// which keeps Data printable.
+pub fn escape(bytes: &[u8], ascii: bool) -> String {
+    let mut r = Vec::with_capacity(bytes.len() + 2);
+
+    r.push(b'"');
+
+    for &b in bytes {
+        match b {
+            0x07 => r.extend_from_slice(b"\\a"),
+            0x08 => r.extend_from_slice(b"\\b"),
+            0x0c => r.extend_from_slice(b"\\f"),
+            b'\n' => r.extend_from_slice(b"\\n"),
+            b'\r' => r.extend_from_slice(b"\\r"),
+            b'\t' => r.extend_from_slice(b"\\t"),
+            0x0b => r.extend_from_slice(b"\\v"),
+            b'\'' => r.extend_from_slice(b"\\'"),
+            b'"' => r.extend_from_slice(b"\\\""),
+            b'\\' => r.extend_from_slice(b"\\\\"),
+            x if x < 0x20 || x == 0x7f || (ascii && x > 0x7f) => {
+                r.extend_from_slice(format!("\\{:03o}", x).as_bytes());
+            }
+            x => r.push(x),
+        }
+    }
+
+    r.push(b'"');
+
+    String::from_utf8_lossy(&r).into_owned()
+}
+
+// printf's %.*g without the `+` and leading zeros of the exponent, like kj
+fn format_g(value: f64, precision: usize) -> String {
+    fn strip(x: &str) -> &str {
+        if x.contains('.') { x.trim_end_matches('0').trim_end_matches('.') } else { x }
+    }
+
+    let e = format!("{:.*e}", precision - 1, value);
+    let (mantissa, exponent) = e.split_at(e.find('e').unwrap());
+    let exponent: i32 = exponent[1..].parse().unwrap();
+
+    if exponent < -4 || exponent >= precision as i32 {
+        format!("{}e{}", strip(mantissa), exponent)
+    } else {
+        strip(&format!("{:.*}", (precision as i32 - 1 - exponent) as usize, value)).to_string()
+    }
+}
+
+fn special(value: f64) -> Option<String> {
+    if value.is_nan() {
+        Some("nan".into())
+    } else if value.is_infinite() {
+        Some(if value > 0.0 { "inf" } else { "-inf" }.into())
+    } else {
+        None
+    }
+}
+
+/// Shortest of DBL_DIG and DBL_DIG + 2 significant digits that reads back.
+pub fn format_f64(value: f64) -> String {
+    if let Some(x) = special(value) {
+        return x;
+    }
+
+    let r = format_g(value, 15);
+
+    if r.parse::<f64>().ok() == Some(value) { r } else { format_g(value, 17) }
+}
+
+/// Shortest of FLT_DIG and FLT_DIG + 3 significant digits that reads back.
+pub fn format_f32(value: f32) -> String {
+    if let Some(x) = special(value as f64) {
+        return x;
+    }
+
+    let r = format_g(value as f64, 6);
+
+    if r.parse::<f32>().ok() == Some(value) { r } else { format_g(value as f64, 9) }
+}
+
+fn format_bits(arena: &Rc<NodeArena>, type_: &TypeReader, bits: u64) -> Result<String, Error> {
+    use schema_capnp::type_ as T;
+
+    Ok(match type_.which()? {
+        T::Void(()) => "void".into(),
+        T::Bool(()) => (bits != 0).to_string(),
+        T::Int8(()) => (bits as u8 as i8).to_string(),
+        T::Int16(()) => (bits as u16 as i16).to_string(),
+        T::Int32(()) => (bits as u32 as i32).to_string(),
+        T::Int64(()) => (bits as i64).to_string(),
+        T::Uint8(()) | T::Uint16(()) | T::Uint32(()) | T::Uint64(()) => bits.to_string(),
+        T::Float32(()) => format_f32(f32::from_bits(bits as u32)),
+        T::Float64(()) => format_f64(f64::from_bits(bits)),
+        T::Enum(x) => match dynamic::enumerant_name(arena, x.get_type_id(), bits as u16)? {
+            Some(name) => name,
+            None => bits.to_string(),
+        },
+        _ => return Err(Error::Type("not a primitive type".into()))
+    })
+}
+
+struct Printer {
+    arena: Rc<NodeArena>,
+}
+
+impl Printer {
+    fn struct_(&self, node: &NodeRef, reader: &StructReader, indent: Indent, mode: Mode) -> Result<String, Error> {
+        let active = dynamic::which_field(node, reader)?.map(|x| x.get_discriminant_value());
+        // fields are sorted by ordinal, so the active union member lands
+        // before the first later non-union field, as with `kj::str`
+        let mut items = Vec::new();
+
+        for field in dynamic::struct_node(node)?.get_fields()?.iter() {
+            let discriminant = field.get_discriminant_value();
+
+            if discriminant != schema_capnp::field::NO_DISCRIMINANT && Some(discriminant) != active {
+                continue;
+            }
+
+            let value = match field.which()? {
+                schema_capnp::field::Group(x) => {
+                    let group = dynamic::get_node(&self.arena, x.get_type_id())?;
+                    self.struct_(&group, reader, indent.next(), Mode::Parenthesized)?
+                }
+                schema_capnp::field::Slot(x) => {
+                    let type_ = x.get_type()?;
+                    let offset = x.get_offset() as usize;
+
+                    if dynamic::is_pointer(&type_)? {
+                        let ptr = reader.get_pointer_field(offset);
+
+                        // null pointers are left out, unless they are the
+                        // selected arm of a union other than the first one
+                        if ptr.is_null() && (discriminant == 0 || discriminant == schema_capnp::field::NO_DISCRIMINANT) {
+                            continue;
+                        }
+
+                        self.pointer(&ptr, &type_, indent.next(), Mode::Parenthesized)?
+                    } else {
+                        let mask = dynamic::default_bits(x.get_default_value()?)?;
+
+                        let bits = match dynamic::element_size(&type_)? {
+                            ElementSize::Void => 0,
+                            ElementSize::Bit => reader.get_bool_field(offset) as u64,
+                            ElementSize::Byte => reader.get_data_field::<u8>(offset) as u64,
+                            ElementSize::TwoBytes => reader.get_data_field::<u16>(offset) as u64,
+                            ElementSize::FourBytes => reader.get_data_field::<u32>(offset) as u64,
+                            _ => reader.get_data_field::<u64>(offset),
+                        };
+
+                        format_bits(&self.arena, &type_, bits ^ mask)?
+                    }
+                }
+            };
+
+            items.push(format!("{} = {}", field.get_name()?, value));
+        }
+
+        Ok(format!("({})", indent.delimit(items, mode, Kind::Record)))
+    }
+
+    fn pointer(&self, ptr: &PointerReader, type_: &TypeReader, indent: Indent, mode: Mode) -> Result<String, Error> {
+        use schema_capnp::type_ as T;
+
+        Ok(match type_.which()? {
+            T::Text(()) => escape(ptr.get_text(None)?.as_bytes(), false),
+            T::Data(()) => escape(ptr.get_data(None)?, true),
+            T::Struct(x) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                self.struct_(&node, &ptr.get_struct(None)?, indent, mode)?
+            }
+            T::List(x) => {
+                let element = x.get_element_type()?;
+                self.list(&ptr.get_list(dynamic::element_size(&element)?, None)?, &element, indent, mode)?
+            }
+            T::AnyPointer(_) => OPAQUE_POINTER.into(),
+            T::Interface(_) => EXTERNAL_CAPABILITY.into(),
+            _ => return Err(Error::Type("not a pointer type".into()))
+        })
+    }
+
+    fn list(&self, list: &ListReader, element: &TypeReader, indent: Indent, mode: Mode) -> Result<String, Error> {
+        use schema_capnp::type_ as T;
+
+        let mut items = Vec::with_capacity(list.len() as usize);
+
+        for i in 0..list.len() {
+            items.push(match element.which()? {
+                T::Struct(x) => {
+                    let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                    self.struct_(&node, &list.get_struct_element(i), indent.next(), Mode::Bare)?
+                }
+                T::Text(()) | T::Data(()) | T::List(_) | T::AnyPointer(_) | T::Interface(_) => {
+                    self.pointer(&list.get_pointer_element(i), element, indent.next(), Mode::Bare)?
+                }
+                _ => {
+                    let bits = match dynamic::element_size(element)? {
+                        ElementSize::Void => 0,
+                        ElementSize::Bit => <bool as PrimitiveElement>::get(list, i) as u64,
+                        ElementSize::Byte => <u8 as PrimitiveElement>::get(list, i) as u64,
+                        ElementSize::TwoBytes => <u16 as PrimitiveElement>::get(list, i) as u64,
+                        ElementSize::FourBytes => <u32 as PrimitiveElement>::get(list, i) as u64,
+                        _ => <u64 as PrimitiveElement>::get(list, i),
+                    };
+
+                    format_bits(&self.arena, element, bits)?
+                }
+            });
+        }
+
+        Ok(format!("[{}]", indent.delimit(items, mode, Kind::List)))
+    }
+}
+
+fn indent(pretty: bool) -> Indent {
+    Indent(if pretty { Some(1) } else { None })
+}
+
+/// `(int32Field = 3, textField = "foo")`; with `pretty` long values are
+/// broken over indented lines. AnyPointer and capability values print as
+/// placeholders, which cannot be parsed back.
+pub fn print_struct(node: &NodeRef, reader: &StructReader, pretty: bool) -> Result<String, Error> {
+    Printer { arena: node.rc() }.struct_(node, reader, indent(pretty), Mode::Bare)
+}
+
+pub fn print_list(arena: &Rc<NodeArena>, list: &ListReader, element: &TypeReader, pretty: bool) -> Result<String, Error> {
+    Printer { arena: arena.clone() }.list(list, element, indent(pretty), Mode::Bare)
+}
+
+enum Value {
+    Ident(String),
+    Int(i128),
+    Float(f64),
+    // quoted literal, bytes as they were after unescaping
+    String(Vec<u8>),
+    // 0x"..." literal
+    Data(Vec<u8>),
+    List(Vec<Node>),
+    Struct(Vec<Assignment>),
+    // `<opaque pointer>` or `<external capability>`, the contents are lost
+    Placeholder,
+}
+
+struct Node {
+    pos: usize,
+    value: Value,
+}
+
+struct Assignment {
+    pos: usize,
+    name: String,
+    value: Node,
+}
+
+fn error_at(source: &str, pos: usize, message: &str) -> Error {
+    let before = &source.as_bytes()[..pos.min(source.len())];
+    let line = before.iter().filter(|&&x| x == b'\n').count() + 1;
+    let column = pos - before.iter().rposition(|&x| x == b'\n').map(|x| x + 1).unwrap_or(0) + 1;
+
+    Error::Value(format!("line {}, column {}: {}", line, column, message))
+}
+
+// as deep as capnp's default nesting limit lets a message go
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    source: &'a str,
+    src: &'a [u8],
+    pos: usize,
+    // structs and lists open around the current position
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, pos: usize, message: &str) -> Result<T, Error> {
+        Err(error_at(self.source, pos, message))
+    }
+
+    fn enter(&mut self) -> Result<(), Error> {
+        if self.depth == MAX_DEPTH {
+            return self.error(self.pos, &format!("nested deeper than {}", MAX_DEPTH));
+        }
+
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).cloned()
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(x) = self.peek() {
+            match x {
+                b' ' | b'\t' | b'\r' | b'\n' => self.pos += 1,
+                b'#' => {
+                    while self.peek().map(|x| x != b'\n').unwrap_or(false) {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn expect(&mut self, x: u8) -> Result<(), Error> {
+        self.skip_space();
+
+        if self.peek() != Some(x) {
+            return self.error(self.pos, &format!("expected '{}'", x as char));
+        }
+
+        self.pos += 1;
+
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        let start = self.pos;
+
+        while let Some(x) = self.peek() {
+            if x.is_ascii_alphanumeric() || x == b'_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+
+        match self.src.get(start) {
+            Some(x) if x.is_ascii_alphabetic() || *x == b'_' => Some(self.source[start..self.pos].to_string()),
+            _ => {
+                self.pos = start;
+                None
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Node, Error> {
+        self.skip_space();
+
+        let pos = self.pos;
+
+        let value = match self.peek() {
+            None => return self.error(pos, "unexpected end of input"),
+            Some(b'(') => Value::Struct(self.struct_()?),
+            Some(b'[') => Value::List(self.list()?),
+            Some(b'"') => Value::String(self.string()?),
+            Some(b'0') if self.src.get(pos + 1) == Some(&b'x') && self.src.get(pos + 2) == Some(&b'"') => {
+                self.pos += 2;
+                Value::Data(self.hex()?)
+            }
+            Some(b'<') => match [OPAQUE_POINTER, EXTERNAL_CAPABILITY].iter().find(|x| self.source[pos..].starts_with(*x)) {
+                Some(x) => {
+                    self.pos += x.len();
+                    Value::Placeholder
+                }
+                None => return self.error(pos, "unexpected character"),
+            },
+            Some(x) if x == b'-' || x.is_ascii_digit() => self.number()?,
+            Some(_) => match self.ident() {
+                Some(x) => Value::Ident(x),
+                None => return self.error(pos, "unexpected character"),
+            }
+        };
+
+        Ok(Node { pos, value })
+    }
+
+    fn struct_(&mut self) -> Result<Vec<Assignment>, Error> {
+        self.enter()?;
+        self.pos += 1;
+
+        let mut r = Vec::new();
+
+        loop {
+            self.skip_space();
+
+            if self.peek() == Some(b')') {
+                self.pos += 1;
+                self.depth -= 1;
+                return Ok(r);
+            }
+
+            let pos = self.pos;
+            let name = match self.ident() {
+                Some(x) => x,
+                None => return self.error(pos, "expected a field name"),
+            };
+
+            self.expect(b'=')?;
+            r.push(Assignment { pos, name, value: self.value()? });
+            self.skip_space();
+
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b')') => {}
+                _ => return self.error(self.pos, "expected ',' or ')'"),
+            }
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<Node>, Error> {
+        self.enter()?;
+        self.pos += 1;
+
+        let mut r = Vec::new();
+
+        loop {
+            self.skip_space();
+
+            if self.peek() == Some(b']') {
+                self.pos += 1;
+                self.depth -= 1;
+                return Ok(r);
+            }
+
+            r.push(self.value()?);
+            self.skip_space();
+
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {}
+                _ => return self.error(self.pos, "expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn digits(&mut self, radix: u32, max: usize) -> u32 {
+        let mut r = 0;
+        let mut n = 0;
+
+        while n < max {
+            match self.peek().and_then(|x| (x as char).to_digit(radix)) {
+                Some(x) => {
+                    r = r * radix + x;
+                    n += 1;
+                    self.pos += 1;
+                }
+                None => break,
+            }
+        }
+
+        r
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, Error> {
+        let start = self.pos;
+        self.pos += 1;
+
+        let mut r = Vec::new();
+
+        loop {
+            let x = match self.peek() {
+                Some(x) => x,
+                None => return self.error(start, "unterminated string"),
+            };
+            self.pos += 1;
+
+            match x {
+                b'"' => return Ok(r),
+                b'\\' => {
+                    let pos = self.pos - 1;
+                    let x = match self.peek() {
+                        Some(x) => x,
+                        None => return self.error(start, "unterminated string"),
+                    };
+                    self.pos += 1;
+
+                    match x {
+                        b'a' => r.push(0x07),
+                        b'b' => r.push(0x08),
+                        b'f' => r.push(0x0c),
+                        b'n' => r.push(b'\n'),
+                        b'r' => r.push(b'\r'),
+                        b't' => r.push(b'\t'),
+                        b'v' => r.push(0x0b),
+                        b'\'' | b'"' | b'\\' | b'?' => r.push(x),
+                        b'x' => {
+                            let before = self.pos;
+                            let value = self.digits(16, 2);
+
+                            if self.pos == before {
+                                return self.error(pos, "\\x needs hex digits");
+                            }
+                            r.push(value as u8);
+                        }
+                        b'0'..=b'7' => {
+                            self.pos -= 1;
+                            let value = self.digits(8, 3);
+
+                            if value > 0xff {
+                                return self.error(pos, "octal escape out of range");
+                            }
+                            r.push(value as u8);
+                        }
+                        _ => return self.error(pos, "unknown escape sequence"),
+                    }
+                }
+                x => r.push(x),
+            }
+        }
+    }
+
+    fn hex(&mut self) -> Result<Vec<u8>, Error> {
+        let start = self.pos;
+        self.pos += 1;
+
+        let mut r = Vec::new();
+        let mut half: Option<u8> = None;
+
+        loop {
+            let x = match self.peek() {
+                Some(x) => x,
+                None => return self.error(start, "unterminated data literal"),
+            };
+
+            match x {
+                b'"' => {
+                    if half.is_some() {
+                        return self.error(self.pos, "odd number of hex digits");
+                    }
+                    self.pos += 1;
+                    return Ok(r);
+                }
+                b' ' | b'\t' | b'\r' | b'\n' => {}
+                _ => match (x as char).to_digit(16) {
+                    Some(d) => match half.take() {
+                        Some(h) => r.push(h << 4 | d as u8),
+                        None => half = Some(d as u8),
+                    },
+                    None => return self.error(self.pos, "expected a hex digit"),
+                }
+            }
+
+            self.pos += 1;
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let start = self.pos;
+        let negative = self.peek() == Some(b'-');
+
+        if negative {
+            self.pos += 1;
+            self.skip_space();
+
+            match self.ident() {
+                Some(ref x) if x == "inf" => return Ok(Value::Float(std::f64::NEG_INFINITY)),
+                Some(_) => return self.error(start, "expected a number"),
+                None => {}
+            }
+        }
+
+        let digits = self.pos;
+
+        if self.peek() == Some(b'0') && (self.src.get(self.pos + 1) == Some(&b'x') || self.src.get(self.pos + 1) == Some(&b'X')) {
+            self.pos += 2;
+
+            let begin = self.pos;
+            while self.peek().map(|x| x.is_ascii_hexdigit()).unwrap_or(false) {
+                self.pos += 1;
+            }
+
+            return match i128::from_str_radix(&self.source[begin..self.pos], 16) {
+                Ok(x) => Ok(Value::Int(if negative { -x } else { x })),
+                Err(_) => self.error(start, "invalid hex number"),
+            };
+        }
+
+        let mut float = false;
+
+        while let Some(x) = self.peek() {
+            match x {
+                b'0'..=b'9' => {}
+                b'.' | b'e' | b'E' => float = true,
+                b'+' | b'-' if float && (self.src[self.pos - 1] | 0x20) == b'e' => {}
+                _ => break,
+            }
+            self.pos += 1;
+        }
+
+        let text = &self.source[digits..self.pos];
+
+        if float {
+            return match text.parse::<f64>() {
+                Ok(x) => Ok(Value::Float(if negative { -x } else { x })),
+                Err(_) => self.error(start, "invalid number"),
+            };
+        }
+
+        // a leading zero makes an octal literal, as in the schema language
+        let parsed = if text.len() > 1 && text.starts_with('0') {
+            i128::from_str_radix(&text[1..], 8)
+        } else {
+            text.parse::<i128>()
+        };
+
+        match parsed {
+            Ok(x) => Ok(Value::Int(if negative { -x } else { x })),
+            Err(_) => self.error(start, "invalid number"),
+        }
+    }
+}
+
+/// Writes parsed values into builders, checking them against the schema.
+struct Writer<'a> {
+    source: &'a str,
+    arena: Rc<NodeArena>,
+}
+
+impl<'a> Writer<'a> {
+    fn error<T>(&self, pos: usize, message: &str) -> Result<T, Error> {
+        Err(error_at(self.source, pos, message))
+    }
+
+    fn struct_(&self, node: &NodeRef, builder: &mut StructBuilder, value: &Node) -> Result<(), Error> {
+        let fields = match value.value {
+            Value::Struct(ref x) => x,
+            _ => return self.error(value.pos, "expected a struct in parentheses"),
+        };
+
+        for x in fields {
+            let field = match dynamic::find_field(node, &x.name) {
+                Ok(field) => field,
+                Err(_) => return self.error(x.pos, &format!("no field named {}", x.name)),
+            };
+
+            dynamic::set_active(node, builder, &field)?;
+
+            match field.which()? {
+                schema_capnp::field::Group(y) => {
+                    let group = dynamic::get_node(&self.arena, y.get_type_id())?;
+                    self.struct_(&group, builder, &x.value)?;
+                }
+                schema_capnp::field::Slot(y) => {
+                    let type_ = y.get_type()?;
+                    let offset = y.get_offset() as usize;
+
+                    if dynamic::is_pointer(&type_)? {
+                        self.pointer(builder.reborrow().get_pointer_field(offset), &type_, &x.value)?;
+                        continue;
+                    }
+
+                    let bits = self.primitive(&type_, &x.value)? ^ dynamic::default_bits(y.get_default_value()?)?;
+
+                    match dynamic::element_size(&type_)? {
+                        ElementSize::Void => {}
+                        ElementSize::Bit => builder.set_bool_field(offset, bits != 0),
+                        ElementSize::Byte => builder.set_data_field::<u8>(offset, bits as u8),
+                        ElementSize::TwoBytes => builder.set_data_field::<u16>(offset, bits as u16),
+                        ElementSize::FourBytes => builder.set_data_field::<u32>(offset, bits as u32),
+                        _ => builder.set_data_field::<u64>(offset, bits),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pointer(&self, mut ptr: PointerBuilder, type_: &TypeReader, value: &Node) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        match (type_.which()?, &value.value) {
+            (_, Value::Placeholder) => return self.error(value.pos, "a placeholder stands for a value the text format cannot hold"),
+            (T::Text(()), Value::String(x)) => match std::str::from_utf8(x) {
+                Ok(x) => ptr.set_text(x),
+                Err(_) => return self.error(value.pos, "text must be valid UTF-8"),
+            },
+            (T::Data(()), Value::String(x)) | (T::Data(()), Value::Data(x)) => ptr.set_data(x),
+            (T::Struct(x), _) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                let mut builder = ptr.init_struct(message::get_node_struct_size(&node)?);
+
+                self.struct_(&node, &mut builder, value)?;
+            }
+            (T::List(x), Value::List(items)) => {
+                let element = x.get_element_type()?;
+                let mut list = dynamic::init_list(&self.arena, ptr, &element, items.len() as u32)?;
+
+                for (i, item) in items.iter().enumerate() {
+                    self.element(&mut list, i as u32, &element, item)?;
+                }
+            }
+            (T::Text(()), _) => return self.error(value.pos, "expected a string"),
+            (T::Data(()), _) => return self.error(value.pos, "expected a string or a 0x\"..\" literal"),
+            (T::List(_), _) => return self.error(value.pos, "expected a list in brackets"),
+            _ => return self.error(value.pos, "this type cannot be written in text format"),
+        }
+
+        Ok(())
+    }
+
+    fn element(&self, list: &mut ListBuilder, idx: u32, element: &TypeReader, value: &Node) -> Result<(), Error> {
+        use schema_capnp::type_ as T;
+
+        match element.which()? {
+            T::Struct(x) => {
+                let node = dynamic::get_node(&self.arena, x.get_type_id())?;
+                return self.struct_(&node, &mut list.reborrow().get_struct_element(idx), value);
+            }
+            T::Text(()) | T::Data(()) | T::List(_) | T::AnyPointer(_) | T::Interface(_) => {
+                return self.pointer(list.reborrow().get_pointer_element(idx), element, value);
+            }
+            _ => {}
+        }
+
+        let bits = self.primitive(element, value)?;
+
+        match dynamic::element_size(element)? {
+            ElementSize::Void => {}
+            ElementSize::Bit => <bool as PrimitiveElement>::set(list, idx, bits != 0),
+            ElementSize::Byte => <u8 as PrimitiveElement>::set(list, idx, bits as u8),
+            ElementSize::TwoBytes => <u16 as PrimitiveElement>::set(list, idx, bits as u16),
+            ElementSize::FourBytes => <u32 as PrimitiveElement>::set(list, idx, bits as u32),
+            _ => <u64 as PrimitiveElement>::set(list, idx, bits),
+        }
+
+        Ok(())
+    }
+
+    /// Raw bits of a primitive value, before the default mask is applied.
+    fn primitive(&self, type_: &TypeReader, value: &Node) -> Result<u64, Error> {
+        use schema_capnp::type_ as T;
+
+        let int = |min: i128, max: i128| -> Result<u64, Error> {
+            match value.value {
+                Value::Int(x) if x >= min && x <= max => Ok(x as u64),
+                Value::Int(_) => self.error(value.pos, "integer out of range"),
+                _ => self.error(value.pos, "expected an integer"),
+            }
+        };
+
+        let float = || -> Result<f64, Error> {
+            match value.value {
+                Value::Int(x) => Ok(x as f64),
+                Value::Float(x) => Ok(x),
+                Value::Ident(ref x) if x == "inf" => Ok(std::f64::INFINITY),
+                Value::Ident(ref x) if x == "nan" => Ok(std::f64::NAN),
+                _ => self.error(value.pos, "expected a number"),
+            }
+        };
+
+        Ok(match type_.which()? {
+            T::Void(()) => match value.value {
+                Value::Ident(ref x) if x == "void" => 0,
+                _ => return self.error(value.pos, "expected void"),
+            },
+            T::Bool(()) => match value.value {
+                Value::Ident(ref x) if x == "true" => 1,
+                Value::Ident(ref x) if x == "false" => 0,
+                _ => return self.error(value.pos, "expected true or false"),
+            },
+            T::Int8(()) => int(i8::min_value() as i128, i8::max_value() as i128)? & 0xff,
+            T::Int16(()) => int(i16::min_value() as i128, i16::max_value() as i128)? & 0xffff,
+            T::Int32(()) => int(i32::min_value() as i128, i32::max_value() as i128)? & 0xffff_ffff,
+            T::Int64(()) => int(i64::min_value() as i128, i64::max_value() as i128)?,
+            T::Uint8(()) => int(0, u8::max_value() as i128)?,
+            T::Uint16(()) => int(0, u16::max_value() as i128)?,
+            T::Uint32(()) => int(0, u32::max_value() as i128)?,
+            T::Uint64(()) => int(0, u64::max_value() as i128)?,
+            T::Float32(()) => (float()? as f32).to_bits() as u64,
+            T::Float64(()) => float()?.to_bits(),
+            T::Enum(x) => {
+                let name = match value.value {
+                    Value::Ident(ref x) => x,
+                    _ => return self.error(value.pos, "expected an enumerant name"),
+                };
+
+                match dynamic::get_node(&self.arena, x.get_type_id())?.which()? {
+                    schema_capnp::node::Enum(e) => {
+                        match e.get_enumerants()?.iter().position(|x| x.get_name().ok() == Some(name.as_str())) {
+                            Some(i) => i as u64,
+                            None => return self.error(value.pos, &format!("unknown enumerant: {}", name)),
+                        }
+                    }
+                    _ => return Err(Error::Type("not an enum".into())),
+                }
+            }
+            _ => return self.error(value.pos, "expected a primitive value"),
+        })
+    }
+}
+
+/// Parses `(field = value, ...)` into the struct `builder` of `node`.
+pub fn parse_struct(node: &NodeRef, builder: &mut StructBuilder, source: &str) -> Result<(), Error> {
+    let mut parser = Parser { source, src: source.as_bytes(), pos: 0, depth: 0 };
+    let value = parser.value()?;
+
+    parser.skip_space();
+
+    if parser.pos < source.len() {
+        return parser.error(parser.pos, "unexpected input after the value");
+    }
+
+    Writer { source, arena: node.rc() }.struct_(node, builder, &value)
+}
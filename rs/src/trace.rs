@@ -0,0 +1,303 @@
+//! Readable text of captured RPC traffic: one line per rpc.capnp `Message`,
+//! with the params and results of calls decoded through a schema when the
+//! interfaces are known.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use capnp::private::layout::{PointerReader, StructReader};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::{Definition, Error, NodeArena};
+use crate::dynamic::{self, NodeRef, RootPointer};
+use crate::framing::{Frame, FrameDecoder, DEFAULT_MAX_SEGMENTS, DEFAULT_MAX_WORDS};
+use crate::protocol::{self, CapDescriptor, Exception, Target};
+use crate::protocol::message::which as M;
+use crate::rpc::{self, Method};
+use crate::text;
+
+fn message_name(which: u16) -> &'static str {
+    match which {
+        M::UNIMPLEMENTED => "unimplemented",
+        M::ABORT => "abort",
+        M::CALL => "call",
+        M::RETURN => "return",
+        M::FINISH => "finish",
+        M::RESOLVE => "resolve",
+        M::RELEASE => "release",
+        M::OBSOLETE_SAVE => "obsoleteSave",
+        M::BOOTSTRAP => "bootstrap",
+        M::OBSOLETE_DELETE => "obsoleteDelete",
+        M::PROVIDE => "provide",
+        M::ACCEPT => "accept",
+        M::JOIN => "join",
+        M::DISEMBARGO => "disembargo",
+        _ => "unknown",
+    }
+}
+
+fn target(x: &Target) -> String {
+    match x {
+        Target::Import(id) => format!("import {}", id),
+        Target::Answer(question, transform) => format!("answer {} {:?}", question, transform),
+    }
+}
+
+fn cap(x: &CapDescriptor) -> String {
+    match x {
+        CapDescriptor::None => "none".into(),
+        CapDescriptor::SenderHosted(id) => format!("senderHosted {}", id),
+        CapDescriptor::SenderPromise(id) => format!("senderPromise {}", id),
+        CapDescriptor::ReceiverHosted(id) => format!("receiverHosted {}", id),
+        CapDescriptor::ReceiverAnswer(question, transform) => format!("receiverAnswer {} {:?}", question, transform),
+        CapDescriptor::ThirdPartyHosted => "thirdPartyHosted".into(),
+    }
+}
+
+fn exception(reader: &StructReader) -> Result<String, Error> {
+    let x = Exception::read(reader)?;
+    Ok(format!("{}: {}", x.type_name(), x.reason))
+}
+
+/// What a question asked, to decode the results that answer it.
+enum Asked {
+    Bootstrap,
+    Call(u64, u16),
+}
+
+/// Follows both directions of a connection. End 0 is the side the capture
+/// was taken on, its messages are marked `>`, the peer's `<`.
+pub struct Tracer {
+    arena: Option<Rc<NodeArena>>,
+    // questions[i]: asked by end i and not answered yet
+    questions: [HashMap<u32, Asked>; 2],
+}
+
+impl Tracer {
+    pub fn new(arena: Option<Rc<NodeArena>>) -> Tracer {
+        Tracer { arena, questions: [HashMap::new(), HashMap::new()] }
+    }
+
+    pub fn frame(&mut self, side: usize, frame: Frame) -> Result<String, Error> {
+        let message = frame.into_reader(capnp::message::ReaderOptions::new());
+        let root: RootPointer = message.get_root()?;
+
+        let text = self.message(side, root.0, true)?;
+
+        Ok(format!("{} {}", if side == 0 { '>' } else { '<' }, text))
+    }
+
+    fn method(&self, interface_id: u64, id: u16) -> Option<Method> {
+        let arena = self.arena.as_ref()?;
+        let node = dynamic::get_node(arena, interface_id).ok()?;
+
+        rpc::method_by_id(arena, &node, interface_id, id).ok()?
+    }
+
+    fn method_name(&self, interface_id: u64, id: u16) -> String {
+        let interface = self.arena.as_ref()
+            .and_then(|x| dynamic::get_node(x, interface_id).ok())
+            .and_then(|x| {
+                let prefix = x.get_display_name_prefix_length() as usize;
+                x.get_display_name().ok().map(|x| x[prefix..].to_string())
+            });
+
+        match (interface, self.method(interface_id, id)) {
+            (Some(x), Some(method)) => format!("{}.{}", x, method.name),
+            (Some(x), None) => format!("{}.@{}", x, id),
+            (None, _) => format!("0x{:x}.@{}", interface_id, id),
+        }
+    }
+
+    /// Content of a payload as `node`, or `otherwise` without one.
+    fn payload(&self, reader: &StructReader, node: Option<&NodeRef>, otherwise: &str) -> Result<String, Error> {
+        let content = reader.get_pointer_field(protocol::payload::CONTENT);
+
+        let mut r = match node {
+            Some(x) if content.is_null() => text::print_struct(x, &StructReader::new_default(), false)?,
+            Some(x) => text::print_struct(x, &content.get_struct(None)?, false)?,
+            None => otherwise.to_string(),
+        };
+
+        let caps = protocol::read_cap_table(reader)?;
+
+        if !caps.is_empty() {
+            r.push_str(&format!(" caps=[{}]", caps.iter().map(cap).collect::<Vec<_>>().join(", ")));
+        }
+
+        Ok(r)
+    }
+
+    /// A message sent by end `side`; with `track`, questions and answers
+    /// are followed, which echoes in `unimplemented` are not.
+    fn message(&mut self, side: usize, ptr: PointerReader, track: bool) -> Result<String, Error> {
+        let (which, body) = protocol::open(ptr)?;
+
+        Ok(match which {
+            M::UNIMPLEMENTED => format!("unimplemented ({})", self.message(1 - side, body, false)?),
+            M::ABORT => format!("abort {}", exception(&body.get_struct(None)?)?),
+            M::BOOTSTRAP => {
+                let id = body.get_struct(None)?.get_data_field::<u32>(protocol::bootstrap::QUESTION_ID);
+
+                if track {
+                    self.questions[side].insert(id, Asked::Bootstrap);
+                }
+
+                format!("bootstrap q={}", id)
+            }
+            M::CALL => self.call(side, &body.get_struct(None)?, track)?,
+            M::RETURN => self.return_(side, &body.get_struct(None)?, track)?,
+            M::FINISH => {
+                let finish = body.get_struct(None)?;
+
+                format!(
+                    "finish q={} releaseResultCaps={}",
+                    finish.get_data_field::<u32>(protocol::finish::QUESTION_ID),
+                    !finish.get_bool_field(protocol::finish::RELEASE_RESULT_CAPS),
+                )
+            }
+            M::RESOLVE => {
+                use crate::protocol::resolve;
+
+                let reader = body.get_struct(None)?;
+                let inner = reader.get_pointer_field(resolve::BODY).get_struct(None)?;
+
+                let resolution = match reader.get_data_field::<u16>(resolve::WHICH) {
+                    resolve::which::CAP => format!("cap={}", cap(&CapDescriptor::read(&inner)?)),
+                    _ => format!("exception {}", exception(&inner)?),
+                };
+
+                format!("resolve promise={} {}", reader.get_data_field::<u32>(resolve::PROMISE_ID), resolution)
+            }
+            M::RELEASE => {
+                let reader = body.get_struct(None)?;
+
+                format!(
+                    "release id={} count={}",
+                    reader.get_data_field::<u32>(protocol::release::ID),
+                    reader.get_data_field::<u32>(protocol::release::REFERENCE_COUNT),
+                )
+            }
+            M::DISEMBARGO => {
+                use crate::protocol::disembargo::{self, which as W};
+
+                let reader = body.get_struct(None)?;
+                let to = Target::read(&reader.get_pointer_field(disembargo::TARGET).get_struct(None)?)?;
+                let id = reader.get_data_field::<u32>(disembargo::ID);
+
+                let context = match reader.get_data_field::<u16>(disembargo::WHICH) {
+                    W::SENDER_LOOPBACK => format!("senderLoopback {}", id),
+                    W::RECEIVER_LOOPBACK => format!("receiverLoopback {}", id),
+                    W::ACCEPT => "accept".into(),
+                    _ => format!("provide {}", id),
+                };
+
+                format!("disembargo target={} {}", target(&to), context)
+            }
+            x => format!("{} (not decoded)", message_name(x)),
+        })
+    }
+
+    fn call(&mut self, side: usize, reader: &StructReader, track: bool) -> Result<String, Error> {
+        use crate::protocol::call;
+
+        let id = reader.get_data_field::<u32>(call::QUESTION_ID);
+        let interface_id = reader.get_data_field::<u64>(call::INTERFACE_ID);
+        let method_id = reader.get_data_field::<u16>(call::METHOD_ID);
+        let to = Target::read(&reader.get_pointer_field(call::TARGET).get_struct(None)?)?;
+
+        if track {
+            self.questions[side].insert(id, Asked::Call(interface_id, method_id));
+        }
+
+        let method = self.method(interface_id, method_id);
+        let params = reader.get_pointer_field(call::PARAMS).get_struct(None)?;
+        let params = self.payload(&params, method.as_ref().map(|x| &x.params), "<not decoded>")?;
+
+        Ok(format!(
+            "call q={} target={} {} params={}",
+            id, target(&to), self.method_name(interface_id, method_id), params,
+        ))
+    }
+
+    fn return_(&mut self, side: usize, reader: &StructReader, track: bool) -> Result<String, Error> {
+        use crate::protocol::return_::{self, which as W};
+
+        let id = reader.get_data_field::<u32>(return_::ANSWER_ID);
+        let body = reader.get_pointer_field(return_::BODY);
+
+        // answers go to questions of the other end
+        let asked = if track { self.questions[1 - side].remove(&id) } else { None };
+
+        let what = match reader.get_data_field::<u16>(return_::WHICH) {
+            W::RESULTS => {
+                let payload = body.get_struct(None)?;
+
+                let results = match asked {
+                    Some(Asked::Bootstrap) => self.payload(&payload, None, "<bootstrap capability>")?,
+                    Some(Asked::Call(interface_id, method_id)) => {
+                        let method = self.method(interface_id, method_id);
+                        self.payload(&payload, method.as_ref().map(|x| &x.results), "<not decoded>")?
+                    }
+                    None => self.payload(&payload, None, "<not decoded>")?,
+                };
+
+                format!("results={}", results)
+            }
+            W::EXCEPTION => format!("exception {}", exception(&body.get_struct(None)?)?),
+            W::CANCELED => "canceled".into(),
+            W::RESULTS_SENT_ELSEWHERE => "resultsSentElsewhere".into(),
+            W::TAKE_FROM_OTHER_QUESTION => {
+                format!("takeFromOtherQuestion {}", reader.get_data_field::<u32>(return_::TAKE_FROM_OTHER_QUESTION))
+            }
+            W::ACCEPT_FROM_THIRD_PARTY => "acceptFromThirdParty".into(),
+            x => format!("unknown {}", x),
+        };
+
+        Ok(format!("return a={} {}", id, what))
+    }
+}
+
+/// Turns captured RPC traffic into text, one line per message. Feed it the
+/// bytes of each direction as they were captured; with a `Definition`,
+/// params and results of calls to its interfaces are decoded too.
+#[pyclass]
+pub struct RpcTracerPy {
+    i: Tracer,
+    decoders: [FrameDecoder; 2],
+}
+
+#[pymethods]
+impl RpcTracerPy {
+    #[new]
+    #[args(definition = "None")]
+    fn __new__(obj: &PyRawObject, definition: Option<&Definition>) -> PyResult<()> {
+        obj.init(RpcTracerPy {
+            i: Tracer::new(definition.map(|x| x.arena.clone())),
+            decoders: [
+                FrameDecoder::new(false, DEFAULT_MAX_SEGMENTS, DEFAULT_MAX_WORDS),
+                FrameDecoder::new(false, DEFAULT_MAX_SEGMENTS, DEFAULT_MAX_WORDS),
+            ],
+        });
+        Ok(())
+    }
+
+    /// Lines for the messages completed by `data`, which this side sent or,
+    /// with `sent=False`, received.
+    #[args(sent = true)]
+    fn feed(&mut self, data: &PyBytes, sent: bool) -> PyResult<Vec<String>> {
+        let inner = |this: &mut Self| -> Result<Vec<String>, Error> {
+            let side = if sent { 0 } else { 1 };
+            let mut r = Vec::new();
+
+            for x in this.decoders[side].feed(data.as_bytes())? {
+                r.push(this.i.frame(side, x)?);
+            }
+
+            Ok(r)
+        };
+
+        inner(self).map_err(PyErr::from)
+    }
+}
@@ -0,0 +1,249 @@
+//! Where RPC messages come from and go to.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+
+use pyo3::prelude::*;
+
+use crate::Error;
+use crate::framing::{Frame, FrameDecoder, DEFAULT_MAX_SEGMENTS, DEFAULT_MAX_WORDS};
+
+pub trait Transport {
+    fn send(&self, py: Python, message: Vec<u8>) -> Result<(), Error>;
+
+    /// Blocks until a whole message arrives; None once the peer has closed.
+    fn receive(&self, py: Python) -> Result<Option<Frame>, Error>;
+
+    fn close(&self);
+}
+
+/// A byte stream in the standard framing, e.g. a Unix socket or a pipe pair.
+/// Blocking reads and writes release the GIL.
+pub struct StreamTransport {
+    reader: RefCell<Option<Box<dyn Read + Send>>>,
+    writer: RefCell<Option<Box<dyn Write + Send>>>,
+    decoder: RefCell<FrameDecoder>,
+    pending: RefCell<VecDeque<Frame>>,
+}
+
+impl StreamTransport {
+    pub fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> StreamTransport {
+        StreamTransport {
+            reader: RefCell::new(Some(reader)),
+            writer: RefCell::new(Some(writer)),
+            decoder: RefCell::new(FrameDecoder::new(false, DEFAULT_MAX_SEGMENTS, DEFAULT_MAX_WORDS)),
+            pending: RefCell::new(VecDeque::new()),
+        }
+    }
+
+    pub fn from_socket(socket: UnixStream) -> Result<StreamTransport, Error> {
+        let writer = socket.try_clone()?;
+
+        Ok(StreamTransport::new(Box::new(socket), Box::new(writer)))
+    }
+
+    /// Uses duplicates of the descriptors, the caller keeps ownership of its own.
+    pub fn from_fds(read_fd: RawFd, write_fd: RawFd) -> Result<StreamTransport, Error> {
+        Ok(StreamTransport::new(Box::new(duplicate(read_fd)?), Box::new(duplicate(write_fd)?)))
+    }
+}
+
+fn duplicate(fd: RawFd) -> Result<File, Error> {
+    let borrowed = unsafe { File::from_raw_fd(fd) };
+    let r = borrowed.try_clone();
+
+    // hand the original back without closing it
+    borrowed.into_raw_fd();
+
+    Ok(r?)
+}
+
+impl Transport for StreamTransport {
+    fn send(&self, py: Python, message: Vec<u8>) -> Result<(), Error> {
+        let mut writer = self.writer.borrow_mut();
+        let writer = writer.as_mut().ok_or(Error::Rpc("connection is closed".into()))?;
+
+        py.allow_threads(|| writer.write_all(&message).and_then(|_| writer.flush()))?;
+
+        Ok(())
+    }
+
+    fn receive(&self, py: Python) -> Result<Option<Frame>, Error> {
+        let mut buf = [0u8; 8192];
+
+        loop {
+            if let Some(x) = self.pending.borrow_mut().pop_front() {
+                return Ok(Some(x));
+            }
+
+            let mut reader = self.reader.borrow_mut();
+            let reader = match reader.as_mut() {
+                Some(x) => x,
+                None => return Ok(None),
+            };
+
+            let n = py.allow_threads(|| reader.read(&mut buf))?;
+
+            if n == 0 {
+                self.decoder.borrow_mut().close()?;
+                return Ok(None);
+            }
+
+            let frames = self.decoder.borrow_mut().feed(&buf[..n])?;
+            self.pending.borrow_mut().extend(frames);
+        }
+    }
+
+    fn close(&self) {
+        // dropping both halves closes the stream, the peer reads end of file
+        self.reader.borrow_mut().take();
+        self.writer.borrow_mut().take();
+    }
+}
+
+/// Runs one step of the connection at one end of a link.
+pub type Pump = Rc<dyn Fn(Python) -> Result<(), Error>>;
+
+/// Both directions of an in-process link. Messages wait in a queue per
+/// direction until the other end takes them. Time is simulated: a message
+/// arrives `latency` seconds after it was sent, the clock jumps to that
+/// moment when it is taken. Lost messages are picked by a seeded generator,
+/// so a run repeats exactly.
+pub struct Link {
+    // queues[i] holds what end i sent, with the time it arrives
+    queues: [VecDeque<(f64, Vec<u8>)>; 2],
+    closed: [bool; 2],
+    pumps: [Option<Pump>; 2],
+    latency: f64,
+    drop_rate: f64,
+    random: u64,
+    pub now: f64,
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+impl Link {
+    pub fn new(latency: f64, drop_rate: f64, seed: u64) -> Rc<RefCell<Link>> {
+        Rc::new(RefCell::new(Link {
+            queues: [VecDeque::new(), VecDeque::new()],
+            closed: [false, false],
+            pumps: [None, None],
+            latency,
+            drop_rate,
+            random: seed,
+            now: 0.0,
+            delivered: 0,
+            dropped: 0,
+        }))
+    }
+
+    /// A transport for end `side` (0 or 1); `pump` steps that end's
+    /// connection when the other end waits for it.
+    pub fn end(this: &Rc<RefCell<Link>>, side: usize, pump: Pump) -> LoopbackTransport {
+        this.borrow_mut().pumps[side] = Some(pump);
+        LoopbackTransport { link: this.clone(), side }
+    }
+
+    // splitmix64, which is fine with any seed
+    fn lose(&mut self) -> bool {
+        if self.drop_rate <= 0.0 {
+            return false;
+        }
+
+        self.random = self.random.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+        let mut x = self.random;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^= x >> 31;
+
+        ((x >> 11) as f64) / ((1u64 << 53) as f64) < self.drop_rate
+    }
+}
+
+/// One end of a `Link`. Waiting for a message runs the other end whenever
+/// a message for it arrives first; with nothing in flight either way, both
+/// would wait forever and receiving fails instead.
+pub struct LoopbackTransport {
+    link: Rc<RefCell<Link>>,
+    side: usize,
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&self, _py: Python, message: Vec<u8>) -> Result<(), Error> {
+        let mut link = self.link.borrow_mut();
+
+        if link.closed[0] || link.closed[1] {
+            return Err(Error::Rpc("connection is closed".into()));
+        }
+
+        if link.lose() {
+            link.dropped += 1;
+            return Ok(());
+        }
+
+        let at = link.now + link.latency;
+        link.queues[self.side].push_back((at, message));
+
+        Ok(())
+    }
+
+    fn receive(&self, py: Python) -> Result<Option<Frame>, Error> {
+        let other = 1 - self.side;
+
+        loop {
+            let pump = {
+                let mut link = self.link.borrow_mut();
+
+                // messages are taken in the order they arrive, whichever
+                // end they arrive at
+                let ours = link.queues[other].front().map(|x| x.0);
+                let theirs = link.queues[self.side].front().map(|x| x.0);
+
+                match (ours, theirs) {
+                    (Some(x), Some(y)) if y < x => {}
+                    (Some(_), _) => {
+                        let (at, message) = link.queues[other].pop_front().unwrap();
+                        link.now = link.now.max(at);
+                        link.delivered += 1;
+                        return Ok(Some(Frame::from_bytes(&message)?));
+                    }
+                    (None, _) if link.closed[other] => return Ok(None),
+                    (None, None) => {
+                        return Err(Error::Rpc("loopback: both ends wait and no message is in flight".into()));
+                    }
+                    (None, Some(_)) => {}
+                }
+
+                link.pumps[other].clone().ok_or(Error::Rpc("loopback: the other end is gone".into()))?
+            };
+
+            // a failing end aborts, which is queued for us and read next;
+            // one that queued nothing cannot step at all
+            if let Err(e) = pump(py) {
+                let mut link = self.link.borrow_mut();
+
+                if link.queues[other].is_empty() {
+                    link.closed[other] = true;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.link.borrow_mut().closed[self.side] = true;
+    }
+}
+
+impl Drop for LoopbackTransport {
+    // the connection owning this end is gone, the other end reads end of file
+    fn drop(&mut self) {
+        self.close();
+    }
+}